@@ -9,10 +9,9 @@ extern crate log;
 extern crate libcsearch;
 extern crate libcustomlogger;
 
-use libcsearch::reader::{IndexReader, POST_ENTRY_SIZE};
+use libcsearch::reader::IndexReader;
 use libcsearch::regexp::Query;
 
-use std::collections::BTreeSet;
 use std::env;
 use std::io::{self, Write};
 
@@ -54,7 +53,7 @@ fn main() {
         env::set_var("CSEARCHINDEX", p);
     }
 
-    let index_path = libcsearch::csearch_index();
+    let index_path = libcsearch::csearch_index().unwrap();
     let idx = IndexReader::open(index_path).unwrap();
 
     if matches.is_present("files") {
@@ -70,34 +69,34 @@ fn main() {
 
     if let Some(t) = matches.value_of("with-trigram") {
         let t_num = t.parse::<u32>().unwrap();
-        let mut h: Option<BTreeSet<u32>> = None;
-        let file_ids = libcsearch::reader::PostReader::list(&idx, t_num, &mut h);
+        let file_ids = libcsearch::reader::PostReader::list(&idx, t_num, None).unwrap();
         println!("{:?}", file_ids);
     }
 }
 
 fn print_indexed_files(idx: &IndexReader) {
-    let post = idx.query(Query::all());
-    for each_fileid in post.into_inner() {
-        println!("{}: {}", each_fileid, idx.name(each_fileid));
+    let post = idx.query(Query::all()).unwrap();
+    for each_fileid in post {
+        println!("{}: {}", each_fileid, idx.name(each_fileid).unwrap());
     }
 }
 
 fn dump_posting_list(idx: &IndexReader) -> io::Result<()> {
+    let entry_len = idx.format().post_entry_len();
     let d: &[u8] = unsafe {
         idx.as_slice()
             .split_at(idx.post_index)
             .1
-            .split_at(POST_ENTRY_SIZE * idx.num_post)
+            .split_at(entry_len * idx.num_post)
             .0
     };
     for i in 0..idx.num_post {
         writeln!(
             &mut std::io::stdout(),
             "{} {} {}",
-            d[i * POST_ENTRY_SIZE],
-            d[i * POST_ENTRY_SIZE + 1],
-            d[i * POST_ENTRY_SIZE + 2]
+            d[i * entry_len],
+            d[i * entry_len + 1],
+            d[i * entry_len + 2]
         )?;
     }
     Ok(())
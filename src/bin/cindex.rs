@@ -6,10 +6,10 @@
 #[macro_use]
 extern crate clap;
 extern crate glob;
+extern crate ignore;
 extern crate regex;
 #[macro_use]
 extern crate log;
-extern crate walkdir;
 
 extern crate consts;
 extern crate libcindex;
@@ -18,21 +18,20 @@ extern crate libcustomlogger;
 extern crate libprofiling;
 extern crate libvarint;
 
-use libcindex::writer::{IndexErrorKind, IndexWriter};
-use libcsearch::reader::IndexReader;
+use ignore::WalkBuilder;
+use libcindex::lock::IndexLock;
+use libcindex::writer::{file_mtime, hash_file, IndexError, IndexErrorKind, IndexWriter};
+use libcsearch::reader::{FileMeta, IndexReadResult, IndexReader, IndexVersion};
 use log::LogLevelFilter;
-use walkdir::{WalkDir, WalkDirIterator};
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::ffi::OsString;
 use std::fs::{self, File, FileType};
 use std::io::{self, BufRead, BufReader};
 #[cfg(unix)]
-use std::os::unix::fs::FileTypeExt;
-#[cfg(windows)]
-use std::path::Component;
-use std::path::{Path, PathBuf};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
 use std::sync::mpsc;
 use std::thread;
@@ -52,37 +51,146 @@ fn is_regular_file(meta: FileType) -> bool {
         && !meta.is_char_device()
 }
 
-#[cfg(windows)]
-fn normalize<P: AsRef<Path>>(p: P) -> io::Result<PathBuf> {
+/// Makes `p` absolute and removes its `.` and `..` components, without
+/// looking at the file system, so symlinks in it are kept
+fn normalize_lexically<P: AsRef<Path>>(p: P) -> io::Result<PathBuf> {
     let mut out = env::current_dir()?;
-    let mut it = p.as_ref().components();
-    // only drop current directory if the first part of p is a prefix (C:/) or root (/)
-    if let Some(c) = it.next() {
-        match c {
-            r @ Component::Prefix(_) | r @ Component::RootDir => {
-                out = PathBuf::new();
-                out.push(r.as_os_str());
-            }
-            r @ _ => out.push(r.as_os_str()),
-        }
-    } else {
-        return Ok(out);
-    };
-    for each_part in it {
+    for each_part in p.as_ref().components() {
         match each_part {
             Component::CurDir => (),
+            // `..` at the root stays at the root
             Component::ParentDir => {
                 out.pop();
             }
-            r @ _ => out.push(r.as_os_str()),
+            // pushing a prefix (C:) or root (/) replaces the current directory
+            r => out.push(r.as_os_str()),
         }
     }
     Ok(out)
 }
 
+/// Makes `p` absolute, resolving symlinks in it unless `logical` is set
+#[cfg(windows)]
+fn normalize<P: AsRef<Path>>(p: P, _logical: bool) -> io::Result<PathBuf> {
+    normalize_lexically(p)
+}
+
 #[cfg(not(windows))]
-fn normalize<P: AsRef<Path>>(p: P) -> io::Result<PathBuf> {
-    fs::canonicalize(p.as_ref())
+fn normalize<P: AsRef<Path>>(p: P, logical: bool) -> io::Result<PathBuf> {
+    if logical {
+        normalize_lexically(p)
+    } else {
+        fs::canonicalize(p.as_ref())
+    }
+}
+
+/// The name of the files listing patterns that only cindex ignores
+const CSEARCHIGNORE: &str = ".csearchignore";
+
+/// Walks the tree under `root`, skipping the entries that match a pattern in
+/// `excludes`, and if `use_ignore` is set, the ones ignored by .gitignore,
+/// .ignore or .csearchignore files.
+///
+/// If `follow_links` is set, symlinks are walked as the files and
+/// directories they point to, except for links to a directory the walk is
+/// already in, which would never end.
+fn walker(
+    root: &Path,
+    excludes: &[glob::Pattern],
+    use_ignore: bool,
+    follow_links: bool,
+) -> WalkBuilder {
+    let mut builder = WalkBuilder::new(root);
+    builder
+        .standard_filters(use_ignore)
+        .hidden(false)
+        .require_git(false)
+        .follow_links(follow_links);
+    if use_ignore {
+        builder.add_custom_ignore_filename(CSEARCHIGNORE);
+    }
+    let excludes = excludes.to_vec();
+    builder.filter_entry(move |d| !excludes.iter().any(|r| r.matches_path(d.path())));
+    builder
+}
+
+/// Returns the files under `root` that `walker` doesn't skip, with their
+/// `file_id`s, passing the errors met along the way to `on_error`
+fn walk_files<F: FnMut(ignore::Error)>(
+    root: &Path,
+    excludes: &[glob::Pattern],
+    use_ignore: bool,
+    follow_links: bool,
+    mut on_error: F,
+) -> Vec<(PathBuf, Option<(u64, u64)>)> {
+    walker(root, excludes, use_ignore, follow_links)
+        .build()
+        .filter_map(|d| d.map_err(&mut on_error).ok())
+        .filter(|d| {
+            d.file_type()
+                .is_some_and(|t| !t.is_dir() && !t.is_symlink())
+        })
+        .map(|d| {
+            let id = d.metadata().ok().and_then(|m| file_id(&m));
+            (d.into_path(), id)
+        })
+        .collect()
+}
+
+/// Identifies a file by its device and inode, which are the same whichever
+/// name or symlink it's reached by
+#[cfg(unix)]
+fn file_id(meta: &fs::Metadata) -> Option<(u64, u64)> {
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_meta: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Removes the names in `files` (which is sorted) that reach a file through
+/// a symlink when another name reaches the same file.
+///
+/// Names of the same file with the same canonical path are kept only once,
+/// by the canonical path if it's one of them, or else by the first of them.
+/// Hard links have different canonical paths, so each is kept.
+fn dedup_linked_files(files: &mut Vec<(PathBuf, Option<(u64, u64)>)>) {
+    let mut by_id = HashMap::<(u64, u64), Vec<usize>>::new();
+    for (i, &(_, id)) in files.iter().enumerate() {
+        if let Some(id) = id {
+            by_id.entry(id).or_default().push(i);
+        }
+    }
+    let mut dropped = HashSet::<usize>::new();
+    for names in by_id.values().filter(|names| names.len() > 1) {
+        let mut by_canonical = HashMap::<PathBuf, Vec<usize>>::new();
+        for &i in names {
+            if let Ok(c) = fs::canonicalize(&files[i].0) {
+                by_canonical.entry(c).or_default().push(i);
+            }
+        }
+        for (canonical, names) in by_canonical {
+            let keep = names
+                .iter()
+                .cloned()
+                .find(|&i| files[i].0 == canonical)
+                .unwrap_or(names[0]);
+            for i in names.into_iter().filter(|&i| i != keep) {
+                debug!(
+                    "{}: same file as {}",
+                    files[i].0.display(),
+                    files[keep].0.display()
+                );
+                dropped.insert(i);
+            }
+        }
+    }
+    let mut i = 0;
+    files.retain(|_| {
+        i += 1;
+        !dropped.contains(&(i - 1))
+    });
 }
 
 fn get_value_from_matches<F: FromStr>(matches: &clap::ArgMatches, name: &str) -> Option<F> {
@@ -98,9 +206,31 @@ fn get_value_from_matches<F: FromStr>(matches: &clap::ArgMatches, name: &str) ->
     }
 }
 
+fn is_thread_count(s: String) -> Result<(), String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err(String::from("there must be at least one thread")),
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{:?} isn't a number: {}", s, e)),
+    }
+}
+
+/// Parses a number of bytes, which may end in K, M or G for KiB, MiB or GiB
+fn parse_size(s: &str) -> Option<usize> {
+    let (digits, shift) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 10),
+        Some('M') => (&s[..s.len() - 1], 20),
+        Some('G') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let n = digits.parse::<usize>().ok()?;
+    n.checked_mul(1 << shift)
+}
+
 const ABOUT: &'static str = "
 cindex prepares the trigram index for use by csearch.  The index
 is the file named by $CSEARCHINDEX, or else $HOME/.csearchindex.
+Unlike csearch, cindex updates only one index, so $CSEARCHINDEX
+must name just one.
 The simplest invocation is
 
 	cindex path...
@@ -117,13 +247,82 @@ or, equivalently:
 
 If cindex is invoked with no paths, it reindexes the paths that have
 already been added, in case the files have changed.  Thus, 'cindex' by
-itself is a useful command to run in a nightly cron job.
+itself is a useful command to run in a nightly cron job.  Files whose
+size and modification time (or contents) haven't changed since they
+were last indexed are carried over from the existing index without
+being read again.
 
 By default cindex adds the named paths to the index but preserves
 information about other paths that might already be indexed
 (the ones printed by cindex --list).  The --reset flag causes cindex to
 delete the existing index before indexing the new paths.
-With no path arguments, cindex -reset removes the index.";
+With no path arguments, cindex -reset removes the index.
+
+The --prune flag removes files that no longer exist on disk from the
+index, without reindexing anything.  The --remove flag removes a path
+and every file under it from the index, so that it's no longer
+reindexed.  Removing a directory inside an indexed path only removes
+its files until the next reindex.
+
+The --verify flag checks the index for corruption, such as a bad
+header or trailer, out-of-range section offsets, unsorted names, or
+posting lists that don't match their counts.  It prints each problem
+found and exits with a non-zero status if there were any.  Indexes are
+written with a checksum of each section, which --verify also checks;
+the --format-v1 flag writes the older format without checksums, which
+the Go cindex and csearch can read.
+
+The --block-postings flag stores each posting list in blocks with a
+table of where each block starts, so that searches for several
+trigrams can skip the parts of long lists they don't need.
+
+Files and directories ignored by the .gitignore, .ignore or .csearchignore
+files in the directory tree being indexed, or in the directories above it,
+are skipped, as are the ones matched by a pattern in the file given with
+--exclude.  These files use the syntax of .gitignore files, with patterns
+in a file applying to the directory it's in and the directories under it,
+and a pattern starting with ! including files an earlier pattern ignored.
+A .gitignore file applies whether or not the tree is a git repository;
+.csearchignore lists files that only cindex should ignore.  The
+--no-ignore flag indexes files regardless of the ignore files.
+
+Each path is stored in the index as an absolute path with every symlink
+in it resolved, so that a tree is indexed under the same name however it's
+reached.  The --logical-paths flag instead stores paths as they're named,
+only made absolute and with . and .. removed, so that a tree reached
+through a symlink, such as a link to the current release, is indexed under
+the name of the link.  Reindexing with no paths keeps the paths as they're
+stored, so they stay logical.  --remove names its path the same way when
+given --logical-paths.
+
+Symlinks are followed to the files and directories they point to, unless
+--no-follow-simlinks is given, in which case they're skipped.  A link to a
+directory the walk is already inside is reported and skipped.  A file that
+can be reached through symlinks as well as by its own path, or through
+several symlinks, is indexed once, under its own path if that was walked
+and otherwise under the first of its names.
+
+Files are read on as many threads as there are CPUs, or the number
+given with --threads.  The index is the same however many are used.
+
+While indexing, cindex sorts the trigrams it finds in memory, writing them
+out to temporary files when they fill the memory set aside for them.  The
+--memory flag sets how much that is, such as 512M or 2G; the default is 128M.
+
+The --merge flag combines existing indexes into one, without reading
+any files:
+
+	cindex --merge OUT IN...
+
+writes to OUT the files of every IN index, which are listed from oldest
+to newest.  As when reindexing, the files under a path indexed by a
+newer index replace those of the older ones.
+
+An index is never rewritten in place: each update is written to a file
+next to it and renamed over it once it's complete and on disk, so
+csearch always sees a whole index and an interrupted update leaves the
+old one as it was.  Updates of the same index wait for each other,
+using a lock on a file named by adding .lock to the name of the index.";
 
 fn main() {
     let matches = clap::App::new("cindex")
@@ -151,17 +350,74 @@ fn main() {
                 .conflicts_with("list-paths")
                 .help("discard existing index"),
         )
+        .arg(
+            clap::Arg::with_name("prune")
+                .long("prune")
+                .conflicts_with("path")
+                .conflicts_with("list-paths")
+                .conflicts_with("reset-index")
+                .help("remove files that no longer exist from the index and exit"),
+        )
+        .arg(
+            clap::Arg::with_name("REMOVE_PATH")
+                .long("remove")
+                .takes_value(true)
+                .conflicts_with("path")
+                .conflicts_with("list-paths")
+                .conflicts_with("reset-index")
+                .conflicts_with("prune")
+                .help("remove REMOVE_PATH and every file under it from the index and exit"),
+        )
+        .arg(
+            clap::Arg::with_name("verify")
+                .long("verify")
+                .conflicts_with("path")
+                .conflicts_with("list-paths")
+                .conflicts_with("reset-index")
+                .conflicts_with("prune")
+                .conflicts_with("REMOVE_PATH")
+                .help("check the index for corruption and exit"),
+        )
+        .arg(
+            clap::Arg::with_name("MERGE")
+                .long("merge")
+                .value_name("INDEX")
+                .multiple(true)
+                .min_values(2)
+                .conflicts_with("path")
+                .conflicts_with("list-paths")
+                .conflicts_with("reset-index")
+                .conflicts_with("prune")
+                .conflicts_with("REMOVE_PATH")
+                .conflicts_with("verify")
+                .help("given OUT IN..., merge the IN indexes, from oldest to newest, into OUT and exit"),
+        )
         .arg(
             clap::Arg::with_name("INDEX_FILE")
                 .long("indexpath")
                 .takes_value(true)
                 .help("use specified INDEX_FILE as the index path. overrides $CSEARCHINDEX"),
         )
+        .arg(
+            clap::Arg::with_name("format-v1")
+                .long("format-v1")
+                .help("write a version 1 index without checksums, which the Go tools can read"),
+        )
+        .arg(
+            clap::Arg::with_name("block-postings")
+                .long("block-postings")
+                .help("store posting lists in blocks that searches can skip over"),
+        )
         .arg(
             clap::Arg::with_name("no-follow-simlinks")
                 .long("no-follow-simlinks")
                 .help("do not follow symlinked files and directories"),
         )
+        .arg(
+            clap::Arg::with_name("logical-paths")
+                .long("logical-paths")
+                .help("store paths made absolute without resolving symlinks in them"),
+        )
         .arg(
             clap::Arg::with_name("MAX_FILE_SIZE_BYTES")
                 .long("maxFileLen")
@@ -195,12 +451,30 @@ fn main() {
                 .takes_value(true)
                 .help("path to file containing a list of file patterns to exclude from indexing"),
         )
+        .arg(
+            clap::Arg::with_name("no-ignore")
+                .long("no-ignore")
+                .help("don't skip files ignored by .gitignore, .ignore or .csearchignore files"),
+        )
         .arg(
             clap::Arg::with_name("FILE")
                 .long("filelist")
                 .takes_value(true)
                 .help("path to file containing a list of file paths to index"),
         )
+        .arg(
+            clap::Arg::with_name("MEMORY")
+                .long("memory")
+                .takes_value(true)
+                .help("sort up to MEMORY bytes of trigrams in memory, such as 512M or 2G"),
+        )
+        .arg(
+            clap::Arg::with_name("THREADS")
+                .long("threads")
+                .takes_value(true)
+                .validator(is_thread_count)
+                .help("read files on THREADS threads. defaults to the number of CPUs"),
+        )
         .arg(
             clap::Arg::with_name("verbose")
                 .long("verbose")
@@ -221,6 +495,9 @@ fn main() {
     libcustomlogger::init(max_log_level).unwrap();
 
     let mut excludes: Vec<glob::Pattern> = vec![glob::Pattern::new(".csearchindex").unwrap()];
+    let use_ignore = !matches.is_present("no-ignore");
+    let follow_links = !matches.is_present("no-follow-simlinks");
+    let logical = matches.is_present("logical-paths");
     let mut args = Vec::<String>::new();
 
     if let Some(p) = matches.values_of("path") {
@@ -233,13 +510,38 @@ fn main() {
 
     if matches.is_present("list-paths") {
         let i = open_index_or_fail();
-        for each_file in i.indexed_paths() {
+        for each_file in read_or_fail(i.indexed_paths()) {
             println!("{}", each_file);
         }
         return;
     }
+    if matches.is_present("verify") {
+        let index_path = index_path_or_fail();
+        let problems = match libcsearch::reader::verify(&index_path) {
+            Ok(problems) => problems,
+            Err(e) => {
+                error!("verify {}: {}", index_path, e);
+                std::process::exit(101);
+            }
+        };
+        if !problems.is_empty() {
+            for p in &problems {
+                error!("{}: {}", index_path, p);
+            }
+            std::process::exit(1);
+        }
+        let i = open_index_or_fail();
+        info!(
+            "{}: ok, {} paths, {} files, {} posting lists",
+            index_path,
+            read_or_fail(i.indexed_paths()).len(),
+            i.num_name,
+            i.num_post
+        );
+        return;
+    }
     if matches.is_present("reset-index") {
-        let index_path = libcsearch::csearch_index();
+        let index_path = index_path_or_fail();
         let p = Path::new(&index_path);
         if !p.exists() {
             // does not exist so nothing to do
@@ -250,8 +552,63 @@ fn main() {
             .expect("failed to get metadata for file!")
             .file_type();
         if is_regular_file(meta) {
-            std::fs::remove_file(p).expect("failed to remove file");
+            let lock = lock_or_fail(&index_path);
+            lock.remove().expect("failed to remove file");
+        }
+        return;
+    }
+    if matches.is_present("prune") {
+        let index_path = index_path_or_fail();
+        let lock = lock_or_fail(&index_path);
+        let pruned_path = lock.temp_path(1);
+        let mut num_pruned = 0;
+        info!("prune {}", index_path);
+        let pruned = libcindex::merge::prune(&pruned_path, &index_path, |name| {
+            let exists = Path::new(name).exists();
+            if !exists {
+                debug!("{}: no longer exists", name);
+                num_pruned += 1;
+            }
+            exists
+        });
+        if let Err(e) = pruned {
+            error!("prune {}: {}", index_path, e);
+            let _ = fs::remove_file(&pruned_path);
+            std::process::exit(101);
+        }
+        info!("pruned {} files", num_pruned);
+        replace_or_fail(&lock, pruned_path);
+        return;
+    }
+    if let Some(remove_path_str) = matches.value_of("REMOVE_PATH") {
+        // the path may no longer exist, in which case it can't be canonicalized
+        let remove_path = env::current_dir().unwrap().join(remove_path_str);
+        let remove_path = normalize(&remove_path, logical).unwrap_or(remove_path);
+        let index_path = index_path_or_fail();
+        let lock = lock_or_fail(&index_path);
+        let removed_path = lock.temp_path(1);
+        info!("remove {} from {}", remove_path.display(), index_path);
+        if let Err(e) = libcindex::merge::remove(&removed_path, &index_path, &remove_path) {
+            error!("remove {}: {}", remove_path.display(), e);
+            let _ = fs::remove_file(&removed_path);
+            std::process::exit(101);
         }
+        replace_or_fail(&lock, removed_path);
+        return;
+    }
+    if let Some(mut merge_paths) = matches.values_of("MERGE") {
+        let out_path = merge_paths.next().unwrap().to_string();
+        let in_paths = merge_paths.collect::<Vec<_>>();
+        // OUT may be one of the indexes being merged
+        let lock = lock_or_fail(&out_path);
+        let merged_path = lock.temp_path(1);
+        info!("merge {} into {}", in_paths.join(" "), out_path);
+        if let Err(e) = libcindex::merge::merge_many(&merged_path, &in_paths) {
+            error!("merge {}: {}", out_path, e);
+            let _ = fs::remove_file(&merged_path);
+            std::process::exit(101);
+        }
+        replace_or_fail(&lock, merged_path);
         return;
     }
     if let Some(exc_path_str) = matches.value_of("EXCLUDE_FILE") {
@@ -268,39 +625,55 @@ fn main() {
         args.extend(f.lines().map(|f| f.unwrap().trim().to_string()));
     }
 
-    if args.is_empty() {
+    let mut paths: Vec<PathBuf> = if args.is_empty() {
+        // the indexed paths were normalized when they were added, and are
+        // reindexed as they are, so logical paths stay logical
         let i = open_index_or_fail();
-        for each_file in i.indexed_paths() {
-            args.push(each_file);
-        }
-    }
-
-    let mut paths: Vec<PathBuf> = args
-        .iter()
-        .filter(|f| !f.is_empty())
-        .map(|f| env::current_dir().unwrap().join(f))
-        .map(|f| normalize(f).unwrap())
-        .collect();
+        read_or_fail(i.indexed_paths())
+            .into_iter()
+            .map(PathBuf::from)
+            .collect()
+    } else {
+        args.iter()
+            .filter(|f| !f.is_empty())
+            .map(|f| env::current_dir().unwrap().join(f))
+            .map(|f| normalize(f, logical).unwrap())
+            .collect()
+    };
     paths.sort();
 
-    let mut index_path = libcsearch::csearch_index();
-    let needs_merge = if Path::new(&index_path).exists() {
-        index_path.push('~');
-        true
+    // the new files are indexed into a temporary file, which either replaces
+    // the index or is merged with it into another
+    let lock = lock_or_fail(index_path_or_fail());
+    let index_path = lock.temp_path(1);
+    let old_index = if lock.index_path().exists() {
+        Some(open_index_or_fail())
     } else {
-        false
+        None
     };
+    let needs_merge = old_index.is_some();
+    let reindexed_paths = paths.clone();
+    // files that can be carried over from the old index, with their current metadata
+    let mut unchanged = HashMap::<String, FileMeta>::new();
 
     let (tx, rx) = mpsc::channel::<OsString>();
     // copying these variables into the worker thread
     let index_path_cloned = index_path.clone();
     let paths_cloned = paths.clone();
     let log_skipped = matches.is_present("logskip");
+    let memory = matches.value_of("MEMORY").map(|s| match parse_size(s) {
+        Some(n) => n,
+        None => {
+            error!("--memory: can't convert value '{}' to a size", s);
+            std::process::exit(101);
+        }
+    });
+    let threads = get_value_from_matches::<usize>(&matches, "THREADS")
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
     let h = thread::spawn(move || {
-        let mut seen = HashSet::<OsString>::new();
-        let mut i = match IndexWriter::new(index_path_cloned) {
+        let mut i = match IndexWriter::new(&index_path_cloned) {
             Ok(i) => i,
-            Err(e) => panic!("IndexWriter: {}", e),
+            Err(e) => write_failed(&index_path_cloned, e.into()),
         };
         if let Some(t) = get_value_from_matches::<u64>(&matches, "MAX_TRIGRAMS_COUNT") {
             i.max_trigram_count = t;
@@ -314,28 +687,36 @@ fn main() {
         if let Some(b) = get_value_from_matches::<u64>(&matches, "MAX_LINE_LEN_BYTES") {
             i.max_line_len = b;
         }
+        if matches.is_present("format-v1") {
+            i.version = IndexVersion::V1;
+        }
+        i.block_postings = matches.is_present("block-postings");
+        if let Some(m) = memory {
+            i.memory = m;
+        }
         i.add_paths(paths_cloned.into_iter().map(PathBuf::into_os_string));
         let _frame = libprofiling::profile("Index files");
-        while let Ok(f) = rx.recv() {
-            if seen.contains(&f) {
-                continue;
-            }
-            if let Err(ref e) = i.add_file(&f) {
-                match e.kind() {
-                    IndexErrorKind::IoError(_) => warn!("{}: {}", Path::new(&f).display(), e),
-                    _ if log_skipped => warn!("{:?}: skipped. {}", f, e),
-                    _ => (),
+        // the files come in sorted, and are read on `threads` threads
+        i.add_files(rx.into_iter().map(PathBuf::from), threads, |f, e| {
+            match e.kind() {
+                IndexErrorKind::IoError(_) | IndexErrorKind::FileNameError => {
+                    warn!("{}: {}", f.display(), e)
                 }
+                _ if log_skipped => warn!("{:?}: skipped. {}", f, e),
+                _ => (),
             }
-            seen.insert(f);
-        }
+        })
+        .unwrap_or_else(|e| write_failed(&index_path_cloned, e));
         info!("flush index");
-        i.flush().expect("failed to flush index to disk");
+        i.flush()
+            .unwrap_or_else(|e| write_failed(&index_path_cloned, e));
         #[allow(dropping_copy_types)]
         drop(_frame);
         libprofiling::print_profiling();
     });
 
+    // the index expects names in sorted order, so gather every file before indexing
+    let mut files = Vec::<(PathBuf, Option<(u64, u64)>)>::new();
     for each_path in paths {
         if !each_path.exists() || !each_path.is_dir() {
             warn!(
@@ -345,40 +726,140 @@ fn main() {
             continue;
         }
         info!("index {}", each_path.display());
-        let tx = tx.clone();
-        let files = WalkDir::new(each_path)
-            .follow_links(true)
-            .into_iter()
-            .filter_entry(|d| {
-                let p = d.path();
-                !excludes.iter().any(|r| r.matches_path(&p))
-            })
-            .filter_map(Result::ok)
-            .filter(|d| !d.file_type().is_dir());
+        files.extend(walk_files(
+            &each_path,
+            &excludes,
+            use_ignore,
+            follow_links,
+            |e| warn!("{}", e),
+        ));
+    }
+    files.sort();
+    files.dedup();
+    if follow_links {
+        dedup_linked_files(&mut files);
+    }
 
-        for d in files {
-            tx.send(OsString::from(d.path())).unwrap();
+    for (f, _) in files {
+        if let Some(ref ix) = old_index {
+            let meta = fs::metadata(&f).ok();
+            let name = f.to_str().map(String::from);
+            if let (Some(meta), Some(name)) = (meta, name) {
+                if let Some(m) = unchanged_file_meta(ix, &name, &meta) {
+                    unchanged.insert(name, m);
+                    continue;
+                }
+            }
         }
+        tx.send(f.into_os_string()).unwrap();
     }
     drop(tx);
     h.join().unwrap();
     if needs_merge {
-        let dest_path = index_path.clone() + &"~";
-        let src1_path = libcsearch::csearch_index();
-        let src2_path = index_path.clone();
-        info!("merge {} {}", src1_path, src2_path);
-        libcindex::merge::merge(dest_path, src1_path, src2_path).unwrap();
-        fs::remove_file(index_path.clone()).unwrap();
-        fs::remove_file(libcsearch::csearch_index()).unwrap();
-        fs::rename(index_path + &"~", libcsearch::csearch_index()).unwrap();
+        drop(old_index);
+        info!("{} unchanged files", unchanged.len());
+        let dest_path = lock.temp_path(2);
+        let src1_path = lock.index_path();
+        let src2_path = &index_path;
+        info!("merge {} {}", src1_path.display(), src2_path.display());
+        // files under the reindexed paths are either unchanged or gone
+        let keep = |name: &str, meta: FileMeta| {
+            if reindexed_paths
+                .iter()
+                .any(|p| Path::new(name).starts_with(p))
+            {
+                unchanged.get(name).cloned()
+            } else {
+                Some(meta)
+            }
+        };
+        let merged = libcindex::merge::merge_filtered(&dest_path, src1_path, src2_path, keep);
+        if let Err(e) = merged {
+            error!("merge {}: {}", src1_path.display(), e);
+            let _ = fs::remove_file(&dest_path);
+            let _ = fs::remove_file(&index_path);
+            std::process::exit(101);
+        }
+        if let Err(e) = fs::remove_file(&index_path) {
+            error!("remove {}: {}", index_path.display(), e);
+            let _ = fs::remove_file(&dest_path);
+            std::process::exit(101);
+        }
+        replace_or_fail(&lock, dest_path);
+    } else {
+        replace_or_fail(&lock, index_path);
     }
 
     info!("done");
     libprofiling::print_profiling();
 }
 
+/// Returns the metadata to record for the file `name` if its contents haven't
+/// changed since it was added to `ix`, so that it doesn't need to be reindexed.
+fn unchanged_file_meta(ix: &IndexReader, name: &str, meta: &fs::Metadata) -> Option<FileMeta> {
+    // a file that can't be looked up in the old index is just reindexed
+    let old = ix.file_meta(ix.find_name(name).unwrap_or(None)?)?;
+    if meta.len() != old.size {
+        return None;
+    }
+    let mtime = file_mtime(meta);
+    if mtime == old.mtime {
+        return Some(old);
+    }
+    // the file was touched, but its contents may still be the same
+    match hash_file(name) {
+        Ok(hash) if hash == old.hash => Some(FileMeta { mtime, ..old }),
+        _ => None,
+    }
+}
+
+/// Returns the path of the index to update, or exits if $CSEARCHINDEX
+/// doesn't name just one
+fn index_path_or_fail() -> String {
+    match libcsearch::csearch_index() {
+        Ok(index_path) => index_path,
+        Err(e) => {
+            error!("{}; set it to one index or give --indexpath", e);
+            std::process::exit(101);
+        }
+    }
+}
+
+/// Locks the index at `index_path` for updating, or exits if it can't be
+fn lock_or_fail<P: AsRef<Path>>(index_path: P) -> IndexLock {
+    match IndexLock::acquire(&index_path) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("lock {}: {}", index_path.as_ref().display(), e);
+            std::process::exit(101);
+        }
+    }
+}
+
+/// Replaces the locked index with the finished index at `new`, or exits if
+/// it can't be.  The old index is left as it was if replacing it fails.
+fn replace_or_fail<P: AsRef<Path>>(lock: &IndexLock, new: P) {
+    if let Err(e) = lock.replace(&new) {
+        error!(
+            "replace {} with {}: {}",
+            lock.index_path().display(),
+            new.as_ref().display(),
+            e
+        );
+        std::process::exit(101);
+    }
+}
+
+/// Exits after failing to write the new index to `path`, removing what was
+/// written of it
+fn write_failed(path: &Path, e: IndexError) -> ! {
+    error!("write {}: {}", path.display(), e);
+    let _ = fs::remove_file(path);
+    std::process::exit(101);
+}
+
 fn open_index_or_fail() -> IndexReader {
-    let index_path = libcsearch::csearch_index();
+    let index_path = index_path_or_fail();
     match IndexReader::open(&index_path) {
         Ok(i) => i,
         Err(e) => {
@@ -387,3 +868,268 @@ fn open_index_or_fail() -> IndexReader {
         }
     }
 }
+
+fn read_or_fail<T>(r: IndexReadResult<T>) -> T {
+    match r {
+        Ok(t) => t,
+        Err(e) => {
+            error!("read {}: {}", index_path_or_fail(), e);
+            std::process::exit(101);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A directory for a test to build files in, removed when it's dropped
+    struct TempTree(PathBuf);
+
+    impl TempTree {
+        fn new() -> TempTree {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!(
+                "cindex-test-{}-{}",
+                process::id(),
+                COUNT.fetch_add(1, Ordering::SeqCst)
+            );
+            let root = env::temp_dir().join(name);
+            fs::create_dir_all(&root).unwrap();
+            // the temporary directory may itself be reached through a symlink
+            TempTree(fs::canonicalize(root).unwrap())
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+
+        /// Writes the file `name`, making the directories it's in
+        fn file(&self, name: &str, contents: &str) -> PathBuf {
+            let p = self.path(name);
+            fs::create_dir_all(p.parent().unwrap()).unwrap();
+            fs::write(&p, contents).unwrap();
+            p
+        }
+    }
+
+    impl Drop for TempTree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Returns the names of the files walked under `root`, relative to it
+    fn walked(root: &Path, use_ignore: bool, follow_links: bool) -> Vec<String> {
+        let mut names = walk_files(root, &[], use_ignore, follow_links, |e| {
+            panic!("walk {}: {}", root.display(), e)
+        })
+        .into_iter()
+        .map(|(p, _)| p.strip_prefix(root).unwrap().display().to_string())
+        .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Returns the names of the files to index under `root`, relative to it,
+    /// after removing the names of the same file as `main` does
+    fn indexed(root: &Path, follow_links: bool) -> Vec<String> {
+        let mut files = walk_files(root, &[], true, follow_links, |e| {
+            panic!("walk {}: {}", root.display(), e)
+        });
+        files.sort();
+        files.dedup();
+        if follow_links {
+            dedup_linked_files(&mut files);
+        }
+        files
+            .into_iter()
+            .map(|(p, _)| p.strip_prefix(root).unwrap().display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_walker_ignore_files() {
+        let tree = TempTree::new();
+        tree.file(".gitignore", "*.log\n!keep.log\n");
+        tree.file(".ignore", "build/\n");
+        tree.file(CSEARCHIGNORE, "secret.txt\n");
+        tree.file("a.txt", "");
+        tree.file("debug.log", "");
+        tree.file("keep.log", "");
+        tree.file("secret.txt", "");
+        tree.file("build/out.txt", "");
+        tree.file("sub/.gitignore", "local.txt\n");
+        tree.file("sub/local.txt", "");
+        tree.file("sub/b.txt", "");
+
+        // there's no git repository, but the ignore files are honored anyway
+        assert_eq!(
+            walked(&tree.0, true, false),
+            [
+                ".csearchignore",
+                ".gitignore",
+                ".ignore",
+                "a.txt",
+                "keep.log",
+                "sub/.gitignore",
+                "sub/b.txt",
+            ]
+        );
+        assert_eq!(
+            walked(&tree.0, false, false),
+            [
+                ".csearchignore",
+                ".gitignore",
+                ".ignore",
+                "a.txt",
+                "build/out.txt",
+                "debug.log",
+                "keep.log",
+                "secret.txt",
+                "sub/.gitignore",
+                "sub/b.txt",
+                "sub/local.txt",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_walker_excludes() {
+        let tree = TempTree::new();
+        tree.file("a.txt", "");
+        tree.file("b.o", "");
+        tree.file("obj/c.txt", "");
+        let excludes = [
+            glob::Pattern::new("*.o").unwrap(),
+            glob::Pattern::new("*/obj").unwrap(),
+        ];
+        let files = walk_files(&tree.0, &excludes, true, false, |e| panic!("{}", e));
+        assert_eq!(
+            files.into_iter().map(|(p, _)| p).collect::<Vec<_>>(),
+            [tree.path("a.txt")]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dedup_canonical_name() {
+        use std::os::unix::fs::symlink;
+
+        let tree = TempTree::new();
+        let file = tree.file("b.txt", "");
+        // the alias sorts first, but the canonical name is the one kept
+        symlink(&file, tree.path("a_link.txt")).unwrap();
+        symlink("b.txt", tree.path("c_link.txt")).unwrap();
+        assert_eq!(indexed(&tree.0, true), ["b.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dedup_only_links() {
+        use std::os::unix::fs::symlink;
+
+        let tree = TempTree::new();
+        let outside = TempTree::new();
+        let file = outside.file("f.txt", "");
+        symlink(&file, tree.path("l2.txt")).unwrap();
+        symlink(&file, tree.path("l1.txt")).unwrap();
+        symlink(&outside.0, tree.path("dir")).unwrap();
+        assert_eq!(indexed(&tree.0, true), ["dir/f.txt"]);
+
+        fs::remove_file(tree.path("dir")).unwrap();
+        assert_eq!(indexed(&tree.0, true), ["l1.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dedup_keeps_hard_links() {
+        let tree = TempTree::new();
+        let file = tree.file("h1.txt", "");
+        fs::hard_link(&file, tree.path("h2.txt")).unwrap();
+        assert_eq!(indexed(&tree.0, true), ["h1.txt", "h2.txt"]);
+        assert_eq!(indexed(&tree.0, false), ["h1.txt", "h2.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_walker_no_follow_links() {
+        use std::os::unix::fs::symlink;
+
+        let tree = TempTree::new();
+        let outside = TempTree::new();
+        tree.file("a.txt", "");
+        outside.file("f.txt", "");
+        symlink("a.txt", tree.path("link.txt")).unwrap();
+        symlink(&outside.0, tree.path("dir")).unwrap();
+        assert_eq!(indexed(&tree.0, false), ["a.txt"]);
+        assert_eq!(indexed(&tree.0, true), ["a.txt", "dir/f.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_walker_symlink_loop() {
+        use std::os::unix::fs::symlink;
+
+        let tree = TempTree::new();
+        tree.file("a.txt", "");
+        tree.file("sub/b.txt", "");
+        symlink(&tree.0, tree.path("sub/up")).unwrap();
+        let mut errors = Vec::new();
+        let files = walk_files(&tree.0, &[], true, true, |e| errors.push(e.to_string()));
+        let mut names = files
+            .into_iter()
+            .map(|(p, _)| p.strip_prefix(&tree.0).unwrap().display().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["a.txt", "sub/b.txt"]);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].contains("loop"), "{:?}", errors);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_normalize_dots() {
+        let cwd = env::current_dir().unwrap();
+        let abs = |p: &str| normalize_lexically(p).unwrap();
+        assert_eq!(abs("/a/./b/../c"), Path::new("/a/c"));
+        assert_eq!(abs("/a/b/.."), Path::new("/a"));
+        assert_eq!(abs("/.."), Path::new("/"));
+        assert_eq!(abs("/../a/../.."), Path::new("/"));
+        assert_eq!(abs("/a/b/"), Path::new("/a/b"));
+        assert_eq!(abs("/a/b/./"), Path::new("/a/b"));
+        assert_eq!(abs(""), cwd);
+        assert_eq!(abs("a/./b"), cwd.join("a/b"));
+        assert_eq!(abs("./a"), cwd.join("a"));
+        assert_eq!(abs("../a"), cwd.parent().unwrap_or(&cwd).join("a"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_normalize_symlinked_root() {
+        use std::os::unix::fs::symlink;
+
+        let tree = TempTree::new();
+        let real = tree.path("real");
+        fs::create_dir_all(real.join("sub")).unwrap();
+        let link = tree.path("link");
+        symlink(&real, &link).unwrap();
+
+        // a logical path keeps the symlink, even through `..`
+        assert_eq!(normalize(&link, true).unwrap(), link);
+        assert_eq!(normalize(link.join("sub/.."), true).unwrap(), link);
+        assert_eq!(
+            normalize(link.join("sub/"), true).unwrap(),
+            link.join("sub")
+        );
+        // otherwise it's resolved
+        assert_eq!(normalize(&link, false).unwrap(), real);
+        assert_eq!(normalize(link.join("sub/.."), false).unwrap(), real);
+        assert_eq!(
+            normalize(link.join("sub/"), false).unwrap(),
+            real.join("sub")
+        );
+    }
+}
@@ -20,19 +20,28 @@ extern crate libcsearch;
 extern crate libcustomlogger;
 extern crate libvarint;
 
-use libcsearch::reader::IndexReader;
-use libcsearch::regexp::{Query, RegexInfo};
+use libcsearch::reader::{IndexReadResult, IndexReader, MultiIndexReader, QueryPlan};
+use libcsearch::regexp::{Query, RegexInfo, StringSet};
 
-use std::collections::BTreeSet;
+use std::cmp;
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::env;
+use std::fmt;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::iter;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+use std::thread;
 
 use grep::{Grep, GrepBuilder};
 use regex::bytes;
 use regex::Regex;
-use termcolor::{Color, ColorChoice, ColorSpec, Stdout, WriteColor};
+use termcolor::{Buffer, BufferWriter, Color, ColorChoice, ColorSpec, WriteColor};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum PrintFormat {
@@ -55,9 +64,25 @@ pub struct MatchOptions {
     pub print_count: bool,
     pub ignore_case: bool,
     pub files_with_matches_only: bool,
+    pub files_without_match: bool,
+    pub invert_match: bool,
+    pub word_regexp: bool,
     pub line_number: bool,
     pub with_color: bool,
     pub max_count: Option<usize>,
+    pub before_context: usize,
+    pub after_context: usize,
+    pub json: bool,
+}
+
+impl MatchOptions {
+    /// Whether lines around the matches are printed too
+    pub fn has_context(&self) -> bool {
+        (self.before_context > 0 || self.after_context > 0)
+            && !self.files_with_matches_only
+            && !self.files_without_match
+            && !self.print_count
+    }
 }
 
 const ABOUT: &str = "
@@ -74,7 +99,52 @@ If no index exists, this command creates one.  If an index already exists, cinde
 overwrites it.  Run cindex --help for more.
 
 Csearch uses the index stored in $CSEARCHINDEX or, if that variable is unset or
-empty, $HOME/.csearchindex.
+empty, $HOME/.csearchindex.  $CSEARCHINDEX may also hold several indexes
+separated by colons, like $PATH, which are all searched; so are several
+--indexpath options.  Files found in more than one index are only searched
+once, and results are printed in order of file name.
+
+The --explain flag prints how the regexp is turned into a query of trigrams,
+how many indexed files contain each trigram, and how many candidate files are
+left after each step, without searching any files.
+
+Candidate files are searched on as many threads as there are CPUs, or the
+number given with --threads, and their matches are still printed in order.
+With --max-count, the search stops as soon as enough matches are found.
+
+As with grep, the exit status is 0 if a line matched, 1 if none did, and 2
+if there was an error, such as a bad regexp, an index that can't be read,
+or an indexed file that can't be opened, even if lines matched too.
+Errors are reported on standard error.
+
+The -F flag searches for PATTERN as a literal string, so that characters
+like . and ( needn't be escaped, and -w only matches PATTERN as a whole
+word: as in grep, the characters just before and after a match must not be
+letters, digits or underscores, and if they are, PATTERN is looked for
+again further along the line.  Both work with -i.
+
+The -v flag selects the lines that don't match instead, and -L prints the
+files with no lines that match.  Since the index can only rule files out,
+-v searches every indexed file, as --brute does; -L lists the files the
+index rules out without reading them, and searches the rest.
+
+The -A, -B and -C flags print lines of context after, before, or around
+each matching line, as grep does: context lines are marked by a - after
+the file name and line number where matching lines have a :, and groups
+of lines that aren't next to each other are separated by a line of --.
+
+The --json flag prints results as JSON objects, one per line, for other
+programs to read.  The lines of each file with matches are preceded by an
+object of type \"begin\" and followed by one of type \"end\", and each
+line is an object of type \"match\", or \"context\" with -A, -B or -C.
+Their data holds the path, the line with its number, its offset in bytes
+from the start of the file, and the column and the start and end in the
+line of each match.  Paths, lines and matches are objects holding either
+\"text\", or \"bytes\" in base64 if they aren't valid UTF-8:
+
+	{\"type\":\"match\",\"data\":{\"path\":{\"text\":\"a.c\"},\"lines\":{\"text\":\"int x;\\n\"},
+	 \"line_number\":3,\"absolute_offset\":20,\"column\":5,
+	 \"submatches\":[{\"match\":{\"text\":\"x\"},\"start\":4,\"end\":5}]}}
 ";
 
 #[cfg(windows)]
@@ -142,6 +212,18 @@ pub fn main() {
                 .help("limit search to filenames matching FILE_PATTERN")
                 .takes_value(true),
         )
+        .arg(
+            clap::Arg::with_name("fixed-strings")
+                .short("F")
+                .long("fixed-strings")
+                .help("treat PATTERN as a literal string instead of a regular expression"),
+        )
+        .arg(
+            clap::Arg::with_name("word-regexp")
+                .short("w")
+                .long("word-regexp")
+                .help("only match PATTERN as a whole word, not next to letters, digits or underscores"),
+        )
         .arg(
             clap::Arg::with_name("ignore-case")
                 .short("i")
@@ -158,6 +240,19 @@ pub fn main() {
                 .long("files-with-matches")
                 .help("Only print filenames that contain matches (don't print the matching lines)"),
         )
+        .arg(
+            clap::Arg::with_name("files-without-match")
+                .short("L")
+                .long("files-without-match")
+                .conflicts_with_all(&["files-with-matches", "count", "json"])
+                .help("Only print filenames that contain no matches"),
+        )
+        .arg(
+            clap::Arg::with_name("invert-match")
+                .short("v")
+                .long("invert-match")
+                .help("select lines that don't match, searching every indexed file"),
+        )
         .arg(
             clap::Arg::with_name("line-number")
                 .short("n")
@@ -169,40 +264,97 @@ pub fn main() {
                 .long("format-vs")
                 .help("print lines in a format that can be parsed by Visual Studio 2008"),
         )
+        .arg(
+            clap::Arg::with_name("json")
+                .long("json")
+                .conflicts_with_all(&["count", "files-with-matches", "visual-studio-format"])
+                .help("print each matching line as a JSON object, one per line"),
+        )
         .arg(
             clap::Arg::with_name("NUM")
                 .short("m")
                 .long("max-count")
                 .takes_value(true)
-                .help("stop after NUM matches"),
+                .validator(is_count)
+                .help("stop after NUM matching lines, or NUM files with -l or -c"),
+        )
+        .arg(
+            clap::Arg::with_name("AFTER")
+                .short("A")
+                .long("after-context")
+                .takes_value(true)
+                .validator(is_count)
+                .help("print AFTER lines of context after each matching line"),
+        )
+        .arg(
+            clap::Arg::with_name("BEFORE")
+                .short("B")
+                .long("before-context")
+                .takes_value(true)
+                .validator(is_count)
+                .help("print BEFORE lines of context before each matching line"),
+        )
+        .arg(
+            clap::Arg::with_name("CONTEXT")
+                .short("C")
+                .long("context")
+                .takes_value(true)
+                .validator(is_count)
+                .help("print CONTEXT lines of context around each matching line"),
+        )
+        .arg(
+            clap::Arg::with_name("THREADS")
+                .long("threads")
+                .takes_value(true)
+                .validator(is_count)
+                .help("search files on THREADS threads. defaults to the number of CPUs"),
         )
         .arg(
             clap::Arg::with_name("bruteforce")
                 .long("brute")
                 .help("brute force - search all files in the index"),
         )
+        .arg(
+            clap::Arg::with_name("explain")
+                .long("explain")
+                .help("show how PATTERN is turned into an index query, without searching"),
+        )
         .arg(
             clap::Arg::with_name("INDEX_FILE")
                 .long("indexpath")
                 .takes_value(true)
-                .help("use specified INDEX_FILE as the index path. overrides $CSEARCHINDEX."),
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "use specified INDEX_FILE as the index path. overrides $CSEARCHINDEX. \
+                     may be given more than once to search several indexes.",
+                ),
         )
-        .get_matches();
+        .get_matches_safe()
+        .unwrap_or_else(|e| {
+            if e.use_stderr() {
+                // usage errors are errors like any other
+                eprintln!("{}", e.message);
+                std::process::exit(EXIT_ERROR);
+            }
+            e.exit()
+        });
 
     // possibly add ignore case flag to the pattern
     let ignore_case = matches.is_present("ignore-case");
 
-    // get the pattern provided by the user
+    // get the pattern provided by the user, and the regexp it stands for
     let pattern = matches.value_of("PATTERN").expect("Failed to get PATTERN");
-
-    // possibly override the csearchindex
-    if let Some(p) = matches.value_of("INDEX_FILE") {
-        env::set_var("CSEARCHINDEX", p);
-    }
+    let fixed_strings = matches.is_present("fixed-strings");
+    let regexp = if fixed_strings {
+        regex::escape(pattern)
+    } else {
+        pattern.to_string()
+    };
 
     // combine cmdline options used for matching/output into a structure
     let match_options = MatchOptions {
-        pattern: pattern.to_string(),
+        pattern: regexp,
         print_format: if matches.is_present("visual-studio-format") {
             PrintFormat::VisualStudio
         } else {
@@ -211,113 +363,393 @@ pub fn main() {
         print_count: matches.is_present("count"),
         ignore_case,
         files_with_matches_only: matches.is_present("files-with-matches"),
+        files_without_match: matches.is_present("files-without-match"),
+        invert_match: matches.is_present("invert-match"),
+        word_regexp: matches.is_present("word-regexp"),
         line_number: matches.is_present("line-number")
-            || matches.is_present("visual-studio-format"),
+            || matches.is_present("visual-studio-format")
+            || matches.is_present("json"),
         with_color: !matches.is_present("nocolor")
             && !matches.is_present("visual-studio-format")
+            && !matches.is_present("json")
             && is_color_output_available(),
-        max_count: matches
-            .value_of("NUM")
-            .map(|s| match usize::from_str_radix(s, 10) {
-                Ok(n) => n,
-                Err(parse_err) => panic!("NUM: {}", parse_err),
-            }),
+        max_count: matches.value_of("NUM").map(|s| s.parse().unwrap()),
+        before_context: context_value(&matches, "BEFORE"),
+        after_context: context_value(&matches, "AFTER"),
+        json: matches.is_present("json"),
     };
 
-    // Get the index from file
-    let index_path = libcsearch::csearch_index();
-    let index_reader = match IndexReader::open(index_path) {
-        Ok(i) => i,
-        Err(e) => panic!("{}", e),
+    // Get the indexes from file, possibly overriding the csearchindex
+    let index_paths = match matches.values_of_os("INDEX_FILE") {
+        Some(paths) => paths.map(PathBuf::from).collect(),
+        None => libcsearch::csearch_indexes(),
     };
+    let index_reader = read_or_exit(MultiIndexReader::open(&index_paths));
 
-    // Find all possibly matching files using the pseudo-regexp
-    let mut post: BTreeSet<u32> = if matches.is_present("bruteforce") {
-        index_reader.query(Query::all()).into_inner()
-    } else {
-        // Get the pseudo-regexp (built using trigrams)
+    // Get the pseudo-regexp (built using trigrams).  Matching whole words
+    // needs the same trigrams.
+    let regex_info = || {
+        if fixed_strings {
+            return RegexInfo::literal(pattern.as_bytes(), ignore_case)
+                .unwrap_or_else(|e| exit_with_error(e));
+        }
         let expr = regex_syntax::ExprBuilder::new()
             .unicode(false)
-            .case_insensitive(matches.is_present("ignore-case"))
-            .parse(pattern)
-            .unwrap();
-        let q = RegexInfo::new(expr).unwrap().query;
-        // panic!("query = {} --- {:?}", q.format_as_string(), q);
+            .case_insensitive(ignore_case)
+            .parse(&match_options.pattern)
+            .unwrap_or_else(|e| exit_with_error(e));
+        RegexInfo::new(expr).unwrap_or_else(|e| exit_with_error(e))
+    };
 
-        index_reader.query(q).into_inner()
+    if matches.is_present("explain") {
+        let stdout = io::stdout();
+        let explained = explain(
+            &mut stdout.lock(),
+            &index_reader,
+            &match_options.pattern,
+            regex_info(),
+        );
+        if let Err(e) = explained {
+            exit_on_write_error(e);
+        }
+        std::process::exit(EXIT_MATCH);
+    }
+
+    // Find all possibly matching files using the pseudo-regexp.  The index
+    // can only show that a file has no matching lines, so every file may
+    // have lines that don't match, or have no lines that match.
+    let mut candidates = None;
+    let mut post: Vec<String> = if matches.is_present("bruteforce") || match_options.invert_match {
+        read_or_exit(index_reader.query(&Query::all()))
+    } else if match_options.files_without_match {
+        let matching = read_or_exit(index_reader.query(&regex_info().query));
+        candidates = Some(matching.into_iter().collect());
+        read_or_exit(index_reader.query(&Query::all()))
+    } else {
+        read_or_exit(index_reader.query(&regex_info().query))
     };
     // println!("identified {} possible queries", post.len());
 
     // If provided, filter possibly matching files via FILE_PATTERN
     if let Some(file_pattern_str) = matches.value_of("FILE_PATTERN") {
-        let file_pattern = match Regex::new(file_pattern_str) {
-            Ok(r) => r,
-            Err(e) => panic!("FILE_PATTERN: {}", e),
-        };
-        post = post
-            .into_iter()
-            .filter(|file_id| {
-                let name = index_reader.name(*file_id);
-                file_pattern.is_match(&name)
-            })
-            .collect::<BTreeSet<_>>();
+        let file_pattern = Regex::new(file_pattern_str)
+            .unwrap_or_else(|e| exit_with_error(format!("FILE_PATTERN: {}", e)));
+        post.retain(|name| file_pattern.is_match(name));
     }
 
     let path_simplifier = PathSimplifier::from(&match_options);
     if matches.is_present("files") {
-        for file_id in post {
-            let name = index_reader.name(file_id);
+        let status = if post.is_empty() {
+            EXIT_NO_MATCH
+        } else {
+            EXIT_MATCH
+        };
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        for name in post {
             let name = path_simplifier.maybe_make_relative(name);
-            println!("{}", name.display());
+            if let Err(e) = writeln!(stdout, "{}", name.display()) {
+                exit_on_write_error(e);
+            }
         }
-        std::process::exit(0);
+        std::process::exit(status);
     }
 
-    // writeln!(io::stderr(), "searching").unwrap();
-    let normal_alloc_size = 4096;
-    let mut buffer = vec![0; normal_alloc_size];
-    let g: Grep = GrepBuilder::new(&match_options.pattern)
-        .case_insensitive(match_options.ignore_case)
-        .build()
-        .unwrap();
-    let matcher = bytes::RegexBuilder::new(&match_options.pattern)
-        .case_insensitive(matches.is_present("ignore-case"))
-        .multi_line(false)
-        .build()
-        .unwrap();
-    let mut stdout = if match_options.with_color {
-        Stdout::new(ColorChoice::Auto)
+    let searcher = Searcher {
+        grep: GrepBuilder::new(&match_options.pattern)
+            .case_insensitive(match_options.ignore_case)
+            .build()
+            .unwrap_or_else(|e| exit_with_error(e)),
+        matcher: bytes::RegexBuilder::new(&match_options.pattern)
+            .case_insensitive(matches.is_present("ignore-case"))
+            .multi_line(false)
+            .build()
+            .unwrap_or_else(|e| exit_with_error(e)),
+        word_end: word_end_regex(&match_options).unwrap_or_else(|e| exit_with_error(e)),
+        candidates,
+        path_simplifier,
+        options: &match_options,
+    };
+    let stdout = if match_options.with_color {
+        BufferWriter::stdout(ColorChoice::Auto)
     } else {
-        Stdout::new(ColorChoice::Never)
+        BufferWriter::stdout(ColorChoice::Never)
     };
-    let mut tmp = Vec::new();
-    for file_id in post {
-        // println!("next file");
-        buffer.resize(normal_alloc_size, 0);
-        let name = index_reader.name(file_id);
-        // writeln!(io::stderr(), "searching {}", name).unwrap();
-        let mut reader = match File::open(&name) {
-            Ok(r) => r,
-            Err(cause) => {
-                warn!("{} - File open failure: {}", name, cause);
+    let threads = match matches.value_of("THREADS") {
+        Some(s) => cmp::max(s.parse().unwrap(), 1),
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let summary = search_files(&searcher, &stdout, post, threads);
+    std::process::exit(summary.exit_status());
+}
+
+/// The exit status when a line matched, when none did, and when there was an
+/// error, as in grep.  Errors take precedence over matches.
+const EXIT_MATCH: i32 = 0;
+const EXIT_NO_MATCH: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+
+/// Checks that an option's value is a count, for clap
+fn is_count(s: String) -> Result<(), String> {
+    s.parse::<usize>()
+        .map(|_| ())
+        .map_err(|e| format!("{:?} isn't a number: {}", s, e))
+}
+
+/// Returns the number of lines of context given by the option `name`, or
+/// else by --context
+fn context_value(matches: &clap::ArgMatches, name: &str) -> usize {
+    matches
+        .value_of(name)
+        .or_else(|| matches.value_of("CONTEXT"))
+        .map_or(0, |s| s.parse().unwrap())
+}
+
+/// Prints an error and exits with the status for errors
+fn exit_with_error<D: fmt::Display>(e: D) -> ! {
+    error!("{}", e);
+    std::process::exit(EXIT_ERROR);
+}
+
+/// Returns the status to exit with after failing to write results.  If the
+/// reader of a pipe went away, as with `csearch foo | head`, nobody is left
+/// to tell, so it isn't an error.
+fn write_error_status(e: &io::Error) -> i32 {
+    if e.kind() == io::ErrorKind::BrokenPipe {
+        EXIT_MATCH
+    } else {
+        EXIT_ERROR
+    }
+}
+
+/// Exits after failing to write results, quietly unless it's an error
+fn exit_on_write_error(e: io::Error) -> ! {
+    match write_error_status(&e) {
+        EXIT_ERROR => exit_with_error(e),
+        status => std::process::exit(status),
+    }
+}
+
+/// What a search found, which decides how csearch exits
+#[derive(Debug, Default)]
+struct Summary {
+    /// Whether any line matched
+    matched: bool,
+    /// Whether any file couldn't be searched
+    failed: bool,
+}
+
+impl Summary {
+    fn exit_status(&self) -> i32 {
+        if self.failed {
+            EXIT_ERROR
+        } else if self.matched {
+            EXIT_MATCH
+        } else {
+            EXIT_NO_MATCH
+        }
+    }
+}
+
+/// Where the results of a search are printed
+trait Output: Sync {
+    /// Returns a buffer to format results in before they're printed
+    fn buffer(&self) -> Buffer;
+    fn print(&self, buf: &Buffer) -> io::Result<()>;
+}
+
+impl Output for BufferWriter {
+    fn buffer(&self) -> Buffer {
+        BufferWriter::buffer(self)
+    }
+    fn print(&self, buf: &Buffer) -> io::Result<()> {
+        BufferWriter::print(self, buf)
+    }
+}
+
+/// How many files each search thread may read ahead of the ones printed
+const FILES_AHEAD_PER_THREAD: usize = 4;
+
+/// Searches `names` on `threads` threads, printing the matches of each
+/// file in the order of `names`.
+///
+/// Files are searched ahead of the one being printed, with their matches
+/// kept in memory until it's their turn.  Once --max-count matches have
+/// been printed, every thread stops, and no more names are taken.
+fn search_files<O, I>(searcher: &Searcher, stdout: &O, names: I, threads: usize) -> Summary
+where
+    O: Output,
+    I: IntoIterator<Item = String>,
+    I::IntoIter: Send,
+{
+    let ahead = threads * FILES_AHEAD_PER_THREAD;
+    let names = names.into_iter();
+    let (name_tx, name_rx) = mpsc::sync_channel::<(usize, String)>(ahead);
+    let name_rx = Mutex::new(name_rx);
+    let (found_tx, found_rx) = mpsc::channel();
+    // holds a slot for each file that's been handed out but not printed
+    let (slot_tx, slot_rx) = mpsc::sync_channel::<()>(ahead);
+    let stop = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            for each in names.enumerate() {
+                if slot_tx.send(()).is_err() || name_tx.send(each).is_err() {
+                    // printing stopped early
+                    return;
+                }
+            }
+        });
+        for _ in 0..threads {
+            let name_rx = &name_rx;
+            let found_tx = found_tx.clone();
+            let stop = &stop;
+            scope.spawn(move || loop {
+                let next = name_rx.lock().unwrap().recv();
+                let (n, name) = match next {
+                    Ok(f) => f,
+                    Err(_) => return,
+                };
+                let found = searcher.search_file(&name, stdout.buffer(), stop);
+                if found_tx.send((n, name, found)).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(found_tx);
+        // the channels are dropped on return, which stops the other threads
+        let mut summary = Summary::default();
+        let printed = print_in_order(searcher.options, stdout, found_rx, slot_rx, &mut summary);
+        stop.store(true, Ordering::Relaxed);
+        if let Err(e) = printed {
+            exit_on_write_error(e);
+        }
+        summary
+    })
+}
+
+/// Prints the matches found by the threads of `search_files`, in the
+/// order the files were handed out, up to --max-count of them, and adds
+/// what was found to `summary`
+fn print_in_order<O: Output>(
+    options: &MatchOptions,
+    stdout: &O,
+    found_rx: Receiver<(usize, String, io::Result<Matches>)>,
+    slot_rx: Receiver<()>,
+    summary: &mut Summary,
+) -> io::Result<()> {
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+    let mut left = options.max_count.unwrap_or(usize::MAX);
+    let mut printed = false;
+    for (n, name, found) in found_rx {
+        pending.insert(n, (name, found));
+        while let Some((name, found)) = pending.remove(&next) {
+            next += 1;
+            let _ = slot_rx.recv();
+            let found = match found {
+                Ok(f) => f,
+                Err(cause) => {
+                    warn!("{} - File open failure: {}", name, cause);
+                    summary.failed = true;
+                    continue;
+                }
+            };
+            if found.ends.is_empty() {
                 continue;
             }
-        };
-        let name = path_simplifier.maybe_make_relative(name);
+            summary.matched = true;
+            if options.has_context() && !options.json && printed {
+                // separates the groups of lines from different files
+                let mut out = stdout.buffer();
+                out.write_all(b"--\n")?;
+                stdout.print(&out)?;
+            }
+            printed = true;
+            let shown = cmp::min(found.ends.len(), left);
+            if shown == found.ends.len() {
+                stdout.print(&found.out)?;
+            } else {
+                // only some of this file's matches are printed
+                let mut out = stdout.buffer();
+                out.write_all(&found.out.as_slice()[..found.ends[shown - 1]])?;
+                stdout.print(&out)?;
+            }
+            if options.json {
+                let mut out = stdout.buffer();
+                write_json_end(&mut out, &found.name, shown)?;
+                stdout.print(&out)?;
+            }
+            left -= shown;
+            if left == 0 {
+                // no more files are printed
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The formatted matches in a file
+struct Matches {
+    /// The name of the file, as it's printed
+    name: PathBuf,
+    out: Buffer,
+    /// The offset in `out` of the end of each match.  A match is a line,
+    /// or a whole file when only file names or counts are printed.
+    ends: Vec<usize>,
+}
+
+/// How to search the candidate files and print their matches
+struct Searcher<'a> {
+    grep: Grep,
+    matcher: bytes::Regex,
+    /// The pattern followed by a non-word character or the end of the
+    /// text, which --word-regexp uses to find other ends of a match
+    word_end: bytes::Regex,
+    /// With --files-without-match, the files that the index shows might
+    /// match, if any.  The others needn't be read.
+    candidates: Option<HashSet<String>>,
+    path_simplifier: PathSimplifier,
+    options: &'a MatchOptions,
+}
+
+impl<'a> Searcher<'a> {
+    /// Searches the file `name`, writing its matches to `out`, up to
+    /// --max-count of them.  Gives up early if `stop` is set.
+    fn search_file(&self, name: &str, out: Buffer, stop: &AtomicBool) -> io::Result<Matches> {
+        let match_options = self.options;
+        let g = &self.grep;
+        let max_count = match_options.max_count.unwrap_or(usize::MAX);
+        if max_count == 0 {
+            return Ok(Matches {
+                name: PathBuf::from(name),
+                out,
+                ends: Vec::new(),
+            });
+        }
+        let mut lines = LinePrinter::new(self, name, out);
+        if let Some(ref candidates) = self.candidates {
+            if !candidates.contains(name) {
+                // the index shows that no line of the file matches
+                lines.finish()?;
+                return Ok(lines.found);
+            }
+        }
+        let normal_alloc_size = 4096;
+        let mut buffer = vec![0; normal_alloc_size];
+        let mut tmp = Vec::new();
+        let mut reader = File::open(name)?;
         let mut trailing_bytes = 0;
-        let mut line_count = 0;
-        let mut num_matches = 0;
         'file: while let Ok(bytes_read) = reader.read(&mut buffer[trailing_bytes..]) {
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
             if bytes_read == 0 && trailing_bytes == 0 {
                 break;
             }
             let total_bytes = bytes_read + trailing_bytes;
             debug_assert!(total_bytes > 0, "BUG: no bytes to process");
-            let mut last_line_end = 0;
-            if match_options.print_count {
-                num_matches += g.iter(&buffer).count();
-                continue;
-            }
             let last_newline = memchr::memrchr(b'\n', &buffer[..total_bytes]);
             let last_newline = match last_newline {
                 Some(nl) => nl + 1,
@@ -330,66 +762,48 @@ pub fn main() {
                 }
             };
             trailing_bytes = total_bytes - last_newline;
-            for each_match in g.iter(&buffer[..last_newline]) {
-                if match_options.files_with_matches_only {
-                    writeln!(&mut stdout, "{}", name.display()).unwrap();
-                    break 'file;
-                }
-                stdout
-                    .set_color(ColorSpec::new().set_bold(true).set_fg(Some(Color::Green)))
-                    .unwrap();
-                write!(&mut stdout, "{}", name.display()).unwrap();
-                stdout.reset().unwrap();
-                if match_options.print_format == PrintFormat::VisualStudio {
-                    write!(&mut stdout, "(").unwrap();
-                } else {
-                    write!(&mut stdout, ":").unwrap();
-                }
-                if match_options.line_number {
-                    let num_lines =
-                        bytecount::count(&buffer[last_line_end..each_match.start()], b'\n');
-                    line_count += num_lines + 1;
-                    last_line_end = each_match.end();
-                    let line_number = line_count.to_string();
-                    stdout
-                        .set_color(ColorSpec::new().set_bold(true).set_fg(Some(Color::Blue)))
-                        .unwrap();
-                    stdout.write(line_number.as_bytes()).unwrap();
-                    stdout.reset().unwrap();
-                    if match_options.print_format == PrintFormat::VisualStudio {
-                        write!(&mut stdout, ")").unwrap();
+            // the lines that aren't selected are only looked at for context
+            // and line numbers, and are kept across chunks by `lines`
+            let chunk = &buffer[..last_newline];
+            let mut line_start = 0;
+            // each match is followed by an empty one at the end of the chunk,
+            // so the lines after the last match are looked at too
+            let matches = g
+                .iter(chunk)
+                .map(|m| (m.start(), m.end()))
+                .chain(iter::once((chunk.len(), chunk.len())));
+            for (start, end) in matches {
+                let (unmatched, matched) = (&chunk[line_start..start], &chunk[start..end]);
+                line_start = end;
+                // with --word-regexp, the line may only match within words
+                let is_match = !matched.is_empty() && self.is_match(matched);
+                if !match_options.invert_match {
+                    lines.skip(unmatched)?;
+                    if !is_match {
+                        lines.skip(matched)?;
+                    } else if !lines.select(matched)? {
+                        break 'file;
                     }
-                    write!(&mut stdout, ":").unwrap();
+                    continue;
                 }
-                let line = &buffer[each_match.start()..each_match.end()];
-                if match_options.with_color {
-                    let mut start_from = 0;
-                    for m in matcher.find_iter(line) {
-                        let to_write = &line[start_from..m.start()];
-                        write!(&mut stdout, "{}", String::from_utf8_lossy(to_write)).unwrap();
-                        stdout
-                            .set_color(ColorSpec::new().set_bold(true).set_fg(Some(Color::Red)))
-                            .unwrap();
-                        let to_write = &line[m.start()..m.end()];
-                        write!(&mut stdout, "{}", String::from_utf8_lossy(to_write)).unwrap();
-                        stdout.reset().unwrap();
-                        start_from = m.end();
+                let mut unmatched = unmatched;
+                while !unmatched.is_empty() {
+                    let nl = memchr::memchr(b'\n', unmatched);
+                    let line_end = nl.map_or(unmatched.len(), |nl| nl + 1);
+                    if !lines.select(&unmatched[..line_end])? {
+                        break 'file;
                     }
-                    if start_from != line.len() {
-                        let to_write = String::from_utf8_lossy(&line[start_from..]);
-                        write!(&mut stdout, "{}", to_write).unwrap();
-                    }
-                } else {
-                    write!(&mut stdout, "{}", String::from_utf8_lossy(line)).unwrap()
+                    unmatched = &unmatched[line_end..];
                 }
-                if line.last() != Some(&b'\n') {
-                    stdout.write(&[b'\n']).unwrap();
+                if is_match || matched.is_empty() {
+                    lines.skip(matched)?;
+                } else if !lines.select(matched)? {
+                    break 'file;
                 }
-                stdout.flush().unwrap();
             }
-            if match_options.line_number {
-                let num_lines = bytecount::count(&buffer[last_line_end..last_newline], b'\n');
-                line_count += num_lines;
+            if lines.found.ends.len() == max_count && lines.after_left == 0 {
+                // the rest can't be printed
+                break 'file;
             }
             tmp.clear();
             tmp.extend_from_slice(&buffer[last_newline..total_bytes]);
@@ -402,10 +816,436 @@ pub fn main() {
             );
             buffer[..tmp.len()].copy_from_slice(&tmp);
         }
-        if match_options.print_count && num_matches != 0 {
-            writeln!(&mut stdout, "{}:{}", name.display(), num_matches).unwrap();
+        lines.finish()?;
+        Ok(lines.found)
+    }
+
+    /// Whether `line`, which the pattern matches, matches it as a whole
+    /// word if it has to
+    fn is_match(&self, line: &[u8]) -> bool {
+        !self.options.word_regexp || self.find_word(line, 0).is_some()
+    }
+
+    /// Returns where the pattern matches in `line`, which is only at whole
+    /// words with --word-regexp
+    fn find_matches(&self, line: &[u8]) -> Vec<Range<usize>> {
+        if !self.options.word_regexp {
+            return self
+                .matcher
+                .find_iter(line)
+                .map(|m| m.start()..m.end())
+                .collect();
+        }
+        let mut found = Vec::new();
+        let mut start = 0;
+        while let Some(m) = self.find_word(line, start) {
+            // an empty match is followed by the next one after it
+            start = if m.end > m.start { m.end } else { m.end + 1 };
+            found.push(m);
+        }
+        found
+    }
+
+    /// Returns the first match of the pattern in `line`, from `start` on,
+    /// that's a whole word.  As in grep, a match followed by a word
+    /// character is tried with every other end it could have, and a match
+    /// that still isn't a word is passed over, with the pattern looked for
+    /// again from just after where it started.
+    fn find_word(&self, line: &[u8], mut start: usize) -> Option<Range<usize>> {
+        // the newline isn't part of the line, so that $ matches before it
+        let line = match line.last() {
+            Some(&b'\n') => &line[..line.len() - 1],
+            _ => line,
+        };
+        let mut ends = self.word_end.locations();
+        while start <= line.len() {
+            let m = self.matcher.find_at(line, start)?;
+            if !char_before(line, m.start()).is_some_and(is_word_char) {
+                if !char_after(line, m.end()).is_some_and(is_word_char) {
+                    return Some(m.start()..m.end());
+                }
+                // the match found first runs into a word, but another one
+                // starting at the same place may not
+                let w = self.word_end.read_captures_at(&mut ends, line, m.start());
+                if w.is_some_and(|w| w.start() == m.start()) {
+                    // the last group follows the groups of the pattern
+                    let (end, _) = ends.pos(self.word_end.captures_len() - 1)?;
+                    return Some(m.start()..end);
+                }
+            }
+            start = m.start() + 1;
+        }
+        None
+    }
+}
+
+/// Builds the pattern of `options` followed by a non-word character or the
+/// end of the text, which is the last group, for `Searcher::word_end`
+fn word_end_regex(options: &MatchOptions) -> Result<bytes::Regex, regex::Error> {
+    bytes::RegexBuilder::new(&format!("(?:{})(\\W|$)", options.pattern))
+        .case_insensitive(options.ignore_case)
+        .multi_line(false)
+        .build()
+}
+
+/// Whether `c` is part of a word, as grep sees it
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Returns the character that ends at `i` in `line`, if it's valid UTF-8
+fn char_before(line: &[u8], i: usize) -> Option<char> {
+    (1..=cmp::min(i, 4))
+        .filter_map(|n| str::from_utf8(&line[i - n..i]).ok())
+        .next()
+        .and_then(|s| s.chars().next_back())
+}
+
+/// Returns the character that starts at `i` in `line`, if it's valid UTF-8
+fn char_after(line: &[u8], i: usize) -> Option<char> {
+    (1..=cmp::min(line.len() - i, 4))
+        .filter_map(|n| str::from_utf8(&line[i..i + n]).ok())
+        .next()
+        .and_then(|s| s.chars().next())
+}
+
+/// Prints the lines of a file that match, and the lines of context around
+/// them, given the file a piece at a time
+struct LinePrinter<'a> {
+    searcher: &'a Searcher<'a>,
+    found: Matches,
+    /// How many lines of the file have been given so far
+    line_count: usize,
+    /// How many bytes of the file have been given so far
+    offset: usize,
+    /// The number of the last line printed, or 0 if none has been
+    last_printed: usize,
+    /// Up to --before-context of the last lines given that weren't
+    /// printed, with their line numbers and offsets
+    before: VecDeque<(usize, usize, Vec<u8>)>,
+    /// How many of the next lines are printed as context after a match
+    after_left: usize,
+    /// How many lines have been selected
+    selected: usize,
+}
+
+impl<'a> LinePrinter<'a> {
+    fn new(searcher: &'a Searcher<'a>, name: &str, out: Buffer) -> Self {
+        LinePrinter {
+            searcher,
+            found: Matches {
+                name: searcher.path_simplifier.maybe_make_relative(name),
+                out,
+                ends: Vec::new(),
+            },
+            line_count: 0,
+            offset: 0,
+            last_printed: 0,
+            before: VecDeque::new(),
+            after_left: 0,
+            selected: 0,
+        }
+    }
+
+    /// Takes a line that's selected, which is one that matches, or with
+    /// --invert-match one that doesn't.  Returns whether the rest of the
+    /// file is needed.
+    fn select(&mut self, line: &[u8]) -> io::Result<bool> {
+        let options = self.searcher.options;
+        if self.found.ends.len() == options.max_count.unwrap_or(usize::MAX) {
+            // only printed as context
+            self.skip(line)?;
+            return Ok(true);
+        }
+        self.selected += 1;
+        if options.files_with_matches_only {
+            writeln!(self.found.out, "{}", self.found.name.display())?;
+            self.found.ends.push(self.found.out.len());
+            return Ok(false);
+        }
+        if options.files_without_match {
+            return Ok(false);
+        }
+        if options.print_count {
+            self.skip(line)?;
+            return Ok(true);
+        }
+        self.print_match(line)?;
+        Ok(true)
+    }
+
+    /// Prints what's printed once the whole file has been given
+    fn finish(&mut self) -> io::Result<()> {
+        let options = self.searcher.options;
+        if options.print_count && self.selected != 0 {
+            writeln!(
+                self.found.out,
+                "{}:{}",
+                self.found.name.display(),
+                self.selected
+            )?;
+            self.found.ends.push(self.found.out.len());
+        } else if options.files_without_match && self.selected == 0 {
+            writeln!(self.found.out, "{}", self.found.name.display())?;
+            self.found.ends.push(self.found.out.len());
+        }
+        Ok(())
+    }
+
+    /// Takes lines that don't match, printing the ones that are context
+    /// after the last match and keeping the ones that may be context before
+    /// the next.  `lines` is made of whole lines.
+    fn skip(&mut self, mut lines: &[u8]) -> io::Result<()> {
+        let options = self.searcher.options;
+        if !options.has_context() {
+            if options.line_number {
+                self.line_count += bytecount::count(lines, b'\n');
+            }
+            self.offset += lines.len();
+            return Ok(());
+        }
+        while !lines.is_empty() && self.after_left > 0 {
+            let end = memchr::memchr(b'\n', lines).map_or(lines.len(), |nl| nl + 1);
+            self.line_count += 1;
+            self.after_left -= 1;
+            let (line_number, offset) = (self.line_count, self.offset);
+            self.write_line(line_number, offset, &lines[..end], false)?;
+            // context after a match belongs to it
+            *self.found.ends.last_mut().unwrap() = self.found.out.len();
+            self.offset += end;
+            lines = &lines[end..];
+        }
+        if options.before_context == 0 || lines.is_empty() {
+            self.line_count += bytecount::count(lines, b'\n');
+            self.offset += lines.len();
+            return Ok(());
+        }
+        // only the last lines can be printed before the next match
+        let start = start_of_last_lines(lines, options.before_context);
+        if start > 0 {
+            self.line_count += bytecount::count(&lines[..start], b'\n');
+            self.offset += start;
+            self.before.clear();
+            lines = &lines[start..];
+        }
+        while !lines.is_empty() {
+            let end = memchr::memchr(b'\n', lines).map_or(lines.len(), |nl| nl + 1);
+            self.line_count += 1;
+            let mut line = if self.before.len() == options.before_context {
+                self.before.pop_front().unwrap().2
+            } else {
+                Vec::new()
+            };
+            line.clear();
+            line.extend_from_slice(&lines[..end]);
+            self.before.push_back((self.line_count, self.offset, line));
+            self.offset += end;
+            lines = &lines[end..];
+        }
+        Ok(())
+    }
+
+    /// Prints a line that matches, after the lines of context before it
+    fn print_match(&mut self, line: &[u8]) -> io::Result<()> {
+        self.line_count += 1;
+        let (line_number, offset) = (self.line_count, self.offset);
+        while let Some((n, o, l)) = self.before.pop_front() {
+            self.write_line(n, o, &l, false)?;
+        }
+        self.write_line(line_number, offset, line, true)?;
+        self.offset += line.len();
+        self.found.ends.push(self.found.out.len());
+        self.after_left = self.searcher.options.after_context;
+        Ok(())
+    }
+
+    /// Prints the line numbered `line_number`, which starts `offset` bytes
+    /// into the file and is a match if `is_match` is set and otherwise
+    /// context, preceded by a group separator if it doesn't follow the last
+    /// line printed
+    fn write_line(
+        &mut self,
+        line_number: usize,
+        offset: usize,
+        line: &[u8],
+        is_match: bool,
+    ) -> io::Result<()> {
+        let match_options = self.searcher.options;
+        if match_options.json {
+            return self.write_json_line(line_number, offset, line, is_match);
+        }
+        let stdout = &mut self.found.out;
+        if match_options.has_context()
+            && self.last_printed != 0
+            && line_number > self.last_printed + 1
+        {
+            writeln!(stdout, "--")?;
+        }
+        self.last_printed = line_number;
+        // grep marks context lines by the separator after the name
+        let separator = if is_match { ":" } else { "-" };
+        stdout.set_color(ColorSpec::new().set_bold(true).set_fg(Some(Color::Green)))?;
+        write!(stdout, "{}", self.found.name.display())?;
+        stdout.reset()?;
+        if match_options.print_format == PrintFormat::VisualStudio {
+            write!(stdout, "(")?;
+        } else {
+            write!(stdout, "{}", separator)?;
+        }
+        if match_options.line_number {
+            stdout.set_color(ColorSpec::new().set_bold(true).set_fg(Some(Color::Blue)))?;
+            stdout.write_all(line_number.to_string().as_bytes())?;
+            stdout.reset()?;
+            if match_options.print_format == PrintFormat::VisualStudio {
+                write!(stdout, ")")?;
+            }
+            write!(stdout, "{}", separator)?;
+        }
+        if match_options.with_color && is_match {
+            let mut start_from = 0;
+            for m in self.searcher.find_matches(line) {
+                let to_write = &line[start_from..m.start];
+                write!(stdout, "{}", String::from_utf8_lossy(to_write))?;
+                stdout.set_color(ColorSpec::new().set_bold(true).set_fg(Some(Color::Red)))?;
+                let to_write = &line[m.start..m.end];
+                write!(stdout, "{}", String::from_utf8_lossy(to_write))?;
+                stdout.reset()?;
+                start_from = m.end;
+            }
+            if start_from != line.len() {
+                let to_write = String::from_utf8_lossy(&line[start_from..]);
+                write!(stdout, "{}", to_write)?;
+            }
+        } else {
+            write!(stdout, "{}", String::from_utf8_lossy(line))?
+        }
+        if line.last() != Some(&b'\n') {
+            stdout.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> LinePrinter<'a> {
+    /// Prints a line as a JSON record, after a record beginning the file if
+    /// it's the first line printed
+    fn write_json_line(
+        &mut self,
+        line_number: usize,
+        offset: usize,
+        line: &[u8],
+        is_match: bool,
+    ) -> io::Result<()> {
+        let out = &mut self.found.out;
+        if out.is_empty() {
+            write!(out, "{{\"type\":\"begin\",\"data\":{{\"path\":")?;
+            write_json_data(out, self.found.name.to_string_lossy().as_bytes())?;
+            writeln!(out, "}}}}")?;
+        }
+        let kind = if is_match { "match" } else { "context" };
+        write!(out, "{{\"type\":\"{}\",\"data\":{{\"path\":", kind)?;
+        write_json_data(out, self.found.name.to_string_lossy().as_bytes())?;
+        write!(out, ",\"lines\":")?;
+        write_json_data(out, line)?;
+        write!(
+            out,
+            ",\"line_number\":{},\"absolute_offset\":{}",
+            line_number, offset
+        )?;
+        let submatches: Vec<_> = if is_match {
+            self.searcher.find_matches(line)
+        } else {
+            Vec::new()
+        };
+        if let Some(first) = submatches.first() {
+            write!(out, ",\"column\":{}", first.start + 1)?;
+        }
+        write!(out, ",\"submatches\":[")?;
+        for (i, m) in submatches.iter().enumerate() {
+            if i > 0 {
+                write!(out, ",")?;
+            }
+            write!(out, "{{\"match\":")?;
+            write_json_data(out, &line[m.clone()])?;
+            write!(out, ",\"start\":{},\"end\":{}}}", m.start, m.end)?;
+        }
+        writeln!(out, "]}}}}")
+    }
+}
+
+/// Writes the JSON record ending the matches printed for the file `name`
+fn write_json_end<W: Write>(out: &mut W, name: &Path, matched_lines: usize) -> io::Result<()> {
+    write!(out, "{{\"type\":\"end\",\"data\":{{\"path\":")?;
+    write_json_data(out, name.to_string_lossy().as_bytes())?;
+    writeln!(
+        out,
+        ",\"stats\":{{\"matched_lines\":{}}}}}}}",
+        matched_lines
+    )
+}
+
+/// Writes `data` as a JSON object holding it as a string if it's UTF-8, or
+/// else encoded in base64: `{"text":"..."}` or `{"bytes":"..."}`
+fn write_json_data<W: Write>(out: &mut W, data: &[u8]) -> io::Result<()> {
+    let s = match str::from_utf8(data) {
+        Ok(s) => s,
+        Err(_) => return write!(out, "{{\"bytes\":\"{}\"}}", base64(data)),
+    };
+    write!(out, "{{\"text\":\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(out, "\\\"")?,
+            '\\' => write!(out, "\\\\")?,
+            '\n' => write!(out, "\\n")?,
+            '\r' => write!(out, "\\r")?,
+            '\t' => write!(out, "\\t")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?,
+        }
+    }
+    write!(out, "\"}}")
+}
+
+/// Encodes `data` in standard base64, with padding
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).cloned().unwrap_or(0),
+            chunk.get(2).cloned().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
         }
     }
+    out
+}
+
+/// Returns where the last `n` lines of `lines` start, or 0 if it has no more
+/// than `n` lines.  The last line needn't end in a newline.
+fn start_of_last_lines(lines: &[u8], n: usize) -> usize {
+    let mut start = lines.len();
+    let mut end = match lines.last() {
+        Some(&b'\n') => lines.len() - 1,
+        _ => lines.len(),
+    };
+    for _ in 0..n {
+        match memchr::memrchr(b'\n', &lines[..end]) {
+            Some(nl) => {
+                start = nl + 1;
+                end = nl;
+            }
+            None => return 0,
+        }
+    }
+    start
 }
 
 struct PathSimplifier {
@@ -433,3 +1273,591 @@ impl PathSimplifier {
         }
     }
 }
+
+/// Formats a set of strings from a RegexInfo, quoting each one
+fn format_string_set(set: &StringSet) -> String {
+    let strings = set
+        .iter()
+        .map(|s| format!("{:?}", String::from_utf8_lossy(s)))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", strings.join(", "))
+}
+
+/// Prints how `pattern` is turned into a query, how the query is planned,
+/// and how many files are left after each step of the plan
+fn explain<W: Write>(
+    out: &mut W,
+    index_reader: &MultiIndexReader,
+    pattern: &str,
+    info: RegexInfo,
+) -> io::Result<()> {
+    writeln!(out, "pattern: {}", pattern)?;
+    writeln!(out, "can empty: {}", info.can_empty)?;
+    match info.exact_set {
+        Some(ref exact) => writeln!(out, "exact: {}", format_string_set(exact))?,
+        None => writeln!(out, "exact: none")?,
+    }
+    writeln!(out, "prefix: {}", format_string_set(&info.prefix))?;
+    writeln!(out, "suffix: {}", format_string_set(&info.suffix))?;
+    writeln!(out, "query: {}", info.query.format_as_string())?;
+
+    for (path, index) in index_reader.paths().iter().zip(index_reader.indexes()) {
+        let plan = index.plan(&info.query);
+        writeln!(
+            out,
+            "plan for {} ({} files):",
+            path.display(),
+            index.num_name
+        )?;
+        explain_plan(out, path, index, &plan, 1, None)?;
+    }
+    let candidates = read_or_exit(index_reader.query(&info.query));
+    writeln!(out, "candidates: {}", candidates.len())
+}
+
+/// Prints `plan` indented by `depth` levels.  The operands of an AND or OR
+/// are followed by the number of candidate files after they're applied.
+fn explain_plan<W: Write>(
+    out: &mut W,
+    path: &Path,
+    index_reader: &IndexReader,
+    plan: &QueryPlan,
+    depth: usize,
+    step: Option<usize>,
+) -> io::Result<()> {
+    let pad = "  ".repeat(depth);
+    let step = match step {
+        Some(n) => format!(" -> {} candidates", n),
+        None => String::new(),
+    };
+    let (name, operands, skipped) = match *plan {
+        QueryPlan::And {
+            ref operands,
+            ref skipped,
+        } => ("and", operands, &skipped[..]),
+        QueryPlan::Or(ref operands) => ("or", operands, &[][..]),
+        _ => {
+            return writeln!(out, "{}{}{}", pad, plan.to_string().trim_end(), step);
+        }
+    };
+    writeln!(out, "{}{}{}", pad, name, step)?;
+    let steps = index_reader
+        .plan_steps(plan)
+        .unwrap_or_else(|e| exit_with_error(format!("{}: {}", path.display(), e)));
+    for (p, n) in operands.iter().zip(steps) {
+        explain_plan(out, path, index_reader, p, depth + 1, Some(n))?;
+    }
+    if !skipped.is_empty() {
+        writeln!(out, "{}skipped", pad)?;
+        for p in skipped {
+            for line in p.to_string().lines() {
+                writeln!(out, "{}  {}", pad, line)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the result of reading the indexes, or exits with an error if
+/// they couldn't be read.  The errors name the index they came from.
+fn read_or_exit<T>(r: IndexReadResult<T>) -> T {
+    r.unwrap_or_else(|e| exit_with_error(e))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::NamedTempFile;
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Collects what's printed instead of writing it to stdout
+    #[derive(Default)]
+    struct Captured(Mutex<Vec<u8>>);
+
+    impl Output for Captured {
+        fn buffer(&self) -> Buffer {
+            Buffer::no_color()
+        }
+        fn print(&self, buf: &Buffer) -> io::Result<()> {
+            self.0.lock().unwrap().extend_from_slice(buf.as_slice());
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn options(pattern: &str) -> MatchOptions {
+        MatchOptions {
+            pattern: pattern.to_string(),
+            print_format: PrintFormat::Normal,
+            print_count: false,
+            ignore_case: false,
+            files_with_matches_only: false,
+            files_without_match: false,
+            invert_match: false,
+            word_regexp: false,
+            line_number: false,
+            with_color: false,
+            max_count: None,
+            before_context: 0,
+            after_context: 0,
+            json: false,
+        }
+    }
+
+    fn searcher<'a>(options: &'a MatchOptions) -> Searcher<'a> {
+        Searcher {
+            grep: GrepBuilder::new(&options.pattern).build().unwrap(),
+            matcher: bytes::RegexBuilder::new(&options.pattern)
+                .multi_line(false)
+                .build()
+                .unwrap(),
+            word_end: word_end_regex(options).unwrap(),
+            candidates: None,
+            path_simplifier: PathSimplifier {
+                make_relative: false,
+            },
+            options,
+        }
+    }
+
+    fn temp_file(contents: &str) -> NamedTempFile {
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(contents.as_bytes()).unwrap();
+        f
+    }
+
+    fn name(f: &NamedTempFile) -> String {
+        f.path().to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_search_files_in_order() {
+        // files with different numbers of matches take different times
+        let files = (0..40)
+            .map(|i| {
+                let mut contents = String::new();
+                for j in 0..(i * 37) % 11 {
+                    contents.push_str(&format!("match {} {}\nskipped\n", i, j));
+                }
+                contents.push_str(&"x".repeat(i * 1000));
+                temp_file(&contents)
+            })
+            .collect::<Vec<_>>();
+        let names = files.iter().map(name).collect::<Vec<_>>();
+        let mut expected = String::new();
+        for (i, name) in names.iter().enumerate() {
+            for j in 0..(i * 37) % 11 {
+                expected.push_str(&format!("{}:match {} {}\n", name, i, j));
+            }
+        }
+
+        let options = options("match");
+        let searcher = searcher(&options);
+        for &threads in [1, 3, 16].iter() {
+            let out = Captured::default();
+            let summary = search_files(&searcher, &out, names.clone(), threads);
+            assert_eq!(out.text(), expected);
+            assert!(summary.matched && !summary.failed);
+        }
+    }
+
+    #[test]
+    fn test_search_files_max_count() {
+        let f = temp_file("skipped\nmatch 1\nmatch 2\n");
+        let mut options = options("match");
+        options.max_count = Some(3);
+        let searcher = searcher(&options);
+        let threads = 4;
+        let taken = AtomicUsize::new(0);
+        let names = (0..200).map(|_| {
+            taken.fetch_add(1, Ordering::SeqCst);
+            name(&f)
+        });
+
+        let out = Captured::default();
+        search_files(&searcher, &out, names, threads);
+        let expected = format!("{0}:match 1\n{0}:match 2\n{0}:match 1\n", name(&f));
+        assert_eq!(out.text(), expected);
+        // no more than the files searched ahead of the two printed are taken
+        let ahead = threads * FILES_AHEAD_PER_THREAD;
+        assert!(taken.load(Ordering::SeqCst) <= 2 + ahead + 1);
+    }
+
+    #[test]
+    fn test_start_of_last_lines() {
+        let lines = b"a\nbb\nc\n";
+        assert_eq!(start_of_last_lines(lines, 0), lines.len());
+        assert_eq!(start_of_last_lines(lines, 1), 5);
+        assert_eq!(start_of_last_lines(lines, 2), 2);
+        assert_eq!(start_of_last_lines(lines, 3), 0);
+        assert_eq!(start_of_last_lines(lines, 4), 0);
+        // the last line needn't end in a newline
+        assert_eq!(start_of_last_lines(b"a\nbb\nc", 1), 5);
+        assert_eq!(start_of_last_lines(b"a\nbb\nc", 2), 2);
+        assert_eq!(start_of_last_lines(b"", 2), 0);
+    }
+
+    fn context_options(before: usize, after: usize) -> MatchOptions {
+        let mut options = options("match");
+        options.before_context = before;
+        options.after_context = after;
+        options.line_number = true;
+        options
+    }
+
+    /// Returns what's printed for the file `f` when it's given as `pieces`,
+    /// which are a line that matches or lines that don't
+    fn print_lines(options: &MatchOptions, pieces: &[(&str, bool)]) -> String {
+        let searcher = searcher(options);
+        let mut lines = LinePrinter::new(&searcher, "f", Buffer::no_color());
+        for &(piece, is_match) in pieces {
+            if is_match {
+                lines.print_match(piece.as_bytes()).unwrap();
+            } else {
+                lines.skip(piece.as_bytes()).unwrap();
+            }
+        }
+        String::from_utf8(lines.found.out.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn test_line_printer_separators() {
+        let pieces = [
+            ("1\n2\n", false),
+            ("3\n", true),
+            ("4\n5\n6\n", false),
+            ("7\n", true),
+            ("8\n9\n", false),
+        ];
+        assert_eq!(
+            print_lines(&context_options(1, 1), &pieces),
+            "f-2-2\nf:3:3\nf-4-4\n--\nf-6-6\nf:7:7\nf-8-8\n"
+        );
+        assert_eq!(
+            print_lines(&context_options(2, 0), &pieces),
+            "f-1-1\nf-2-2\nf:3:3\n--\nf-5-5\nf-6-6\nf:7:7\n"
+        );
+        // groups that are next to each other aren't separated
+        assert_eq!(
+            print_lines(&context_options(3, 0), &pieces),
+            "f-1-1\nf-2-2\nf:3:3\nf-4-4\nf-5-5\nf-6-6\nf:7:7\n"
+        );
+        assert_eq!(
+            print_lines(&context_options(0, 0), &pieces),
+            "f:3:3\nf:7:7\n"
+        );
+    }
+
+    #[test]
+    fn test_line_printer_overlapping_context() {
+        // lines 3 and 4 are context both after 2 and before 5, and are only
+        // printed once; line 8 is too far from either match
+        let pieces = [
+            ("1\n", false),
+            ("2\n", true),
+            ("3\n4\n", false),
+            ("5\n", true),
+            ("6\n7\n8\n9\n10\n", false),
+            ("11\n", true),
+        ];
+        assert_eq!(
+            print_lines(&context_options(2, 2), &pieces),
+            "f-1-1\nf:2:2\nf-3-3\nf-4-4\nf:5:5\nf-6-6\nf-7-7\n--\n\
+             f-9-9\nf-10-10\nf:11:11\n"
+        );
+        // context before a match is limited to the lines since the last one
+        assert_eq!(
+            print_lines(&context_options(3, 0), &pieces[..4]),
+            "f-1-1\nf:2:2\nf-3-3\nf-4-4\nf:5:5\n"
+        );
+    }
+
+    #[test]
+    fn test_context_across_chunks() {
+        // files are read 4 KiB at a time, and the lines of context before
+        // line 43 start in the chunk before it
+        let mut contents = String::new();
+        for i in 1..=50 {
+            let line = if i == 43 { "match" } else { "line" };
+            contents.push_str(&format!("{:<98}{}\n", line, i % 10));
+        }
+        assert_eq!(contents.len(), 50 * 100);
+        let f = temp_file(&contents);
+        let options = context_options(6, 1);
+        let searcher = searcher(&options);
+        let found = searcher
+            .search_file(&name(&f), Buffer::no_color(), &AtomicBool::new(false))
+            .unwrap();
+
+        let mut expected = String::new();
+        for i in 37..=44 {
+            let sep = if i == 43 { ':' } else { '-' };
+            let line = &contents[(i - 1) * 100..i * 100];
+            expected.push_str(&format!("{}{}{}{}{}", name(&f), sep, i, sep, line));
+        }
+        assert_eq!(String::from_utf8(found.out.into_inner()).unwrap(), expected);
+        assert_eq!(found.ends, vec![expected.len()]);
+    }
+
+    fn json_data(data: &[u8]) -> String {
+        let mut out = Vec::new();
+        write_json_data(&mut out, data).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_write_json_data() {
+        assert_eq!(json_data(b"int x;"), r#"{"text":"int x;"}"#);
+        assert_eq!(json_data(b"say \"hi\\\"\n"), r#"{"text":"say \"hi\\\"\n"}"#);
+        assert_eq!(json_data(b"\t\r\x00\x1f"), r#"{"text":"\t\r\u0000\u001f"}"#);
+        assert_eq!(
+            json_data("caf\u{e9}".as_bytes()),
+            "{\"text\":\"caf\u{e9}\"}"
+        );
+        assert_eq!(json_data(b"caf\xe9"), r#"{"bytes":"Y2Fm6Q=="}"#);
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(b"\xff\xfe\xfd"), "//79");
+    }
+
+    #[test]
+    fn test_write_json_line() {
+        let mut options = options("x");
+        options.json = true;
+        options.line_number = true;
+        let searcher = searcher(&options);
+        let mut lines = LinePrinter::new(&searcher, "a.c", Buffer::no_color());
+        lines.skip(b"int a;\n").unwrap();
+        lines.print_match(b"int x, \"x\";\n").unwrap();
+        lines.print_match(b"x\xff\n").unwrap();
+        let out = String::from_utf8(lines.found.out.into_inner()).unwrap();
+        let expected = [
+            r#"{"type":"begin","data":{"path":{"text":"a.c"}}}"#,
+            concat!(
+                r#"{"type":"match","data":{"path":{"text":"a.c"},"#,
+                r#""lines":{"text":"int x, \"x\";\n"},"line_number":2,"#,
+                r#""absolute_offset":7,"column":5,"submatches":["#,
+                r#"{"match":{"text":"x"},"start":4,"end":5},"#,
+                r#"{"match":{"text":"x"},"start":8,"end":9}]}}"#
+            ),
+            concat!(
+                r#"{"type":"match","data":{"path":{"text":"a.c"},"#,
+                r#""lines":{"bytes":"eP8K"},"line_number":3,"#,
+                r#""absolute_offset":19,"column":1,"submatches":["#,
+                r#"{"match":{"text":"x"},"start":0,"end":1}]}}"#
+            ),
+        ];
+        assert_eq!(out, expected.join("\n") + "\n");
+
+        let mut end = Vec::new();
+        write_json_end(&mut end, Path::new("a.c"), 2).unwrap();
+        assert_eq!(
+            String::from_utf8(end).unwrap(),
+            "{\"type\":\"end\",\"data\":{\"path\":{\"text\":\"a.c\"},\
+             \"stats\":{\"matched_lines\":2}}}\n"
+        );
+    }
+
+    #[test]
+    fn test_files_without_match() {
+        let matches = temp_file("a match\n");
+        let candidate = temp_file("no luck\n");
+        let mut options = options("match");
+        options.files_without_match = true;
+        let mut searcher = searcher(&options);
+        // the index rules the last file out, so it isn't even opened
+        let ruled_out = "/does/not/exist".to_string();
+        searcher.candidates = Some(vec![name(&matches), name(&candidate)].into_iter().collect());
+        let names = vec![name(&matches), name(&candidate), ruled_out.clone()];
+
+        let out = Captured::default();
+        let summary = search_files(&searcher, &out, names, 2);
+        assert_eq!(out.text(), format!("{}\n{}\n", name(&candidate), ruled_out));
+        assert!(!summary.failed);
+        assert_eq!(summary.exit_status(), EXIT_MATCH);
+    }
+
+    #[test]
+    fn test_invert_match() {
+        let f = temp_file("a match\nno luck\nmatch again\nnone\n");
+        let mut options = options("match");
+        options.invert_match = true;
+        options.line_number = true;
+        let searcher = searcher(&options);
+        let out = Captured::default();
+        let summary = search_files(&searcher, &out, vec![name(&f)], 1);
+        assert_eq!(out.text(), format!("{0}:2:no luck\n{0}:4:none\n", name(&f)));
+        assert_eq!(summary.exit_status(), EXIT_MATCH);
+
+        // every line matches, so none is selected
+        let f = temp_file("a match\nmatch again\n");
+        let out = Captured::default();
+        let summary = search_files(&searcher, &out, vec![name(&f)], 1);
+        assert_eq!(out.text(), "");
+        assert_eq!(summary.exit_status(), EXIT_NO_MATCH);
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let status = |matched, failed| Summary { matched, failed }.exit_status();
+        assert_eq!(status(true, false), EXIT_MATCH);
+        assert_eq!(status(false, false), EXIT_NO_MATCH);
+        assert_eq!(status(false, true), EXIT_ERROR);
+        // an error wins over matches
+        assert_eq!(status(true, true), EXIT_ERROR);
+        assert_eq!(Summary::default().exit_status(), EXIT_NO_MATCH);
+    }
+
+    #[test]
+    fn test_write_error_status() {
+        let status = |kind| write_error_status(&io::Error::new(kind, "write"));
+        // nobody is left to tell when the reader of a pipe goes away
+        assert_eq!(status(io::ErrorKind::BrokenPipe), EXIT_MATCH);
+        assert_eq!(status(io::ErrorKind::Other), EXIT_ERROR);
+        assert_eq!(status(io::ErrorKind::WriteZero), EXIT_ERROR);
+    }
+
+    #[test]
+    fn test_open_failure() {
+        let f = temp_file("a match\n");
+        let missing = format!("{}.missing", name(&f));
+        let options = options("match");
+        let searcher = searcher(&options);
+
+        // the files after the one that failed are still printed
+        let names = vec![missing.clone(), name(&f), missing.clone(), name(&f)];
+        let out = Captured::default();
+        let summary = search_files(&searcher, &out, names, 2);
+        assert_eq!(out.text(), format!("{0}:a match\n{0}:a match\n", name(&f)));
+        assert!(summary.matched && summary.failed);
+        assert_eq!(summary.exit_status(), EXIT_ERROR);
+
+        let out = Captured::default();
+        let summary = search_files(&searcher, &out, vec![missing], 1);
+        assert_eq!(out.text(), "");
+        assert!(!summary.matched && summary.failed);
+        assert_eq!(summary.exit_status(), EXIT_ERROR);
+    }
+
+    #[test]
+    fn test_print_in_order_open_failure() {
+        let options = options("match");
+        let out = Captured::default();
+        let (found_tx, found_rx) = mpsc::channel();
+        let (slot_tx, slot_rx) = mpsc::sync_channel(3);
+        let found = |text: &str| {
+            let mut buf = out.buffer();
+            buf.write_all(text.as_bytes()).unwrap();
+            Ok(Matches {
+                name: PathBuf::from(text),
+                out: buf,
+                ends: vec![text.len()],
+            })
+        };
+        // the files arrive out of order, and the failed one isn't printed
+        found_tx.send((2, "c".to_string(), found("c\n"))).unwrap();
+        let failed = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        found_tx.send((1, "b".to_string(), Err(failed))).unwrap();
+        found_tx.send((0, "a".to_string(), found("a\n"))).unwrap();
+        drop(found_tx);
+        for _ in 0..3 {
+            slot_tx.send(()).unwrap();
+        }
+
+        let mut summary = Summary::default();
+        print_in_order(&options, &out, found_rx, slot_rx, &mut summary).unwrap();
+        assert_eq!(out.text(), "a\nc\n");
+        assert!(summary.matched && summary.failed);
+        assert_eq!(summary.exit_status(), EXIT_ERROR);
+    }
+
+    fn word_options(pattern: &str) -> MatchOptions {
+        let mut options = options(pattern);
+        options.word_regexp = true;
+        options
+    }
+
+    /// Returns the whole-word matches of the regex `pattern` in `line`
+    fn regex_words(pattern: &str, line: &str) -> Vec<(usize, usize)> {
+        let options = word_options(pattern);
+        searcher(&options)
+            .find_matches(line.as_bytes())
+            .into_iter()
+            .map(|m| (m.start, m.end))
+            .collect()
+    }
+
+    /// Returns the whole-word matches of the literal `pattern` in `line`
+    fn words(pattern: &str, line: &str) -> Vec<(usize, usize)> {
+        regex_words(&regex::escape(pattern), line)
+    }
+
+    #[test]
+    fn test_word_regexp() {
+        // the characters around the match are checked, not the edges of
+        // the pattern
+        assert_eq!(words("foo.bar(", "foo.bar(x)\n"), vec![]);
+        assert_eq!(words("foo.bar(", "foo.bar( x\n"), vec![(0, 8)]);
+        assert_eq!(words("(a)", "x = (a);\n"), vec![(4, 7)]);
+        assert_eq!(words("(a)", "x(a)\n"), vec![]);
+        // matches next to word characters are passed over for later ones
+        assert_eq!(words("foo", "foobar foo_ foo\n"), vec![(12, 15)]);
+        assert_eq!(words("foo", "foo foo"), vec![(0, 3), (4, 7)]);
+        assert_eq!(words("oo", "foo"), vec![]);
+        // letters aren't only ASCII
+        assert_eq!(words("caf", "caf\u{e9} caf\n"), vec![(6, 9)]);
+    }
+
+    #[test]
+    fn test_word_regexp_alternation() {
+        // every alternative starting where the first one did is tried
+        assert_eq!(regex_words("foo|foo_bar", "foo_bar\n"), vec![(0, 7)]);
+        assert_eq!(
+            regex_words("foo_bar|foo", "foo_bar foo_baz foo\n"),
+            vec![(0, 7), (16, 19)]
+        );
+        assert_eq!(regex_words("foo|foo_bar", "foo_baz\n"), vec![]);
+        assert_eq!(regex_words("(f)(o)o|(foo)_bar", "foo_bar\n"), vec![(0, 7)]);
+        // as are the other ends of a repetition
+        assert_eq!(regex_words("a+", "aaab aa"), vec![(5, 7)]);
+        assert_eq!(regex_words("[a-z]+?", "ab-c"), vec![(0, 2), (3, 4)]);
+        assert_eq!(regex_words(r"x\w*?", "xyz"), vec![(0, 3)]);
+        // $ in the pattern still only matches at the end of the line
+        assert_eq!(regex_words("foo|foo_bar$", "foo_bar\n"), vec![(0, 7)]);
+        assert_eq!(regex_words("a|ab$", "ab c\n"), vec![]);
+        assert_eq!(regex_words("a|ab$", "ab\n"), vec![(0, 2)]);
+    }
+
+    #[test]
+    fn test_word_regexp_lines() {
+        let f = temp_file("foo.bar(x)\nfoo.bar( y)\nfoobar\n");
+        let options = word_options(&regex::escape("foo.bar("));
+        let out = Captured::default();
+        let summary = search_files(&searcher(&options), &out, vec![name(&f)], 1);
+        assert_eq!(out.text(), format!("{}:foo.bar( y)\n", name(&f)));
+        assert_eq!(summary.exit_status(), EXIT_MATCH);
+
+        // with -v, lines where the pattern is only part of a word are selected
+        let mut options = word_options("foo");
+        options.invert_match = true;
+        let out = Captured::default();
+        search_files(&searcher(&options), &out, vec![name(&f)], 1);
+        assert_eq!(out.text(), format!("{}:foobar\n", name(&f)));
+    }
+}
@@ -1,2 +1,4 @@
 pub const MAGIC: &'static str = "csearch index 1\n";
+pub const MAGIC_V2: &'static str = "csearch index 2\n";
+pub const MAGIC_V3: &'static str = "csearch index 3\n";
 pub const TRAILER_MAGIC: &'static str = "\ncsearch trailr\n";
@@ -1,4 +1,7 @@
 extern crate byteorder;
+extern crate crc32fast;
+#[cfg(unix)]
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate memmap;
@@ -9,5 +12,6 @@ extern crate libcsearch;
 extern crate libprofiling;
 extern crate libvarint;
 
+pub mod lock;
 pub mod merge;
 pub mod writer;
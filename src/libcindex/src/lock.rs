@@ -0,0 +1,187 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Updating an index in place.
+//
+// An index is never written where it's read from.  The new index goes to a
+// temporary file next to it, named by adding '~' to the name of the index,
+// which is flushed to disk and then renamed over the old index in one step.
+// Readers see either the old index or the new one, and a crash at any point
+// leaves the old index as it was, plus at worst a temporary file.
+//
+// On Unix, updates to the same index are serialized by an advisory lock on
+// a file named by adding ".lock" to the name of the index.  The lock is
+// dropped by the operating system when the process holding it exits, so a
+// crash never leaves an index locked; the lock file itself is left behind and
+// reused.  Whoever holds the lock owns the temporary files, so it removes any
+// left over from a crashed update.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The suffixes of the temporary files used to update an index
+const TEMP_SUFFIXES: [&str; 2] = ["~", "~~"];
+
+/// An exclusive lock on updating an index, held until it's dropped
+#[derive(Debug)]
+pub struct IndexLock {
+    index: PathBuf,
+    file: File,
+}
+
+impl IndexLock {
+    /// Locks the index at `index` for updating, waiting for any other
+    /// process updating it to finish, and removes the temporary files left
+    /// over from updates that didn't finish.
+    ///
+    /// ```no_run
+    /// # use libcindex::lock::IndexLock;
+    /// let lock = IndexLock::acquire("index").unwrap();
+    /// // write the new index to lock.temp_path(1), then
+    /// lock.replace(lock.temp_path(1)).unwrap();
+    /// ```
+    pub fn acquire<P: AsRef<Path>>(index: P) -> io::Result<IndexLock> {
+        let index = index.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(with_suffix(&index, ".lock"))?;
+        if !try_lock(&file)? {
+            info!("waiting for another update of {}", index.display());
+            lock(&file)?;
+        }
+        let lock = IndexLock { index, file };
+        for i in 1..=TEMP_SUFFIXES.len() {
+            let temp = lock.temp_path(i);
+            if temp.exists() {
+                info!("remove {} left by an earlier update", temp.display());
+                fs::remove_file(&temp)?;
+            }
+        }
+        Ok(lock)
+    }
+
+    /// Returns the path of the index
+    pub fn index_path(&self) -> &Path {
+        &self.index
+    }
+
+    /// Returns the path of the `n`th temporary file (from 1) to write while
+    /// updating the index
+    pub fn temp_path(&self, n: usize) -> PathBuf {
+        with_suffix(&self.index, TEMP_SUFFIXES[n - 1])
+    }
+
+    /// Flushes the finished index at `new` to disk and renames it over the
+    /// index, replacing it in one step
+    pub fn replace<P: AsRef<Path>>(&self, new: P) -> io::Result<()> {
+        replace_file(new, &self.index)
+    }
+
+    /// Removes the index
+    pub fn remove(&self) -> io::Result<()> {
+        match fs::remove_file(&self.index) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            r => r,
+        }
+    }
+}
+
+impl Drop for IndexLock {
+    fn drop(&mut self) {
+        let _ = unlock(&self.file);
+    }
+}
+
+/// Takes an exclusive lock on `file` if no one else holds one, returning
+/// whether it did
+#[cfg(unix)]
+fn try_lock(file: &File) -> io::Result<bool> {
+    match flock(file, libc::LOCK_EX | libc::LOCK_NB) {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        r => r.map(|()| true),
+    }
+}
+
+/// Takes an exclusive lock on `file`, waiting for whoever holds it
+#[cfg(unix)]
+fn lock(file: &File) -> io::Result<()> {
+    flock(file, libc::LOCK_EX)
+}
+
+#[cfg(unix)]
+fn unlock(file: &File) -> io::Result<()> {
+    flock(file, libc::LOCK_UN)
+}
+
+#[cfg(unix)]
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn try_lock(_file: &File) -> io::Result<bool> {
+    // updates aren't serialized, only made atomic by the rename
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+fn lock(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn unlock(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Flushes the file at `new` to disk and renames it to `dest`, replacing
+/// whatever was there in one step
+pub fn replace_file<P1, P2>(new: P1, dest: P2) -> io::Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    File::open(new.as_ref())?.sync_all()?;
+    fs::rename(new.as_ref(), dest.as_ref())?;
+    sync_dir(dest.as_ref())
+}
+
+/// Flushes the directory holding `path` to disk, so that a rename into it
+/// survives a crash
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(d) if d != Path::new("") => d,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    // directories can't be opened as files, and renames are durable anyway
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = OsString::from(path.as_os_str());
+    s.push(suffix);
+    PathBuf::from(s)
+}
@@ -29,20 +29,31 @@
 //
 // Copy the name index and posting list index into C's index and write the trailer.
 // Rename C's index onto the new index.
+//
+// Merging more than two indexes works the same way, except that each file is
+// shadowed by the paths of every newer index: merge_many gives the same result
+// as merging the indexes into the oldest one by one, without writing the
+// indexes in between.
+//
+// C is written in the same format version as B, so that merging in a freshly
+// written index decides the format of the result.  Prune and remove keep the
+// version of the index they rewrite.  Either way, C gets wide offsets if it
+// turns out to be too large for 4-byte ones.
 
-use libcsearch::reader::IndexReader;
+use libcsearch::reader::{FileMeta, IndexReader};
 use libprofiling;
-use writer::{copy_file, get_offset};
+use writer::{copy_offsets, fit_format, get_offset, write_trailer};
 
 use byteorder::{BigEndian, WriteBytesExt};
-use consts;
 use tempfile::tempfile;
 
 use super::postdatawriter::PostDataWriter;
 use super::postmapreader::{IdRange, PostMapReader};
 
-use std::fs::File;
-use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
 use std::path::Path;
 use std::u32;
 
@@ -55,8 +66,8 @@ where
     let _frame_merge = libprofiling::profile("merge");
     let ix1 = IndexReader::open(src1)?;
     let ix2 = IndexReader::open(src2)?;
-    let paths1 = ix1.indexed_paths();
-    let paths2 = ix2.indexed_paths();
+    let paths1 = ix1.indexed_paths()?;
+    let paths2 = ix2.indexed_paths()?;
 
     let mut i1: u32 = 0;
     let mut i2: u32 = 0;
@@ -66,7 +77,7 @@ where
     for path in &paths2 {
         let _frame = libprofiling::profile("merge: merge indexed paths");
         let old = i1;
-        while (i1 as usize) < ix1.num_name && ix1.name(i1 as u32) < *path {
+        while (i1 as usize) < ix1.num_name && ix1.name(i1 as u32)? < *path {
             i1 += 1;
         }
         let mut lo = i1;
@@ -76,7 +87,7 @@ where
             let l2_u = l2.chars().next().unwrap() as u8;
             l1.to_string() + &((l2_u + 1) as char).to_string()
         };
-        while (i1 as usize) < ix1.num_name && ix1.name(i1 as u32) < limit {
+        while (i1 as usize) < ix1.num_name && ix1.name(i1 as u32)? < limit {
             i1 += 1;
         }
 
@@ -93,11 +104,11 @@ where
         // Determine range defined by this path.
         // Because we are iterating over the ix2 paths,
         // there can't be gaps, so it must start at i2.
-        if (i2 as usize) < ix2.num_name && ix2.name(i2) < *path {
-            panic!("merge: inconsistent index ({} < {})", ix2.name(i2), *path);
+        if (i2 as usize) < ix2.num_name && ix2.name(i2)? < *path {
+            return Err(inconsistent(format!("{} < {}", ix2.name(i2)?, *path)));
         }
         lo = i2;
-        while (i2 as usize) < ix2.num_name && ix2.name(i2) < limit {
+        while (i2 as usize) < ix2.num_name && ix2.name(i2)? < limit {
             i2 += 1;
         }
         let hi = i2;
@@ -117,16 +128,298 @@ where
             high: ix1.num_name as u32,
             new: new,
         });
-        new += (ix1.num_name as u32) - i1;
     }
     if (i2 as usize) < ix2.num_name {
-        panic!("merge: inconsistent index ({} < {})", i2, ix2.num_name);
+        return Err(inconsistent(format!("{} < {}", i2, ix2.num_name)));
     }
-    let num_name = new;
-    let mut ix3 = BufWriter::new(File::create(dest)?);
-    ix3.write(consts::MAGIC.as_bytes())?;
+    write_merged(
+        dest,
+        vec![(&ix1, map1), (&ix2, map2)],
+        merge_paths(&paths1, &paths2),
+        &HashMap::new(),
+    )
+}
 
-    let path_data = get_offset(&mut ix3)?;
+/// Merges the indexes in `srcs`, from oldest to newest, into `dest`.
+///
+/// Files in an index are shadowed by the paths of every newer index, so
+/// the result is the same as merging each index into the ones before it
+/// with `merge`, in order.  The path list of `dest` is the union of the path
+/// lists of `srcs`, and its format is that of the newest index.
+pub fn merge_many<P1, P2>(dest: P1, srcs: &[P2]) -> io::Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let _frame_merge = libprofiling::profile("merge_many");
+    if srcs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "merge: no indexes to merge",
+        ));
+    }
+    let mut indexes = Vec::with_capacity(srcs.len());
+    let mut paths = Vec::with_capacity(srcs.len());
+    for src in srcs {
+        let ix = IndexReader::open(src)?;
+        paths.push(ix.indexed_paths()?);
+        indexes.push(ix);
+    }
+
+    // the paths of the indexes newer than each one, which shadow its files
+    let mut shadows = vec![Vec::new(); indexes.len()];
+    for i in (1..indexes.len()).rev() {
+        shadows[i - 1] = merge_paths(&shadows[i], &paths[i]);
+    }
+
+    // the files that aren't shadowed, by name, with the index they're from
+    let mut next = BinaryHeap::new();
+    let mut kept = Vec::with_capacity(indexes.len());
+    for (i, ix) in indexes.iter().enumerate() {
+        if i > 0 {
+            check_paths(ix, &paths[i])?;
+        }
+        let mut names = UnshadowedNames::new(ix, &shadows[i]);
+        if let Some((name, id)) = names.next()? {
+            next.push(Reverse((name, i, id)));
+        }
+        kept.push(names);
+    }
+
+    let mut maps = indexes.iter().map(|_| Vec::new()).collect::<Vec<_>>();
+    let mut new: u32 = 0;
+    while let Some(Reverse((_, i, id))) = next.pop() {
+        let _frame = libprofiling::profile("merge_many: merge names");
+        push_id(&mut maps[i], id, new);
+        new += 1;
+        if let Some((name, id)) = kept[i].next()? {
+            next.push(Reverse((name, i, id)));
+        }
+    }
+
+    let all_paths = paths.iter().fold(Vec::new(), |all, p| merge_paths(&all, p));
+    let sources = indexes.iter().zip(maps).collect();
+    write_merged(dest, sources, all_paths, &HashMap::new())
+}
+
+/// Reads the names of an index in order, leaving out the ones under a
+/// path in a sorted list of paths, none of which is a prefix of another
+struct UnshadowedNames<'a> {
+    ix: &'a IndexReader,
+    shadows: &'a [String],
+    file_id: u32,
+    /// The index in `shadows` of the first path that's not before the
+    /// last name read
+    shadow: usize,
+}
+
+impl<'a> UnshadowedNames<'a> {
+    fn new(ix: &'a IndexReader, shadows: &'a [String]) -> Self {
+        UnshadowedNames {
+            ix,
+            shadows,
+            file_id: 0,
+            shadow: 0,
+        }
+    }
+
+    /// Returns the next name that isn't shadowed, with its file ID
+    fn next(&mut self) -> io::Result<Option<(String, u32)>> {
+        while let Some(name) = next_name(self.ix, self.file_id)? {
+            let id = self.file_id;
+            self.file_id += 1;
+            // skip the paths that can't cover this name or any after it
+            while self.shadow < self.shadows.len()
+                && self.shadows[self.shadow] < name
+                && !name.starts_with(&self.shadows[self.shadow])
+            {
+                self.shadow += 1;
+            }
+            match self.shadows.get(self.shadow) {
+                Some(p) if name.starts_with(p) => continue,
+                _ => return Ok(Some((name, id))),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Checks that every file in `ix` is under one of `paths`, which merging
+/// relies on for all but the oldest index
+fn check_paths(ix: &IndexReader, paths: &[String]) -> io::Result<()> {
+    let mut p = 0;
+    for i in 0..(ix.num_name as u32) {
+        let name = ix.name(i)?;
+        while p < paths.len() && paths[p] < name && !name.starts_with(&paths[p]) {
+            p += 1;
+        }
+        match paths.get(p) {
+            Some(path) if name.starts_with(path) => {}
+            _ => {
+                return Err(inconsistent(format!(
+                    "{} is not under an indexed path",
+                    name
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Merges the files in `src2` into `src1`, writing the combined index to `dest`.
+///
+/// Unlike `merge`, files in `src1` aren't shadowed by the paths in `src2`;
+/// instead `keep` is called with the name and metadata of each file in `src1`,
+/// and returns the metadata to record for it, or `None` to drop the file.
+/// Files that appear in both indexes are always taken from `src2`.
+/// The path list of `dest` is the union of the path lists of `src1` and `src2`.
+pub fn merge_filtered<P1, P2, P3, F>(dest: P1, src1: P2, src2: P3, mut keep: F) -> io::Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+    F: FnMut(&str, FileMeta) -> Option<FileMeta>,
+{
+    let _frame_merge = libprofiling::profile("merge_filtered");
+    let ix1 = IndexReader::open(src1)?;
+    let ix2 = IndexReader::open(src2)?;
+
+    let mut map1 = Vec::<IdRange>::new();
+    let mut map2 = Vec::<IdRange>::new();
+    let mut meta1 = HashMap::<u32, FileMeta>::new();
+    let mut i1: u32 = 0;
+    let mut i2: u32 = 0;
+    let mut new: u32 = 0;
+    let mut name1 = next_name(&ix1, i1)?;
+    let mut name2 = next_name(&ix2, i2)?;
+    loop {
+        let _frame = libprofiling::profile("merge_filtered: merge names");
+        let from_first = match (&name1, &name2) {
+            (None, None) => break,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (Some(n1), Some(n2)) => {
+                if n1 == n2 {
+                    // the newer index wins
+                    i1 += 1;
+                    name1 = next_name(&ix1, i1)?;
+                    continue;
+                }
+                n1 < n2
+            }
+        };
+        if from_first {
+            let old_meta = ix1.file_meta(i1).unwrap_or_default();
+            if let Some(m) = keep(name1.as_ref().unwrap(), old_meta) {
+                push_id(&mut map1, i1, new);
+                if m != old_meta {
+                    meta1.insert(i1, m);
+                }
+                new += 1;
+            }
+            i1 += 1;
+            name1 = next_name(&ix1, i1)?;
+        } else {
+            push_id(&mut map2, i2, new);
+            new += 1;
+            i2 += 1;
+            name2 = next_name(&ix2, i2)?;
+        }
+    }
+
+    let paths = merge_paths(&ix1.indexed_paths()?, &ix2.indexed_paths()?);
+    write_merged(dest, vec![(&ix1, map1), (&ix2, map2)], paths, &meta1)
+}
+
+/// Rewrites the index `src` into `dest`, dropping every file for which
+/// `keep` returns false.  The remaining file IDs are renumbered, and the
+/// posting lists are remapped to match, just like in a merge.
+pub fn prune<P1, P2, F>(dest: P1, src: P2, keep: F) -> io::Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    F: FnMut(&str) -> bool,
+{
+    let _frame_prune = libprofiling::profile("prune");
+    let ix = IndexReader::open(src)?;
+    let paths = ix.indexed_paths()?;
+    rewrite(dest, &ix, paths, keep)
+}
+
+/// Rewrites the index `src` into `dest` without `path`.
+///
+/// If `path` (or a path under it) is in the path list, it's dropped from
+/// the path list.  Every file under `path` is dropped from the index.
+pub fn remove<P1, P2, P3>(dest: P1, src: P2, path: P3) -> io::Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    P3: AsRef<Path>,
+{
+    let _frame_remove = libprofiling::profile("remove");
+    let ix = IndexReader::open(src)?;
+    let path = path.as_ref();
+    let paths = ix
+        .indexed_paths()?
+        .into_iter()
+        .filter(|p| !Path::new(p).starts_with(path))
+        .collect();
+    rewrite(dest, &ix, paths, |name| !Path::new(name).starts_with(path))
+}
+
+/// Writes the files of `ix` for which `keep` returns true to `dest`,
+/// with `paths` as the path list.
+fn rewrite<P, F>(dest: P, ix: &IndexReader, paths: Vec<String>, mut keep: F) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    F: FnMut(&str) -> bool,
+{
+    let mut map = Vec::<IdRange>::new();
+    let mut new: u32 = 0;
+    for i in 0..(ix.num_name as u32) {
+        if keep(&ix.name(i)?) {
+            push_id(&mut map, i, new);
+            new += 1;
+        }
+    }
+    write_merged(dest, vec![(ix, map)], paths, &HashMap::new())
+}
+
+/// Returns the name of `file_id` in `ix`, or None past the last name
+fn next_name(ix: &IndexReader, file_id: u32) -> io::Result<Option<String>> {
+    if (file_id as usize) < ix.num_name {
+        Ok(Some(ix.name(file_id)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Returns the error for indexes that don't line up the way they should
+fn inconsistent(detail: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("merge: inconsistent index ({})", detail),
+    )
+}
+
+/// Maps `old` to `new`, extending the last range in `map` if possible
+fn push_id(map: &mut Vec<IdRange>, old: u32, new: u32) {
+    if let Some(r) = map.last_mut() {
+        if r.high == old && r.new + (r.high - r.low) == new {
+            r.high += 1;
+            return;
+        }
+    }
+    map.push(IdRange {
+        low: old,
+        high: old + 1,
+        new,
+    });
+}
+
+/// Merges two sorted path lists, dropping paths that are covered by an earlier path
+fn merge_paths(paths1: &[String], paths2: &[String]) -> Vec<String> {
+    let mut paths = Vec::new();
     let mut mi1 = 0;
     let mut mi2 = 0;
     let mut last = "\0".to_string(); // not a prefix of anything
@@ -146,78 +439,125 @@ where
             continue;
         }
         last = p.clone();
-        ix3.write(&p.as_bytes())?;
+        paths.push(p);
+    }
+    paths
+}
+
+/// Calls `f` with the number of the source index and old file ID of each
+/// file in the merged index, in order of the new file IDs.
+fn for_each_merged_id<F>(maps: &[&[IdRange]], mut f: F) -> io::Result<()>
+where
+    F: FnMut(usize, u32) -> io::Result<()>,
+{
+    let num_name = maps
+        .iter()
+        .flat_map(|m| m.iter())
+        .map(|r| r.high - r.low)
+        .sum();
+    let mut new = 0;
+    let mut mi = vec![0; maps.len()];
+    while new < num_name {
+        let src = (0..maps.len())
+            .find(|&s| mi[s] < maps[s].len() && maps[s][mi[s]].new == new)
+            .expect("merge: inconsistent index");
+        let r = &maps[src][mi[src]];
+        for i in r.low..r.high {
+            f(src, i)?;
+            new += 1;
+        }
+        mi[src] += 1;
+    }
+    Ok(())
+}
+
+/// Writes the index made up of the files of each source index that are
+/// mapped to a new file ID to `dest`, in the format of the last source.
+///
+/// `meta1` holds the file metadata that should replace what is recorded
+/// in the first source for some of its files.
+fn write_merged<P: AsRef<Path>>(
+    dest: P,
+    sources: Vec<(&IndexReader, Vec<IdRange>)>,
+    paths: Vec<String>,
+    meta1: &HashMap<u32, FileMeta>,
+) -> io::Result<()> {
+    let format = sources.last().expect("merge: no source index").0.format();
+    let maps = sources.iter().map(|s| &s.1[..]).collect::<Vec<_>>();
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dest)?;
+    let mut ix3 = BufWriter::new(f);
+    ix3.write(format.version.magic().as_bytes())?;
+
+    let path_data = get_offset(&mut ix3)?;
+    for p in &paths {
+        ix3.write(p.as_bytes())?;
         ix3.write("\0".as_bytes())?;
     }
     ix3.write("\0".as_bytes())?;
 
+    // Merged list of file metadata
+    for_each_merged_id(&maps, |src, i| {
+        let _frame = libprofiling::profile("merge: Merge list of file metadata");
+        let meta = match meta1.get(&i) {
+            Some(m) if src == 0 => *m,
+            _ => sources[src].0.file_meta(i).unwrap_or_default(),
+        };
+        ix3.write_u64::<BigEndian>(meta.size)?;
+        ix3.write_u64::<BigEndian>(meta.mtime)?;
+        ix3.write_u64::<BigEndian>(meta.hash)
+    })?;
+
     // Merged list of names
     let name_data = get_offset(&mut ix3)?;
     let mut name_index_file = BufWriter::new(tempfile()?);
 
-    new = 0;
-    mi1 = 0;
-    mi2 = 0;
-
-    while new < num_name {
+    let mut new: u32 = 0;
+    for_each_merged_id(&maps, |src, i| {
         let _frame = libprofiling::profile("merge: Merge list of names");
-        if mi1 < map1.len() && map1[mi1].new == new {
-            for i in map1[mi1].low..map1[mi1].high {
-                let name = ix1.name(i);
-                let new_offset: u32 = get_offset(&mut ix3)? as u32;
-                name_index_file
-                    .write_u32::<BigEndian>(new_offset - (name_data as u32))
-                    .unwrap();
-                ix3.write(&name.as_bytes())?;
-                ix3.write("\0".as_bytes())?;
-                new += 1;
-            }
-            mi1 += 1;
-        } else if mi2 < map2.len() && map2[mi2].new == new {
-            for i in map2[mi2].low..map2[mi2].high {
-                let name = ix2.name(i);
-                let new_offset: u32 = get_offset(&mut ix3)? as u32;
-                name_index_file
-                    .write_u32::<BigEndian>(new_offset - (name_data as u32))
-                    .unwrap();
-                ix3.write(&name.as_bytes())?;
-                ix3.write("\0".as_bytes())?;
-                new += 1;
-            }
-            mi2 += 1;
-        } else {
-            panic!("merge: inconsistent index");
-        }
-    }
-    if ((new * 4) as u64) != get_offset(&mut name_index_file)? {
-        panic!("merge: inconsistent index");
+        let name = sources[src].0.name(i)?;
+        let new_offset = get_offset(&mut ix3)?;
+        name_index_file.write_u64::<BigEndian>(new_offset - name_data)?;
+        ix3.write(name.as_bytes())?;
+        ix3.write("\0".as_bytes())?;
+        new += 1;
+        Ok(())
+    })?;
+    if (new as u64) * 8 != get_offset(&mut name_index_file)? {
+        return Err(inconsistent(String::from("name index size")));
     }
-    name_index_file
-        .write_u32::<BigEndian>(get_offset(&mut ix3)? as u32)
-        .unwrap();
+    name_index_file.write_u64::<BigEndian>(get_offset(&mut ix3)?)?;
 
     let post_data = get_offset(&mut ix3)?;
 
-    let post_index_file = merge_list_of_posting_lists(
-        PostMapReader::new(&ix1, map1),
-        PostMapReader::new(&ix2, map2),
-        &mut ix3,
-    )?;
+    let mut readers = Vec::with_capacity(sources.len());
+    for (ix, map) in sources {
+        readers.push(PostMapReader::new(ix, map)?);
+    }
+    let post_index_file = merge_list_of_posting_lists(readers, &mut ix3, format.block_postings())?;
 
     // Name index
     let name_index = get_offset(&mut ix3)?;
-    name_index_file.seek(SeekFrom::Start(0)).unwrap();
-    copy_file(
+    let format = fit_format(format, name_index + 4 * (new as u64 + 1))?;
+    copy_offsets(
         &mut ix3,
-        &mut BufReader::new(name_index_file.into_inner().unwrap()),
-    );
+        &mut name_index_file.into_inner()?,
+        0,
+        format.wide_offsets(),
+    )?;
 
     // Posting list index
-    let post_index = get_offset(&mut ix3).unwrap();
-    copy_file(
+    let post_index = get_offset(&mut ix3)?;
+    copy_offsets(
         &mut ix3,
-        &mut BufReader::new(post_index_file.into_inner().unwrap()),
-    );
+        &mut post_index_file.into_inner()?,
+        3 + 4,
+        format.wide_offsets(),
+    )?;
 
     trace!("path_data  = {}", path_data);
     trace!("name_data  = {}", name_data);
@@ -225,61 +565,43 @@ where
     trace!("name_index = {}", name_index);
     trace!("post_index = {}", post_index);
 
-    ix3.write_u32::<BigEndian>(path_data as u32).unwrap();
-    ix3.write_u32::<BigEndian>(name_data as u32).unwrap();
-    ix3.write_u32::<BigEndian>(post_data as u32).unwrap();
-    ix3.write_u32::<BigEndian>(name_index as u32).unwrap();
-    ix3.write_u32::<BigEndian>(post_index as u32).unwrap();
-    ix3.write(consts::TRAILER_MAGIC.as_bytes())?;
-    Ok(())
+    let off = [path_data, name_data, post_data, name_index, post_index];
+    write_trailer(&mut ix3, &off, format)
 }
 
 fn merge_list_of_posting_lists(
-    mut r1: PostMapReader,
-    mut r2: PostMapReader,
+    mut readers: Vec<PostMapReader>,
     ix3: &mut BufWriter<File>,
+    blocks: bool,
 ) -> io::Result<BufWriter<File>> {
     // Merged list of posting lists.
-    let mut w = PostDataWriter::new(ix3)?;
+    let mut w = PostDataWriter::new(ix3, blocks)?;
+    let mut ids = Vec::new();
 
     loop {
         let _frame = libprofiling::profile("merge: merge list of posting lists");
-        if r1.trigram < r2.trigram {
-            w.trigram(r1.trigram);
-            while r1.next_id() {
-                w.file_id(r1.file_id);
-            }
-            r1.next_trigram();
-            w.end_trigram();
-        } else if r2.trigram < r1.trigram {
-            w.trigram(r2.trigram);
-            while r2.next_id() {
-                w.file_id(r2.file_id);
-            }
-            r2.next_trigram();
-            w.end_trigram();
-        } else {
-            if r1.trigram == u32::MAX {
-                break;
-            }
-            w.trigram(r1.trigram);
-            r1.next_id();
-            r2.next_id();
-            while r1.file_id < u32::MAX || r2.file_id < u32::MAX {
-                if r1.file_id < r2.file_id {
-                    w.file_id(r1.file_id);
-                    r1.next_id();
-                } else if r2.file_id < r1.file_id {
-                    w.file_id(r2.file_id);
-                    r2.next_id();
-                } else {
-                    panic!("merge: inconsistent index");
-                }
+        let trigram = readers.iter().map(|r| r.trigram).min().unwrap_or(u32::MAX);
+        if trigram == u32::MAX {
+            break;
+        }
+        w.trigram(trigram);
+        ids.clear();
+        for r in readers.iter_mut().filter(|r| r.trigram == trigram) {
+            while r.next_id()? {
+                ids.push(r.file_id);
             }
-            r1.next_trigram();
-            r2.next_trigram();
-            w.end_trigram();
+            r.next_trigram()?;
+        }
+        // each index's IDs are in order, but they're interleaved
+        ids.sort_unstable();
+        if let Some(w) = ids.windows(2).find(|w| w[0] == w[1]) {
+            let detail = format!("file {} in more than one index", w[0]);
+            return Err(inconsistent(detail));
+        }
+        for &id in &ids {
+            w.file_id(id);
         }
+        w.end_trigram();
     }
 
     Ok(w.into_inner())
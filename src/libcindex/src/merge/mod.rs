@@ -1,4 +1,4 @@
-pub use self::merge::merge;
+pub use self::merge::{merge, merge_filtered, merge_many, prune, remove};
 
 mod merge;
 mod postdatawriter;
@@ -9,7 +9,7 @@ use std::io::{self, BufWriter, Seek, Write};
 use std::u32;
 
 use libvarint;
-use writer::{get_offset, WriteTrigram};
+use writer::{get_offset, write_posting_blocks, WriteTrigram};
 
 use byteorder::{BigEndian, WriteBytesExt};
 use tempfile::tempfile;
@@ -17,16 +17,19 @@ use tempfile::tempfile;
 pub struct PostDataWriter<'a, W: 'a + Write + Seek> {
     out: &'a mut BufWriter<W>,
     post_index_file: BufWriter<File>,
-    base: u32,
+    base: u64,
     count: u32,
-    offset: u32,
+    offset: u64,
     last: u32,
     t: u32,
+    /// The file IDs of the current posting list, if it's block-encoded
+    ids: Option<Vec<u32>>,
 }
 
 impl<'a, W: Write + Seek> PostDataWriter<'a, W> {
-    pub fn new(out: &'a mut BufWriter<W>) -> io::Result<Self> {
-        let base = get_offset(out)? as u32;
+    /// Creates a writer for posting lists, block-encoding them if `blocks` is set
+    pub fn new(out: &'a mut BufWriter<W>, blocks: bool) -> io::Result<Self> {
+        let base = get_offset(out)?;
         Ok(PostDataWriter {
             out: out,
             post_index_file: BufWriter::with_capacity(256 << 10, tempfile()?),
@@ -35,15 +38,25 @@ impl<'a, W: Write + Seek> PostDataWriter<'a, W> {
             offset: 0,
             last: 0,
             t: 0,
+            ids: if blocks { Some(Vec::new()) } else { None },
         })
     }
     pub fn trigram(&mut self, t: u32) {
-        self.offset = get_offset(self.out).unwrap() as u32;
+        self.offset = get_offset(self.out).unwrap();
         self.count = 0;
         self.t = t;
         self.last = u32::MAX;
+        if let Some(ref mut ids) = self.ids {
+            ids.clear();
+        }
     }
     pub fn file_id(&mut self, id: u32) {
+        if let Some(ref mut ids) = self.ids {
+            // written all at once in end_trigram
+            ids.push(id);
+            self.count += 1;
+            return;
+        }
         if self.count == 0 {
             self.out.write_trigram(self.t).unwrap();
         }
@@ -55,13 +68,22 @@ impl<'a, W: Write + Seek> PostDataWriter<'a, W> {
         if self.count == 0 {
             return;
         }
-        libvarint::write_uvarint(self.out, 0).unwrap();
+        match self.ids {
+            Some(ref ids) => {
+                self.out.write_trigram(self.t).unwrap();
+                write_posting_blocks(self.out, ids).unwrap();
+            }
+            None => {
+                libvarint::write_uvarint(self.out, 0).unwrap();
+            }
+        }
         self.post_index_file.write_trigram(self.t).unwrap();
         self.post_index_file
             .write_u32::<BigEndian>(self.count)
             .unwrap();
+        // like the name index, this is narrowed when copied into the index
         self.post_index_file
-            .write_u32::<BigEndian>(self.offset - self.base)
+            .write_u64::<BigEndian>(self.offset - self.base)
             .unwrap();
     }
     pub fn into_inner(self) -> BufWriter<File> {
@@ -4,10 +4,9 @@
 // Use of this source code is governed by a BSD-style
 // license that can be found in the LICENSE file.
 
-use libcsearch::reader::{IndexReader, POST_ENTRY_SIZE};
+use libcsearch::reader::{IndexReadResult, IndexReader, PostingList};
 
 use libprofiling;
-use libvarint;
 
 use std::u32;
 
@@ -23,87 +22,66 @@ pub struct PostMapReader<'a> {
     pub id_map: Vec<IdRange>,
     tri_num: u32,
     pub trigram: u32,
-    count: u32,
-    offset: u32,
-    d: &'a [u8],
-    old_id: u32,
+    list: PostingList<'a>,
     pub file_id: u32,
     i: usize,
 }
 
 impl<'a> PostMapReader<'a> {
-    pub fn new(index: &'a IndexReader, id_map: Vec<IdRange>) -> PostMapReader<'a> {
+    pub fn new(index: &'a IndexReader, id_map: Vec<IdRange>) -> IndexReadResult<PostMapReader<'a>> {
         let _frame = libprofiling::profile("PostMapReader::new");
-        let s = unsafe { index.as_slice() };
         let mut p = PostMapReader {
             index: index,
             id_map: id_map,
             tri_num: 0,
             trigram: u32::MAX,
-            count: 0,
-            offset: 0,
-            d: s,
-            old_id: u32::MAX,
+            list: PostingList::empty(),
             file_id: 0,
             i: 0,
         };
-        p.load();
-        p
+        p.load()?;
+        Ok(p)
     }
-    pub fn next_trigram(&mut self) {
+    pub fn next_trigram(&mut self) -> IndexReadResult<()> {
         let _frame = libprofiling::profile("PostMapReader::next_trigram");
         self.tri_num += 1;
-        self.load();
+        self.load()
     }
-    fn load(&mut self) {
+    fn load(&mut self) -> IndexReadResult<()> {
         if self.tri_num >= (self.index.num_post as u32) {
             self.trigram = u32::MAX;
-            self.count = 0;
+            self.list = PostingList::empty();
             self.file_id = u32::MAX;
-            return;
+            return Ok(());
         }
-        let (trigram, count, offset) = self
-            .index
-            .list_at((self.tri_num as usize) * POST_ENTRY_SIZE);
+        let entry_len = self.index.format().post_entry_len();
+        let (trigram, count, offset) = self.index.list_at((self.tri_num as usize) * entry_len)?;
         self.trigram = trigram;
-        self.count = count;
-        self.offset = offset;
         if count == 0 {
+            self.list = PostingList::empty();
             self.file_id = u32::MAX;
-            return;
+            return Ok(());
         }
-        self.d = unsafe {
-            let s = self.index.as_slice();
-            let split_point = self.index.post_data + self.offset + 3;
-            let (_, right_side) = s.split_at(split_point as usize);
-            right_side
-        };
-        self.old_id = u32::MAX;
+        self.list = self.index.posting_list_at(count, offset)?;
         self.i = 0;
+        Ok(())
     }
-    pub fn next_id(&mut self) -> bool {
-        while self.count > 0 {
-            self.count -= 1;
-            let (delta, n) = libvarint::read_uvarint(self.d).unwrap();
-            if n <= 0 || delta == 0 {
-                panic!("merge: inconsistent index at trigram {}", self.trigram);
-            }
-            self.d = self.d.split_at(n as usize).1;
-            self.old_id = self.old_id.wrapping_add(delta as u32);
-            while self.i < self.id_map.len() && self.id_map[self.i].high <= self.old_id {
+    pub fn next_id(&mut self) -> IndexReadResult<bool> {
+        while let Some(old_id) = self.list.next_id()? {
+            while self.i < self.id_map.len() && self.id_map[self.i].high <= old_id {
                 self.i += 1;
             }
             if self.i >= self.id_map.len() {
-                self.count = 0;
                 break;
             }
-            if self.old_id < self.id_map[self.i].low {
+            if old_id < self.id_map[self.i].low {
                 continue;
             }
-            self.file_id = self.id_map[self.i].new + self.old_id - self.id_map[self.i].low;
-            return true;
+            self.file_id = self.id_map[self.i].new + old_id - self.id_map[self.i].low;
+            return Ok(true);
         }
+        self.list = PostingList::empty();
         self.file_id = u32::MAX;
-        return false;
+        Ok(false)
     }
 }
@@ -0,0 +1,134 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// the profiling frames are only guards when profiling is enabled
+#![allow(clippy::let_unit_value)]
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use libcsearch::reader::FileMeta;
+use libprofiling;
+
+use super::error::{IndexError, IndexErrorKind, IndexResult};
+use super::filemeta::{file_mtime, HashReader};
+use super::sparseset::SparseSet;
+use super::trigramiter::TrigramReader;
+
+pub const MAX_FILE_LEN: u64 = 1 << 30;
+pub const MAX_TEXT_TRIGRAMS: u64 = 30000;
+pub const MAX_INVALID_UTF8_RATION: f64 = 0.1;
+pub const MAX_LINE_LEN: u64 = 2000;
+
+/// The trigrams of a file, ready to be added to an index with
+/// `IndexWriter::add_trigrams`
+#[derive(Debug, Clone)]
+pub struct FileTrigrams {
+    /// Metadata to record for the file
+    pub meta: FileMeta,
+    /// The distinct trigrams in the file, in any order
+    pub trigrams: Vec<u32>,
+}
+
+/// Reads files into the set of trigrams they contain, skipping the ones
+/// that don't look like text.
+///
+/// Each extractor can read one file at a time, so several threads can each
+/// use their own to read files in parallel for a single `IndexWriter`.
+///
+/// ```no_run
+/// # use libcindex::writer::IndexWriter;
+/// let mut index = IndexWriter::new("index").unwrap();
+/// let mut extractor = index.trigram_extractor();
+/// let trigrams = extractor.extract_file("/path/to/file").unwrap();
+/// index.add_trigrams("/path/to/file", trigrams).unwrap();
+/// index.flush().unwrap();
+/// ```
+pub struct TrigramExtractor {
+    /// Max number of allowed trigrams in a file
+    pub max_trigram_count: u64,
+    /// Max percentage of invalid utf-8 sequences allowed
+    pub max_utf8_invalid: f64,
+    /// Don't index a file if its size in bytes is larger than this
+    pub max_file_len: u64,
+    /// Stop indexing a file if it has a line longer than this
+    pub max_line_len: u64,
+
+    trigram: SparseSet,
+}
+
+impl TrigramExtractor {
+    /// Creates an extractor with the default limits
+    pub fn new() -> TrigramExtractor {
+        TrigramExtractor {
+            max_trigram_count: MAX_TEXT_TRIGRAMS,
+            max_utf8_invalid: MAX_INVALID_UTF8_RATION,
+            max_file_len: MAX_FILE_LEN,
+            max_line_len: MAX_LINE_LEN,
+            trigram: SparseSet::new(),
+        }
+    }
+
+    /// Opens a file and reads its trigrams
+    pub fn extract_file<P: AsRef<Path>>(&mut self, filename: P) -> IndexResult<FileTrigrams> {
+        let _frame = libprofiling::profile("TrigramExtractor::extract_file");
+        let f = File::open(filename.as_ref())?;
+        let metadata = f.metadata()?;
+        self.extract(f, metadata.len(), file_mtime(&metadata))
+    }
+
+    /// Reads the trigrams of a file
+    ///
+    /// `size` is the size of the file referred to by `f`, and `mtime` its
+    /// modification time (in nanoseconds since the unix epoch).
+    pub fn extract<R: Read>(&mut self, f: R, size: u64, mtime: u64) -> IndexResult<FileTrigrams> {
+        let _frame = libprofiling::profile("TrigramExtractor::extract");
+        if size > self.max_file_len {
+            return Err(IndexError::new(
+                IndexErrorKind::FileTooLong,
+                format!("file too long, ignoring ({} > {})", size, self.max_file_len),
+            ));
+        }
+        self.trigram.clear();
+        let max_utf8_invalid = ((size as f64) * self.max_utf8_invalid) as u64;
+        let mut f = HashReader::new(f);
+        {
+            let mut trigrams = TrigramReader::new(&mut f, max_utf8_invalid, self.max_line_len);
+            let _trigram_insert_frame =
+                libprofiling::profile("TrigramExtractor::extract: Insert Trigrams");
+            for each_trigram in trigrams.by_ref() {
+                self.trigram.insert(each_trigram);
+            }
+            if let Some(Err(e)) = trigrams.take_error() {
+                return Err(e);
+            }
+        }
+        if (self.trigram.len() as u64) > self.max_trigram_count {
+            return Err(IndexError::new(
+                IndexErrorKind::TooManyTrigrams,
+                format!(
+                    "Too many trigrams ({} > {})",
+                    self.trigram.len(),
+                    self.max_trigram_count
+                ),
+            ));
+        }
+        Ok(FileTrigrams {
+            meta: FileMeta {
+                size,
+                mtime,
+                hash: f.hash(),
+            },
+            trigrams: self.trigram.take_dense(),
+        })
+    }
+}
+
+impl Default for TrigramExtractor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
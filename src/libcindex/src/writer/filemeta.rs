@@ -0,0 +1,66 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use std::fs::{File, Metadata};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Wraps a reader, computing the 64-bit FNV-1a hash of everything read through it
+pub struct HashReader<R: Read> {
+    inner: R,
+    hash: u64,
+}
+
+impl<R: Read> HashReader<R> {
+    pub fn new(inner: R) -> HashReader<R> {
+        HashReader {
+            inner,
+            hash: FNV_OFFSET_BASIS,
+        }
+    }
+    /// Returns the hash of the bytes read so far
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        for b in &buf[..n] {
+            self.hash ^= *b as u64;
+            self.hash = self.hash.wrapping_mul(FNV_PRIME);
+        }
+        Ok(n)
+    }
+}
+
+/// Returns the content hash of the file at `path`, as recorded in the index
+pub fn hash_file<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    let mut r = HashReader::new(File::open(path)?);
+    io::copy(&mut r, &mut io::sink())?;
+    Ok(r.hash())
+}
+
+/// Returns the modification time of a file, as recorded in the index
+pub fn file_mtime(meta: &Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() * 1_000_000_000 + (d.subsec_nanos() as u64))
+        .unwrap_or(0)
+}
+
+#[test]
+fn test_hash_reader() {
+    let mut r = HashReader::new("hello".as_bytes());
+    io::copy(&mut r, &mut io::sink()).unwrap();
+    assert_eq!(r.hash(), 0xa430d84680aabd0b);
+    assert_eq!(HashReader::new(io::empty()).hash(), FNV_OFFSET_BASIS);
+}
@@ -3,22 +3,35 @@
 // Use of this source code is governed by a BSD-style
 // license that can be found in the LICENSE file.
 
+use std::fs::File;
 use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use consts::TRAILER_MAGIC;
+use crc32fast::Hasher;
+use libcsearch::reader::{IndexFormat, IndexVersion, FLAG_WIDE_OFFSETS};
+
 pub use self::error::{IndexError, IndexErrorKind, IndexResult};
+pub use self::extract::{FileTrigrams, TrigramExtractor};
+pub use self::filemeta::{file_mtime, hash_file};
+pub use self::postblocks::write_posting_blocks;
 pub use self::write::IndexWriter;
 
 mod error;
+mod extract;
+mod filemeta;
 mod sparseset;
 mod write;
 
+mod postblocks;
 mod postentry;
 mod postheap;
 mod postinglist;
 mod sort_post;
 mod trigramiter;
 
-const NPOST: usize = (64 << 20) / 8; // 64 MB worth of post entries
+/// The default memory budget for posting entries, in bytes
+pub const DEFAULT_MEMORY: usize = 128 << 20;
 
 /// Returns the offset in a seekable object.
 pub fn get_offset<S: Seek>(seekable: &mut S) -> io::Result<u64> {
@@ -43,6 +56,130 @@ pub fn copy_file<R: Read + Seek, W: Write>(dest: &mut BufWriter<W>, src: &mut R)
     }
 }
 
+/// Copies a temporary index file into the index.
+///
+/// `src` holds fixed-size entries of `prefix` bytes followed by an 8-byte
+/// offset.  The offsets are written as 8 bytes if `wide` is set, and as
+/// 4 bytes otherwise.
+pub fn copy_offsets<R: Read + Seek, W: Write>(
+    dest: &mut BufWriter<W>,
+    src: &mut R,
+    prefix: usize,
+    wide: bool,
+) -> io::Result<()> {
+    src.seek(SeekFrom::Start(0))?;
+    let mut src = BufReader::new(src);
+    let mut buf = vec![0; prefix];
+    while !src.fill_buf()?.is_empty() {
+        src.read_exact(&mut buf)?;
+        let offset = src.read_u64::<BigEndian>()?;
+        dest.write_all(&buf)?;
+        if wide {
+            dest.write_u64::<BigEndian>(offset)?;
+        } else {
+            dest.write_u32::<BigEndian>(offset as u32)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the format to write an index in, starting from `format`.
+///
+/// `max_offset` is the largest offset the index has to record (the offset
+/// of the posting list index, when written with 4-byte offsets).  Wide
+/// offsets are turned on if it doesn't fit in 4 bytes.  A version 2 index
+/// that needs any format flags becomes a version 3 index, while a version
+/// 1 index can't have them at all.
+pub fn fit_format(mut format: IndexFormat, max_offset: u64) -> io::Result<IndexFormat> {
+    if max_offset > u32::MAX as u64 {
+        format.flags |= FLAG_WIDE_OFFSETS;
+    }
+    if format.flags != 0 {
+        match format.version {
+            IndexVersion::V1 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "the version 1 format can't have format flags ({:08x}); \
+                         the index may be too large for it",
+                        format.flags
+                    ),
+                ))
+            }
+            IndexVersion::V2 => format.version = IndexVersion::V3,
+            IndexVersion::V3 => {}
+        }
+    }
+    Ok(format)
+}
+
+/// Writes the index trailer: the section offsets in `off`, followed by
+/// the section checksums and the format flags if `format` has them, and
+/// the trailer magic.
+///
+/// The format is only settled once all the sections are written, so the
+/// magic at the start of the index is rewritten to match it.
+///
+/// The sections must end at the current offset of `index`.  The checksums
+/// are computed by reading the sections back, so the file behind `index`
+/// has to be open for reading too.
+pub fn write_trailer(
+    index: &mut BufWriter<File>,
+    off: &[u64; 5],
+    format: IndexFormat,
+) -> io::Result<()> {
+    let end = get_offset(index)?;
+    let mut sums = [0; 5];
+    index.flush()?;
+    {
+        let f = index.get_mut();
+        f.seek(SeekFrom::Start(0))?;
+        f.write_all(format.version.magic().as_bytes())?;
+        if format.version.has_checksums() {
+            for i in 0..5 {
+                let section_end = if i < 4 { off[i + 1] } else { end };
+                sums[i] = checksum(f, off[i], section_end)?;
+            }
+        }
+        f.seek(SeekFrom::Start(end))?;
+    }
+    for v in off.iter() {
+        if format.wide_offsets() {
+            index.write_u64::<BigEndian>(*v)?;
+        } else {
+            index.write_u32::<BigEndian>(*v as u32)?;
+        }
+    }
+    if format.version.has_checksums() {
+        for v in sums.iter() {
+            index.write_u32::<BigEndian>(*v)?;
+        }
+    }
+    if format.version.has_flags() {
+        index.write_u32::<BigEndian>(format.flags)?;
+    }
+    index.write_all(TRAILER_MAGIC.as_bytes())
+}
+
+/// Returns the CRC-32 of the bytes of `f` between `start` and `end`
+fn checksum<R: Read + Seek>(f: &mut R, start: u64, end: u64) -> io::Result<u32> {
+    f.seek(SeekFrom::Start(start))?;
+    let mut hasher = Hasher::new();
+    let mut section = BufReader::new(f.take(end - start));
+    loop {
+        let length = {
+            let b = section.fill_buf()?;
+            hasher.update(b);
+            b.len()
+        };
+        if length == 0 {
+            break;
+        }
+        section.consume(length);
+    }
+    Ok(hasher.finalize())
+}
+
 /// Used for writing trigrams
 pub trait WriteTrigram: Write {
     /// Write a trigram to a stream
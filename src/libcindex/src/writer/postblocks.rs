@@ -0,0 +1,103 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Block encoding of posting lists, for indexes with FLAG_BLOCK_POSTINGS.
+// See read.rs in libcsearch for the format, and postings.rs there for
+// the decoder.
+
+use std::io::{self, Write};
+
+use byteorder::{BigEndian, WriteBytesExt};
+use libcsearch::reader::POST_BLOCK_SIZE;
+
+/// Writes the sorted file IDs in `ids` as a block-encoded posting list,
+/// leaving out the trigram that starts the list.
+pub fn write_posting_blocks<W: Write>(w: &mut W, ids: &[u32]) -> io::Result<()> {
+    let mut blocks = Vec::new();
+    for block in ids.chunks(POST_BLOCK_SIZE) {
+        w.write_u32::<BigEndian>(block[0])?;
+        w.write_u32::<BigEndian>(blocks.len() as u32)?;
+        encode_block(&mut blocks, block);
+    }
+    w.write_all(&blocks)
+}
+
+/// Appends the bit width and packed deltas of `block` to `out`
+fn encode_block(out: &mut Vec<u8>, block: &[u32]) {
+    let deltas = block.windows(2).map(|w| w[1] - w[0] - 1);
+    let max = deltas.clone().max().unwrap_or(0);
+    let width = 32 - max.leading_zeros() as usize;
+    out.push(width as u8);
+    let mut bits: u64 = 0;
+    let mut nbits = 0;
+    for d in deltas {
+        bits |= (d as u64) << nbits;
+        nbits += width;
+        while nbits >= 8 {
+            out.push(bits as u8);
+            bits >>= 8;
+            nbits -= 8;
+        }
+    }
+    if nbits > 0 {
+        out.push(bits as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libcsearch::reader::{IndexFormat, IndexVersion, PostingList, FLAG_BLOCK_POSTINGS};
+
+    fn encode(ids: &[u32]) -> Vec<u8> {
+        let mut v = Vec::new();
+        write_posting_blocks(&mut v, ids).unwrap();
+        v
+    }
+
+    fn block_format() -> IndexFormat {
+        IndexFormat {
+            version: IndexVersion::V3,
+            flags: FLAG_BLOCK_POSTINGS,
+        }
+    }
+
+    #[test]
+    fn test_encode_block() {
+        // deltas of 1 need no bits at all
+        assert_eq!(encode(&[5, 6, 7]), vec![0, 0, 0, 5, 0, 0, 0, 0, 0]);
+        // deltas 3, 1 and 4 are stored as 2, 0 and 3 in 2 bits each
+        assert_eq!(
+            encode(&[1, 4, 5, 9]),
+            vec![0, 0, 0, 1, 0, 0, 0, 0, 2, 0b11_00_10]
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let ids = (0..1000u32).map(|i| i * i / 7 + i).collect::<Vec<_>>();
+        let data = encode(&ids);
+        let mut list = PostingList::new(&data, ids.len(), block_format()).unwrap();
+        let mut decoded = Vec::new();
+        while let Some(id) = list.next_id().unwrap() {
+            decoded.push(id);
+        }
+        assert_eq!(decoded, ids);
+    }
+
+    #[test]
+    fn test_advance_to() {
+        let ids = (0..1000u32).map(|i| 3 * i).collect::<Vec<_>>();
+        let data = encode(&ids);
+        let mut list = PostingList::new(&data, ids.len(), block_format()).unwrap();
+        assert_eq!(list.advance_to(1).unwrap(), Some(3));
+        assert_eq!(list.advance_to(3).unwrap(), Some(6));
+        // skips several blocks
+        assert_eq!(list.advance_to(2000).unwrap(), Some(2001));
+        assert_eq!(list.next_id().unwrap(), Some(2004));
+        assert_eq!(list.advance_to(2997).unwrap(), Some(2997));
+        assert_eq!(list.advance_to(2998).unwrap(), None);
+    }
+}
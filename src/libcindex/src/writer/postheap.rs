@@ -1,29 +1,63 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom};
 use std::vec;
 
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use libprofiling;
 
 use super::postentry::PostEntry;
 
+/// Where the entries of a sorted run come from
+enum Run {
+    Mem(vec::IntoIter<PostEntry>),
+    /// A run written by `write_run`, read back as it's merged
+    File(BufReader<File>),
+}
+
+impl Run {
+    fn next(&mut self) -> io::Result<Option<PostEntry>> {
+        match *self {
+            Run::Mem(ref mut m) => Ok(m.next()),
+            Run::File(ref mut f) => f.read_u64::<BigEndian>().map(|v| Some(PostEntry(v))),
+        }
+    }
+}
+
+/// Writes a sorted run of entries to `f`, to be merged with `PostHeap::add_file`
+pub fn write_run<I: IntoIterator<Item = PostEntry>>(f: File, entries: I) -> io::Result<File> {
+    let _frame = libprofiling::profile("PostHeap::write_run");
+    let mut w = BufWriter::with_capacity(256 << 10, f);
+    for p in entries {
+        w.write_u64::<BigEndian>(p.value())?;
+    }
+    w.into_inner().map_err(|e| e.into_error())
+}
+
 struct PostChunk {
     e: PostEntry,
-    m: vec::IntoIter<PostEntry>,
+    m: Run,
     size: usize,
+    /// An error reading the run, which ends it early
+    error: Option<io::Error>,
 }
 
 impl PostChunk {
     pub fn new(v: Vec<PostEntry>) -> Option<PostChunk> {
         let size = v.len();
+        Self::from_run(Run::Mem(v.into_iter()), size).unwrap()
+    }
+    /// Reads the first entry of a run of `size` entries
+    fn from_run(mut m: Run, size: usize) -> io::Result<Option<PostChunk>> {
         if size == 0 {
-            None
-        } else {
-            let mut m = v.into_iter();
-            let e = m.next().unwrap();
-            Some(PostChunk {
-                e: e,
-                m: m,
-                size: size,
-            })
+            return Ok(None);
         }
+        let e = m.next()?.unwrap();
+        Ok(Some(PostChunk {
+            e,
+            m,
+            size,
+            error: None,
+        }))
     }
     pub fn is_empty(&self) -> bool {
         self.size == 0
@@ -41,10 +75,17 @@ impl Iterator for PostChunk {
             return None;
         }
         let result = self.e;
-        if let Some(c) = self.m.next() {
-            self.e = c;
-        }
         self.size -= 1;
+        if self.size > 0 {
+            match self.m.next() {
+                Ok(Some(c)) => self.e = c,
+                Ok(None) => self.size = 0,
+                Err(e) => {
+                    self.error = Some(e);
+                    self.size = 0;
+                }
+            }
+        }
         Some(result)
     }
     fn size_hint(&self) -> (usize, Option<usize>) {
@@ -52,6 +93,7 @@ impl Iterator for PostChunk {
     }
 }
 
+/// Merges sorted runs of entries, held in memory or in files
 pub struct PostHeap {
     ch: Vec<PostChunk>,
 }
@@ -66,6 +108,17 @@ impl PostHeap {
             self.add(p);
         }
     }
+    /// Adds a run of `size` entries written to `f` by `write_run`, which is
+    /// read a buffer at a time as the runs are merged
+    pub fn add_file(&mut self, mut f: File, size: usize) -> io::Result<()> {
+        let _frame = libprofiling::profile("PostHeap::add_file");
+        f.seek(SeekFrom::Start(0))?;
+        let run = Run::File(BufReader::with_capacity(64 << 10, f));
+        if let Some(p) = PostChunk::from_run(run, size)? {
+            self.add(p);
+        }
+        Ok(())
+    }
     fn add(&mut self, ch: PostChunk) {
         if !ch.is_empty() {
             self.push(ch);
@@ -119,34 +172,51 @@ impl IntoIterator for PostHeap {
     }
 }
 
+/// Yields the entries of every run in order.  Like `TrigramReader`, it
+/// stops at the first error reading a run, which `take_error` returns.
 pub struct IntoIter {
     inner: PostHeap,
-    place: usize,
+    error: Option<io::Error>,
 }
 
 impl IntoIter {
     pub fn new(inner: PostHeap) -> Self {
         IntoIter {
             inner: inner,
-            place: 0,
+            error: None,
         }
     }
+    /// If an error occurred reading a run, extracts it
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
 }
 
 impl Iterator for IntoIter {
     type Item = PostEntry;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.place < self.inner.ch.len() {
-            let e = self.inner.ch[self.place].next();
-            if self.inner.ch[self.place].is_empty() {
-                self.place += 1;
-            } else {
-                self.inner.sift_down(self.place);
-            }
-            e
-        } else {
-            None
+        if self.error.is_some() {
+            return None;
+        }
+        let ch = &mut self.inner.ch;
+        if ch.is_empty() {
+            return None;
+        }
+        let e = ch[0].next();
+        if let Some(err) = ch[0].error.take() {
+            // the entries after this one may be out of order
+            self.error = Some(err);
+            return e;
+        }
+        if ch[0].is_empty() {
+            // replace the run with the last one, which is then sifted
+            // down into place
+            ch.swap_remove(0);
         }
+        if !ch.is_empty() {
+            self.inner.sift_down(0);
+        }
+        e
     }
 }
 
@@ -218,4 +288,42 @@ mod tests {
         p.add_mem(v2.clone());
         assert!(p.into_iter().collect::<Vec<_>>() == v_comb);
     }
+
+    #[test]
+    fn test_postheap_iter_files() {
+        let runs = (0..5u32)
+            .map(|r| {
+                (0..100)
+                    .map(|i| PostEntry::new(i % 7, i * 5 + r))
+                    .collect::<Vec<_>>()
+            })
+            .map(|mut v| {
+                v.sort();
+                v
+            })
+            .collect::<Vec<_>>();
+        let mut p = PostHeap::new();
+        for v in &runs[1..] {
+            let f = write_run(::tempfile::tempfile().unwrap(), v.clone()).unwrap();
+            p.add_file(f, v.len()).unwrap();
+        }
+        p.add_mem(runs[0].clone());
+        let mut all = runs.concat();
+        all.sort();
+        let mut it = p.into_iter();
+        assert_eq!(it.by_ref().collect::<Vec<_>>(), all);
+        assert!(it.take_error().is_none());
+    }
+
+    #[test]
+    fn test_postheap_short_file() {
+        // a run that's shorter than it should be is an error
+        let v = vec![PostEntry::new(0, 32), PostEntry::new(5, 32)];
+        let f = write_run(::tempfile::tempfile().unwrap(), v.clone()).unwrap();
+        let mut p = PostHeap::new();
+        p.add_file(f, 3).unwrap();
+        let mut it = p.into_iter();
+        assert_eq!(it.by_ref().collect::<Vec<_>>(), v);
+        assert!(it.take_error().is_some());
+    }
 }
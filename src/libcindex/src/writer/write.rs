@@ -7,28 +7,39 @@
 // license that can be found in the LICENSE file.
 
 #![allow(dead_code)]
+use std::cmp;
+use std::collections::BTreeMap;
 use std::ffi::OsString;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::{self, BufWriter, Read, Write};
 use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+use std::thread;
 
 use byteorder::{BigEndian, WriteBytesExt};
 use libprofiling;
 use libvarint;
 use tempfile::tempfile;
 
-use consts::{MAGIC, TRAILER_MAGIC};
+use libcsearch::reader::{IndexFormat, IndexVersion, FLAG_BLOCK_POSTINGS, FLAG_WIDE_OFFSETS};
 
 use super::error::{IndexError, IndexErrorKind, IndexResult};
+use super::extract::{
+    FileTrigrams, TrigramExtractor, MAX_FILE_LEN, MAX_INVALID_UTF8_RATION, MAX_LINE_LEN,
+    MAX_TEXT_TRIGRAMS,
+};
+use super::filemeta::file_mtime;
 use super::postentry::PostEntry;
-use super::postheap::PostHeap;
+use super::postheap::{write_run, PostHeap};
 use super::postinglist::{to_diffs, TakeWhilePeek};
 use super::sort_post::sort_post;
-use super::sparseset::SparseSet;
-use super::trigramiter::TrigramReader;
-use super::NPOST;
-use super::{copy_file, get_offset, WriteTrigram};
+use super::DEFAULT_MEMORY;
+use super::{
+    copy_file, copy_offsets, fit_format, get_offset, write_posting_blocks, write_trailer,
+    WriteTrigram,
+};
 
 // Index writing.  See read.rs for details of on-disk format.
 //
@@ -39,17 +50,33 @@ use super::{copy_file, get_offset, WriteTrigram};
 // Instead, we sort and flush the list to a new temporary file each time
 // it reaches its maximum in-memory size, and then at the end we
 // create the final posting lists by merging the temporary files as we
-// read them back in.
+// read them back in.  The in-memory list is limited by the writer's memory
+// budget, which counts the scratch space needed to sort it as well.  So that
+// a small budget doesn't leave too many temporary files open, runs of about
+// the same size are merged into one as they pile up.
 //
 // It would also be useful to be able to create an index for a subset
 // of the files and then merge that index into an existing one.  This would
 // allow incremental updating of an existing index when a directory changes.
-// But we have not implemented that.
+// The per-file metadata written alongside the names lets cindex do just that:
+// it only indexes files that changed and merges them in with merge_filtered.
+//
+// Reading a file and collecting its trigrams is most of the work of indexing
+// it, and doesn't depend on the rest of the index, so add_files hands that to
+// a pool of threads, each with its own TrigramExtractor.  The trigrams are
+// added to the index in the order the files were given, which keeps file IDs
+// (and so the whole index) the same however many threads there are.
+
+/// How many files each thread of `add_files` may read ahead of the ones
+/// added to the index
+const FILES_AHEAD_PER_THREAD: usize = 4;
 
-const MAX_FILE_LEN: u64 = 1 << 30;
-const MAX_TEXT_TRIGRAMS: u64 = 30000;
-const MAX_INVALID_UTF8_RATION: f64 = 0.1;
-const MAX_LINE_LEN: u64 = 2000;
+/// How many temporary files of the same level are merged into one file of
+/// the next level
+const RUNS_MERGED: usize = 16;
+
+/// The fewest posting entries the in-memory list grows by
+const MIN_POST_GROWTH: usize = 1024;
 
 pub struct IndexWriter {
     /// Max number of allowed trigrams in a file
@@ -60,13 +87,27 @@ pub struct IndexWriter {
     pub max_file_len: u64,
     /// Stop indexing a file if it has a line longer than this
     pub max_line_len: u64,
+    /// Format version of the index to write.  Use `IndexVersion::V1`
+    /// for an index the Go tools can read.
+    pub version: IndexVersion,
+    /// Always use 8-byte offsets.  Otherwise they are only used if the
+    /// index is too large for 4-byte offsets.
+    pub wide_offsets: bool,
+    /// Write the posting lists as blocks of bit-packed deltas with a table
+    /// of where each block starts, which lets queries skip blocks.
+    /// Needs a version 3 index, so it can't be used with `IndexVersion::V1`.
+    pub block_postings: bool,
+    /// Roughly how many bytes of posting entries to sort in memory before
+    /// writing them out to a temporary file
+    pub memory: usize,
 
     paths: Vec<OsString>,
 
+    meta_data: BufWriter<File>,
     name_data: BufWriter<File>,
     name_index: BufWriter<File>,
 
-    trigram: SparseSet,
+    extractor: TrigramExtractor,
 
     /// Tracks the number of names written to disk (used to assign file IDs)
     pub number_of_names_written: usize,
@@ -74,7 +115,9 @@ pub struct IndexWriter {
     pub bytes_written: usize,
 
     post: Vec<PostEntry>,
-    post_files: Vec<Vec<PostEntry>>,
+    /// The temporary files of sorted entries, with the number in each and
+    /// how many times they've been merged into a larger one
+    post_files: Vec<(File, usize, u32)>,
     post_index: BufWriter<File>,
 
     index: BufWriter<File>,
@@ -89,19 +132,30 @@ impl IndexWriter {
     /// ```
     pub fn new<P: AsRef<Path>>(filename: P) -> io::Result<IndexWriter> {
         let _frame = libprofiling::profile("IndexWriter::new");
-        let f = File::create(filename)?;
+        // opened for reading too, so the checksums can be computed at the end
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(filename)?;
         Ok(IndexWriter {
             max_trigram_count: MAX_TEXT_TRIGRAMS,
             max_utf8_invalid: MAX_INVALID_UTF8_RATION,
             max_file_len: MAX_FILE_LEN,
             max_line_len: MAX_LINE_LEN,
+            version: IndexVersion::default(),
+            wide_offsets: false,
+            block_postings: false,
+            memory: DEFAULT_MEMORY,
             paths: Vec::new(),
+            meta_data: make_temp_buf()?,
             name_data: make_temp_buf()?,
             name_index: make_temp_buf()?,
-            trigram: SparseSet::new(),
+            extractor: TrigramExtractor::new(),
             number_of_names_written: 0,
             bytes_written: 0,
-            post: Vec::with_capacity(NPOST),
+            post: Vec::new(),
             post_files: Vec::new(),
             post_index: make_temp_buf()?,
             index: BufWriter::with_capacity(256 << 10, f),
@@ -128,7 +182,7 @@ impl IndexWriter {
         let _frame = libprofiling::profile("IndexWriter::add_file");
         let f = File::open(filename.as_ref())?;
         let metadata = f.metadata()?;
-        self.add(filename, f, metadata.len())
+        self.add_with_mtime(filename, f, metadata.len(), file_mtime(&metadata))
     }
 
     /// Indexes a file
@@ -136,55 +190,185 @@ impl IndexWriter {
     /// `filename` is the name of the opened file referred to by `f`.
     /// `size` is the size of the file referred to by `f`.
     pub fn add<P, R>(&mut self, filename: P, f: R, size: u64) -> IndexResult<()>
+    where
+        P: AsRef<Path>,
+        R: Read,
+    {
+        self.add_with_mtime(filename, f, size, 0)
+    }
+
+    /// Indexes a file, recording `mtime` as its modification time
+    /// (in nanoseconds since the unix epoch) in the file metadata.
+    pub fn add_with_mtime<P, R>(
+        &mut self,
+        filename: P,
+        f: R,
+        size: u64,
+        mtime: u64,
+    ) -> IndexResult<()>
     where
         P: AsRef<Path>,
         R: Read,
     {
         let _frame = libprofiling::profile("IndexWriter::add");
-        if size > self.max_file_len {
-            return Err(IndexError::new(
-                IndexErrorKind::FileTooLong,
-                format!("file too long, ignoring ({} > {})", size, self.max_file_len),
-            ));
-        }
-        self.trigram.clear();
-        let max_utf8_invalid = ((size as f64) * self.max_utf8_invalid) as u64;
-        {
-            let mut trigrams = TrigramReader::new(f, max_utf8_invalid, self.max_line_len);
-            let _trigram_insert_frame = libprofiling::profile("IndexWriter::add: Insert Trigrams");
-            while let Some(each_trigram) = trigrams.next() {
-                self.trigram.insert(each_trigram);
+        // the limits may have changed since the last file
+        self.extractor.max_trigram_count = self.max_trigram_count;
+        self.extractor.max_utf8_invalid = self.max_utf8_invalid;
+        self.extractor.max_file_len = self.max_file_len;
+        self.extractor.max_line_len = self.max_line_len;
+        let trigrams = self.extractor.extract(f, size, mtime)?;
+        self.add_trigrams(filename, trigrams)
+    }
+
+    /// Returns an extractor with the same limits as this writer, to read
+    /// files into trigrams for `add_trigrams` on another thread
+    pub fn trigram_extractor(&self) -> TrigramExtractor {
+        let mut extractor = TrigramExtractor::new();
+        extractor.max_trigram_count = self.max_trigram_count;
+        extractor.max_utf8_invalid = self.max_utf8_invalid;
+        extractor.max_file_len = self.max_file_len;
+        extractor.max_line_len = self.max_line_len;
+        extractor
+    }
+
+    /// Adds a file whose trigrams have already been read, such as by a
+    /// `TrigramExtractor`.  The trigrams must be distinct.
+    ///
+    /// The file gets the next file ID, so files have to be added in sorted
+    /// order whichever way they're read.
+    pub fn add_trigrams<P: AsRef<Path>>(
+        &mut self,
+        filename: P,
+        trigrams: FileTrigrams,
+    ) -> IndexResult<()> {
+        let _frame = libprofiling::profile("IndexWriter::add_trigrams");
+        let FileTrigrams { meta, trigrams } = trigrams;
+        debug!("{} {} {:?}", meta.size, trigrams.len(), filename.as_ref());
+        let file_id = self.add_name(filename)?;
+        self.bytes_written += meta.size as usize;
+        self.meta_data.write_u64::<BigEndian>(meta.size)?;
+        self.meta_data.write_u64::<BigEndian>(meta.mtime)?;
+        self.meta_data.write_u64::<BigEndian>(meta.hash)?;
+        self.push_trigrams_to_post(file_id, trigrams)
+    }
+
+    /// Indexes `files`, which must be sorted, reading them on `threads`
+    /// threads.
+    ///
+    /// The index is the same as if each file were added with `add_file`
+    /// in turn.  `skipped` is called with the files that couldn't be read,
+    /// aren't text or whose names aren't UTF-8, and why, which doesn't stop
+    /// the others from being indexed.  Errors writing the index do stop it, and are returned.
+    ///
+    /// ```no_run
+    /// # use libcindex::writer::IndexWriter;
+    /// # use std::path::PathBuf;
+    /// let mut index = IndexWriter::new("index").unwrap();
+    /// let files = vec![PathBuf::from("/path/to/a"), PathBuf::from("/path/to/b")];
+    /// index
+    ///     .add_files(files, 4, |f, e| println!("{}: {}", f.display(), e))
+    ///     .unwrap();
+    /// index.flush().unwrap();
+    /// ```
+    pub fn add_files<I, F>(&mut self, files: I, threads: usize, skipped: F) -> IndexResult<()>
+    where
+        I: IntoIterator<Item = PathBuf>,
+        I::IntoIter: Send,
+        F: FnMut(&Path, IndexError),
+    {
+        let _frame = libprofiling::profile("IndexWriter::add_files");
+        let threads = cmp::max(threads, 1);
+        let ahead = threads * FILES_AHEAD_PER_THREAD;
+        let (file_tx, file_rx) = mpsc::sync_channel::<(usize, PathBuf)>(ahead);
+        let file_rx = Mutex::new(file_rx);
+        let (read_tx, read_rx) = mpsc::channel();
+        // holds a slot for each file that's been handed out but not added
+        let (slot_tx, slot_rx) = mpsc::sync_channel::<()>(ahead);
+        let files = files.into_iter();
+
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                for each in files.enumerate() {
+                    if slot_tx.send(()).is_err() || file_tx.send(each).is_err() {
+                        // adding the files failed
+                        return;
+                    }
+                }
+            });
+            for _ in 0..threads {
+                let mut extractor = self.trigram_extractor();
+                let file_rx = &file_rx;
+                let read_tx = read_tx.clone();
+                scope.spawn(move || loop {
+                    let next = file_rx.lock().unwrap().recv();
+                    let (n, filename) = match next {
+                        Ok(f) => f,
+                        Err(_) => return,
+                    };
+                    let trigrams = extractor.extract_file(&filename);
+                    if read_tx.send((n, filename, trigrams)).is_err() {
+                        return;
+                    }
+                });
             }
-            if let Some(e) = trigrams.take_error() {
-                return e;
+            drop(read_tx);
+            // the channels are dropped on return, which stops the other
+            // threads if adding a file failed
+            self.add_in_order(read_rx, slot_rx, skipped)
+        })
+    }
+
+    /// Adds the files read by the threads of `add_files`, in the order
+    /// they were handed out
+    fn add_in_order<F>(
+        &mut self,
+        read_rx: Receiver<(usize, PathBuf, IndexResult<FileTrigrams>)>,
+        slot_rx: Receiver<()>,
+        mut skipped: F,
+    ) -> IndexResult<()>
+    where
+        F: FnMut(&Path, IndexError),
+    {
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        for (n, filename, trigrams) in read_rx {
+            pending.insert(n, (filename, trigrams));
+            while let Some((filename, trigrams)) = pending.remove(&next) {
+                match trigrams {
+                    Ok(t) => {
+                        if let Err(e) = self.add_trigrams(&filename, t) {
+                            // nothing is written for a file whose name is
+                            // rejected, so it's skipped like an unreadable one
+                            if e.kind() != IndexErrorKind::FileNameError {
+                                return Err(e);
+                            }
+                            skipped(&filename, e);
+                        }
+                    }
+                    Err(e) => skipped(&filename, e),
+                }
+                next += 1;
+                let _ = slot_rx.recv();
             }
         }
-        if (self.trigram.len() as u64) > self.max_trigram_count {
-            return Err(IndexError::new(
-                IndexErrorKind::TooManyTrigrams,
-                format!(
-                    "Too many trigrams ({} > {})",
-                    self.trigram.len(),
-                    self.max_trigram_count
-                ),
-            ));
-        }
-        debug!("{} {} {:?}", size, self.trigram.len(), filename.as_ref());
-        self.bytes_written += size as usize;
-
-        let file_id = self.add_name(filename)?;
-        let v = self.trigram.take_dense();
-        self.push_trigrams_to_post(file_id, v)
+        Ok(())
     }
 
     /// Take trigrams in `trigams` and push them to the post list,
     /// possibly flushing them to file.
     fn push_trigrams_to_post(&mut self, file_id: u32, trigrams: Vec<u32>) -> IndexResult<()> {
         let _frame = libprofiling::profile("IndexWriter::push_trigrams_to_post");
+        let max_post_len = self.max_post_len();
         for each_trigram in trigrams {
-            if self.post.len() >= NPOST {
+            if self.post.len() >= max_post_len {
                 self.flush_post()?;
             }
+            let len = self.post.len();
+            if len == self.post.capacity() {
+                // doubled like a Vec would be, but not past the budget
+                let more = cmp::max(len, MIN_POST_GROWTH);
+                self.post.reserve_exact(cmp::min(more, max_post_len - len));
+            }
             self.post.push(PostEntry::new(each_trigram, file_id));
         }
         Ok(())
@@ -193,13 +377,16 @@ impl IndexWriter {
     /// Add `filename` to the nameData section of the index
     fn add_name<P: AsRef<Path>>(&mut self, filename: P) -> IndexResult<u32> {
         let _frame = libprofiling::profile("IndexWriter::add_name");
-        let offset = get_offset(&mut self.name_data)?;
-        self.name_index.write_u32::<BigEndian>(offset as u32)?;
-
+        // checked before anything is written, so a bad name leaves the
+        // index as it was
         let s = filename.as_ref().to_str().ok_or(IndexError::new(
             IndexErrorKind::FileNameError,
             "UTF-8 Conversion error",
         ))?;
+        let offset = get_offset(&mut self.name_data)?;
+        // offsets are narrowed (if they can be) when copied into the index
+        self.name_index.write_u64::<BigEndian>(offset)?;
+
         self.name_data.write(s.as_bytes())?;
         self.name_data.write_u8(0)?;
 
@@ -212,7 +399,7 @@ impl IndexWriter {
     pub fn flush(mut self) -> IndexResult<()> {
         let _frame = libprofiling::profile("IndexWriter::flush");
         self.add_name("")?;
-        self.index.write(MAGIC.as_bytes())?;
+        self.index.write(self.version.magic().as_bytes())?;
 
         let mut off = [0; 5];
         off[0] = get_offset(&mut self.index)?;
@@ -226,6 +413,9 @@ impl IndexWriter {
             self.index.write_u8(0)?;
         }
         self.index.write_u8(0)?;
+
+        self.meta_data.flush()?;
+        copy_file(&mut self.index, &mut self.meta_data.get_mut());
         off[1] = get_offset(&mut self.index)?;
 
         self.name_data.flush()?;
@@ -235,17 +425,27 @@ impl IndexWriter {
         self.merge_post()?;
         off[3] = get_offset(&mut self.index)?;
 
+        let num_offsets = get_offset(&mut self.name_index)? / 8;
+        let format = fit_format(self.format(), off[3] + 4 * num_offsets)?;
+
         self.name_index.flush()?;
-        copy_file(&mut self.index, &mut self.name_index.get_mut());
+        copy_offsets(
+            &mut self.index,
+            self.name_index.get_mut(),
+            0,
+            format.wide_offsets(),
+        )?;
         off[4] = get_offset(&mut self.index)?;
 
         self.post_index.flush()?;
-        copy_file(&mut self.index, &mut self.post_index.get_mut());
-
-        for v in off.iter() {
-            self.index.write_u32::<BigEndian>(*v as u32)?;
-        }
-        self.index.write(TRAILER_MAGIC.as_bytes())?;
+        copy_offsets(
+            &mut self.index,
+            self.post_index.get_mut(),
+            3 + 4,
+            format.wide_offsets(),
+        )?;
+
+        write_trailer(&mut self.index, &off, format)?;
         info!(
             "{} data bytes, {} index bytes",
             self.bytes_written,
@@ -259,15 +459,16 @@ impl IndexWriter {
         let mut heap = PostHeap::new();
         info!("merge {} files + mem", self.post_files.len());
 
-        for p in self.post_files.drain(..) {
-            heap.add_mem(p);
+        for (f, len, _) in self.post_files.drain(..) {
+            heap.add_file(f, len)?;
         }
         sort_post(&mut self.post);
         let mut v = Vec::new();
         mem::swap(&mut v, &mut self.post);
         heap.add_mem(v);
 
-        let mut h = heap.into_iter().peekable();
+        let mut entries = heap.into_iter();
+        let mut h = entries.by_ref().peekable();
         let offset0 = get_offset(&mut self.index)?;
 
         let _frame_write = libprofiling::profile(
@@ -284,39 +485,103 @@ impl IndexWriter {
             // posting list
             let plist_trigram = plist.trigram();
             self.index.write_trigram(plist_trigram)?;
-            let mut written = 0;
             let _fname_diffs = libprofiling::profile("IndexWriter::merge_post: Write file diffs");
-            for each_file in to_diffs(plist.map(|p| p.file_id())) {
-                libvarint::write_uvarint(&mut self.index, each_file)?;
-                written += 1;
-            }
+            let count = if self.block_postings {
+                let ids = plist.map(|p| p.file_id()).collect::<Vec<_>>();
+                write_posting_blocks(&mut self.index, &ids)?;
+                ids.len() as u32
+            } else {
+                let mut written = 0;
+                for each_file in to_diffs(plist.map(|p| p.file_id())) {
+                    libvarint::write_uvarint(&mut self.index, each_file)?;
+                    written += 1;
+                }
+                // don't count the terminating 0
+                written - 1
+            };
             #[allow(dropping_copy_types)]
             drop(_fname_diffs);
 
             let _fname_diffs = libprofiling::profile("IndexWriter::merge_post: Write file diffs");
             self.post_index.write_trigram(plist_trigram)?;
-            self.post_index.write_u32::<BigEndian>(written - 1)?;
-            self.post_index.write_u32::<BigEndian>(offset as u32)?;
+            self.post_index.write_u32::<BigEndian>(count)?;
+            self.post_index.write_u64::<BigEndian>(offset)?;
+        }
+        if let Some(e) = entries.take_error() {
+            return Err(e);
         }
         // NOTE: write last entry like how the go version works
         let offset = get_offset(&mut self.index)? - offset0;
         self.index.write_trigram(0xffffff)?; // END trigram
-        libvarint::write_uvarint(&mut self.index, 0)?; // NUL byte for END postlist
+        if !self.block_postings {
+            libvarint::write_uvarint(&mut self.index, 0)?; // NUL byte for END postlist
+        }
         self.post_index.write_trigram(0xffffff)?; // END trigram
         self.post_index.write_u32::<BigEndian>(0)?; // nothing written
-        self.post_index.write_u32::<BigEndian>(offset as u32)?;
+        self.post_index.write_u64::<BigEndian>(offset)?;
 
         Ok(())
     }
 
+    /// Returns the format asked for, before it's fitted to the size of the index
+    fn format(&self) -> IndexFormat {
+        let mut flags = 0;
+        if self.wide_offsets {
+            flags |= FLAG_WIDE_OFFSETS;
+        }
+        if self.block_postings {
+            flags |= FLAG_BLOCK_POSTINGS;
+        }
+        IndexFormat {
+            version: self.version,
+            flags,
+        }
+    }
+
+    /// Returns how many posting entries fit in the memory budget.  Sorting
+    /// them takes as much space again.
+    fn max_post_len(&self) -> usize {
+        cmp::max(self.memory / (2 * mem::size_of::<PostEntry>()), 1)
+    }
+
     /// Flush the post data to a temporary file
     pub fn flush_post(&mut self) -> io::Result<()> {
         let _frame = libprofiling::profile("IndexWriter::flush_post");
         sort_post(&mut self.post);
-        let mut v = Vec::with_capacity(NPOST);
-        mem::swap(&mut v, &mut self.post);
-        self.post_files.push(v);
-        Ok(())
+        let f = write_run(tempfile()?, self.post.iter().cloned())?;
+        self.post_files.push((f, self.post.len(), 0));
+        self.post.clear();
+        self.merge_post_files()
+    }
+
+    /// Merges the last `RUNS_MERGED` temporary files into one while they're
+    /// of the same level, so that there are only a few files of each level
+    fn merge_post_files(&mut self) -> io::Result<()> {
+        loop {
+            let n = self.post_files.len();
+            if n < RUNS_MERGED {
+                return Ok(());
+            }
+            let level = self.post_files[n - 1].2;
+            if self.post_files[n - RUNS_MERGED..]
+                .iter()
+                .any(|&(_, _, l)| l != level)
+            {
+                return Ok(());
+            }
+            let mut heap = PostHeap::new();
+            let mut merged_len = 0;
+            for (f, len, _) in self.post_files.drain(n - RUNS_MERGED..) {
+                heap.add_file(f, len)?;
+                merged_len += len;
+            }
+            let mut entries = heap.into_iter();
+            let f = write_run(tempfile()?, entries.by_ref())?;
+            if let Some(e) = entries.take_error() {
+                return Err(e);
+            }
+            self.post_files.push((f, merged_len, level + 1));
+        }
     }
 }
 
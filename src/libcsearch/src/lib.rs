@@ -1,4 +1,5 @@
 extern crate byteorder;
+extern crate crc32fast;
 extern crate memmap;
 extern crate num;
 extern crate regex;
@@ -11,13 +12,65 @@ pub mod reader;
 pub mod regexp;
 
 use std::env;
+use std::io;
+use std::path::PathBuf;
 
-pub fn csearch_index() -> String {
-    env::var("CSEARCHINDEX")
-        .or_else(|_| {
-            env::var("HOME")
-                .or_else(|_| env::var("USERPROFILE"))
-                .map(|s| s + &"/.csearchindex")
+/// Returns the paths of the indexes to search: the paths in $CSEARCHINDEX,
+/// which are separated like $PATH, or $HOME/.csearchindex if it's unset or
+/// empty.
+pub fn csearch_indexes() -> Vec<PathBuf> {
+    let paths: Vec<PathBuf> = env::var_os("CSEARCHINDEX")
+        .map(|v| {
+            env::split_paths(&v)
+                .filter(|p| !p.as_os_str().is_empty())
+                .collect()
         })
-        .expect("no valid path to index")
+        .unwrap_or_default();
+    if !paths.is_empty() {
+        return paths;
+    }
+    let home = env::var("HOME")
+        .or_else(|_| env::var("USERPROFILE"))
+        .expect("no valid path to index");
+    vec![PathBuf::from(home + "/.csearchindex")]
+}
+
+/// Returns the path of the index to update, which is the only one of
+/// `csearch_indexes`.  If $CSEARCHINDEX holds several, it's an error rather
+/// than updating one that may not be the one meant.
+pub fn csearch_index() -> io::Result<String> {
+    only_index(csearch_indexes())
+}
+
+fn only_index(mut paths: Vec<PathBuf>) -> io::Result<String> {
+    if paths.len() > 1 {
+        let paths = paths
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>();
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "$CSEARCHINDEX holds {} indexes ({}), but only one can be updated",
+                paths.len(),
+                paths.join(", ")
+            ),
+        ));
+    }
+    Ok(paths.swap_remove(0).to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_index() {
+        let one = vec![PathBuf::from("/a/index")];
+        assert_eq!(only_index(one).unwrap(), "/a/index");
+        let two = vec![PathBuf::from("/a/index"), PathBuf::from("/b/index")];
+        let e = only_index(two).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("/a/index, /b/index"));
+    }
 }
@@ -0,0 +1,86 @@
+use std::error::{self, Error};
+use std::fmt;
+use std::io;
+
+/// The Error type for reading an index.
+///
+/// Errors can come from std::io::Error, or
+/// from finding that the index is malformed.
+#[derive(Debug)]
+pub struct IndexReadError {
+    kind: IndexReadErrorKind,
+    error: Box<dyn error::Error + Send + Sync>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexReadErrorKind {
+    /// A read error returned from a std::io function
+    IoError(io::ErrorKind),
+    /// The file doesn't start with the magic of a known index version
+    BadMagic,
+    /// A section of the index (or the trailer) ends before it should
+    TruncatedSection,
+    /// An offset in the index points outside of the section it refers to
+    OffsetOutOfRange,
+    /// A posting list holds a varint that can't be decoded
+    BadVarint,
+    /// A block of a block-encoded posting list can't be decoded
+    BadBlock,
+    /// The index uses format flags that this version can't read
+    UnsupportedFormat,
+}
+
+impl IndexReadError {
+    /// Creates a new IndexReadError. Works the same as std::io::Error.
+    ///
+    /// ```
+    /// # use libcsearch::reader::{IndexReadError, IndexReadErrorKind};
+    /// let e = IndexReadError::new(IndexReadErrorKind::BadMagic, "not an index");
+    /// assert_eq!(e.kind(), IndexReadErrorKind::BadMagic);
+    /// ```
+    pub fn new<E>(kind: IndexReadErrorKind, error: E) -> IndexReadError
+    where
+        E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        IndexReadError {
+            kind,
+            error: error.into(),
+        }
+    }
+    /// Returns the type of the error
+    pub fn kind(&self) -> IndexReadErrorKind {
+        self.kind.clone()
+    }
+}
+
+impl From<io::Error> for IndexReadError {
+    fn from(e: io::Error) -> Self {
+        IndexReadError {
+            kind: IndexReadErrorKind::IoError(e.kind()),
+            error: Box::new(e),
+        }
+    }
+}
+
+impl From<IndexReadError> for io::Error {
+    fn from(e: IndexReadError) -> Self {
+        match e.kind() {
+            IndexReadErrorKind::IoError(ekind) => io::Error::new(ekind, e),
+            _ => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+impl Error for IndexReadError {}
+
+impl fmt::Display for IndexReadError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(fmt)
+    }
+}
+
+/// A specialized result type for reading an index.
+///
+/// Behaves similarly to std::io::Result
+///
+pub type IndexReadResult<T> = Result<T, IndexReadError>;
@@ -1,7 +1,25 @@
+mod error;
+mod multi;
+mod plan;
+mod postings;
 mod read;
 mod search;
+mod stream;
+mod verify;
 
+pub use self::error::{IndexReadError, IndexReadErrorKind, IndexReadResult};
+pub use self::multi::MultiIndexReader;
+pub use self::plan::{QueryPlan, SKIP_MIN_FRACTION, SKIP_MIN_RATIO};
+pub use self::postings::{PostingList, POST_BLOCK_SIZE};
+pub use self::read::FileId;
+pub use self::read::FileMeta;
+pub use self::read::IndexFormat;
 pub use self::read::IndexReader;
+pub use self::read::IndexVersion;
 pub use self::read::PostReader;
-pub use self::read::PostSet;
+pub use self::read::FILE_META_ENTRY_SIZE;
+pub use self::read::FLAG_BLOCK_POSTINGS;
+pub use self::read::FLAG_WIDE_OFFSETS;
 pub use self::read::POST_ENTRY_SIZE;
+pub use self::stream::PostStream;
+pub use self::verify::{verify, verify_bytes};
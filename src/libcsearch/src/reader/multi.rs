@@ -0,0 +1,72 @@
+// Copyright 2016 Vernon Jones.
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use std::path::{Path, PathBuf};
+
+use super::error::{IndexReadError, IndexReadResult};
+use super::read::IndexReader;
+use regexp::Query;
+
+/// Several indexes searched as one.
+///
+/// Queries run against every index, and return the names of the matching
+/// files in sorted order, with each name only once even if more than one
+/// index holds it.  Errors name the index they came from.
+pub struct MultiIndexReader {
+    paths: Vec<PathBuf>,
+    indexes: Vec<IndexReader>,
+}
+
+impl MultiIndexReader {
+    /// Opens the indexes at `paths`
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> IndexReadResult<MultiIndexReader> {
+        let mut r = MultiIndexReader {
+            paths: Vec::with_capacity(paths.len()),
+            indexes: Vec::with_capacity(paths.len()),
+        };
+        for p in paths {
+            let p = p.as_ref();
+            let index = IndexReader::open(p).map_err(|e| with_path(p, e))?;
+            r.paths.push(p.to_path_buf());
+            r.indexes.push(index);
+        }
+        Ok(r)
+    }
+
+    /// Returns the paths of the indexes, in the order they were opened
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Returns the indexes, in the same order as `paths`
+    pub fn indexes(&self) -> &[IndexReader] {
+        &self.indexes
+    }
+
+    /// Returns the sorted names of the files in any index that may match
+    /// `query`, without duplicates
+    pub fn query(&self, query: &Query) -> IndexReadResult<Vec<String>> {
+        let mut names = Vec::new();
+        for (path, index) in self.paths.iter().zip(&self.indexes) {
+            add_names(index, query, &mut names).map_err(|e| with_path(path, e))?;
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+/// Adds the names of the files in `index` that may match `query` to `names`
+fn add_names(index: &IndexReader, query: &Query, names: &mut Vec<String>) -> IndexReadResult<()> {
+    let mut stream = index.query_stream(query)?;
+    while let Some(file_id) = stream.next_id()? {
+        names.push(index.name(file_id)?);
+    }
+    Ok(())
+}
+
+/// Adds the path of the index an error came from to its message
+fn with_path(path: &Path, e: IndexReadError) -> IndexReadError {
+    IndexReadError::new(e.kind(), format!("{}: {}", path.display(), e))
+}
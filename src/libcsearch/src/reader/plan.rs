@@ -0,0 +1,181 @@
+// Copyright 2016 Vernon Jones.
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Query planning.
+//
+// Before a query is evaluated, each trigram is looked up in the posting
+// list index to find how many files contain it.  The operands of each AND
+// are then sorted so that the one with the fewest files comes first, which
+// drives the intersection and ends it as soon as it runs out.  Operands
+// that match a large part of the index and many times more files than the
+// most selective one are left out entirely: the candidate files are
+// checked against the regexp anyway, so skipping them only costs a few
+// extra reads, while reading their posting lists can cost much more.
+
+use std::cmp;
+use std::fmt;
+
+use super::read::IndexReader;
+use regexp::{Query, QueryOperation};
+
+/// AND operands are only skipped if they may match at least this fraction
+/// of the files in the index
+pub const SKIP_MIN_FRACTION: f64 = 0.1;
+
+/// AND operands are only skipped if they may match at least this many times
+/// as many files as the most selective operand
+pub const SKIP_MIN_RATIO: u64 = 32;
+
+/// How a query is evaluated, as returned by `IndexReader::plan`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryPlan {
+    /// No files
+    None,
+    /// Every file
+    All,
+    /// The files that contain `trigram`, of which there are `count`
+    Trigram { trigram: u32, count: u32 },
+    /// The files in all of `operands`, which are in the order they're
+    /// intersected.  `skipped` are the operands of the query that were
+    /// left out because they're not selective enough to be worth reading.
+    And {
+        operands: Vec<QueryPlan>,
+        skipped: Vec<QueryPlan>,
+    },
+    /// The files in any of the operands
+    Or(Vec<QueryPlan>),
+}
+
+impl QueryPlan {
+    /// Plans `query` using the posting list counts in `index`
+    pub fn new(index: &IndexReader, query: &Query) -> QueryPlan {
+        let num_files = index.num_name as u64;
+        match query.operation {
+            QueryOperation::None => QueryPlan::None,
+            QueryOperation::All => QueryPlan::All,
+            QueryOperation::And => {
+                let mut operands = Self::operands(index, query);
+                if operands.is_empty() {
+                    return QueryPlan::None;
+                }
+                operands.retain(|p| *p != QueryPlan::All);
+                operands.sort_by_key(|p| p.estimate(num_files));
+                let min = match operands.first() {
+                    Some(p) => p.estimate(num_files),
+                    None => return QueryPlan::All,
+                };
+                let skipped = if min == 0 {
+                    // the intersection ends before it starts
+                    Vec::new()
+                } else {
+                    // skip_at is larger than min, so the first operand stays
+                    let skip_at = cmp::max(
+                        (num_files as f64 * SKIP_MIN_FRACTION).ceil() as u64,
+                        min.saturating_mul(SKIP_MIN_RATIO),
+                    );
+                    let first_skipped = operands
+                        .iter()
+                        .position(|p| p.estimate(num_files) >= skip_at)
+                        .unwrap_or(operands.len());
+                    operands.split_off(first_skipped)
+                };
+                if operands.len() == 1 && skipped.is_empty() {
+                    return operands.pop().unwrap();
+                }
+                QueryPlan::And { operands, skipped }
+            }
+            QueryOperation::Or => {
+                let mut operands = Self::operands(index, query);
+                if operands.contains(&QueryPlan::All) {
+                    return QueryPlan::All;
+                }
+                operands.retain(|p| *p != QueryPlan::None);
+                match operands.len() {
+                    0 => QueryPlan::None,
+                    1 => operands.pop().unwrap(),
+                    _ => QueryPlan::Or(operands),
+                }
+            }
+        }
+    }
+
+    fn operands(index: &IndexReader, query: &Query) -> Vec<QueryPlan> {
+        let trigrams = query.trigram.iter().map(|t| {
+            let trigram = (t[0] as u32) << 16 | (t[1] as u32) << 8 | (t[2] as u32);
+            QueryPlan::Trigram {
+                trigram,
+                count: index.trigram_count(trigram),
+            }
+        });
+        let sub = query.sub.iter().map(|q| QueryPlan::new(index, q));
+        trigrams.chain(sub).collect()
+    }
+
+    /// Returns the most files this plan can match in an index of
+    /// `num_files` files
+    pub fn estimate(&self, num_files: u64) -> u64 {
+        match *self {
+            QueryPlan::None => 0,
+            QueryPlan::All => num_files,
+            QueryPlan::Trigram { count, .. } => count as u64,
+            QueryPlan::And { ref operands, .. } => operands
+                .iter()
+                .map(|p| p.estimate(num_files))
+                .min()
+                .unwrap_or(num_files),
+            QueryPlan::Or(ref operands) => cmp::min(
+                operands.iter().map(|p| p.estimate(num_files)).sum(),
+                num_files,
+            ),
+        }
+    }
+
+    fn fmt_indented(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        let pad = "  ".repeat(indent);
+        match *self {
+            QueryPlan::None => writeln!(f, "{}none", pad),
+            QueryPlan::All => writeln!(f, "{}all", pad),
+            QueryPlan::Trigram { trigram, count } => {
+                let t = [(trigram >> 16) as u8, (trigram >> 8) as u8, trigram as u8];
+                writeln!(
+                    f,
+                    "{}{:?}: {} files",
+                    pad,
+                    String::from_utf8_lossy(&t),
+                    count
+                )
+            }
+            QueryPlan::And {
+                ref operands,
+                ref skipped,
+            } => {
+                writeln!(f, "{}and", pad)?;
+                for p in operands {
+                    p.fmt_indented(f, indent + 1)?;
+                }
+                if !skipped.is_empty() {
+                    writeln!(f, "{}skipped", pad)?;
+                    for p in skipped {
+                        p.fmt_indented(f, indent + 1)?;
+                    }
+                }
+                Ok(())
+            }
+            QueryPlan::Or(ref operands) => {
+                writeln!(f, "{}or", pad)?;
+                for p in operands {
+                    p.fmt_indented(f, indent + 1)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Prints the plan as a tree, one operand per line
+impl fmt::Display for QueryPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright 2016 Vernon Jones.
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Posting list decoding.
+//
+// A posting list is stored in one of two encodings (see read.rs).  The
+// original one is a list of varint-encoded deltas, which can only be read
+// from the front.  Indexes with FLAG_BLOCK_POSTINGS split each list into
+// blocks of POST_BLOCK_SIZE file IDs instead, and start the list with the
+// first file ID and offset of every block, so that advancing to a file ID
+// only has to decode the one block that can hold it.
+
+use libvarint;
+
+use super::error::{IndexReadError, IndexReadErrorKind, IndexReadResult};
+use super::read::{FileId, IndexFormat};
+
+/// Number of file IDs in each block of a block-encoded posting list
+pub const POST_BLOCK_SIZE: usize = 128;
+
+/// Size of a block table entry: the first file ID and the offset of a block
+const BLOCK_ENTRY_SIZE: usize = 4 + 4;
+
+fn truncated(what: &str) -> IndexReadError {
+    IndexReadError::new(
+        IndexReadErrorKind::TruncatedSection,
+        format!(
+            "corrupt index: {} runs past the end of the posting lists",
+            what
+        ),
+    )
+}
+
+fn read_u32(d: &[u8]) -> u32 {
+    (d[0] as u32) << 24 | (d[1] as u32) << 16 | (d[2] as u32) << 8 | (d[3] as u32)
+}
+
+/// The block table and block data of a block-encoded posting list
+struct Blocks<'a> {
+    table: &'a [u8],
+    data: &'a [u8],
+    /// Number of the block in `ids`, if one has been loaded
+    current: Option<usize>,
+    ids: Vec<FileId>,
+}
+
+/// Reads the file IDs of a posting list in increasing order
+pub struct PostingList<'a> {
+    count: usize,
+    /// Number of file IDs read so far
+    read: usize,
+    file_id: i64,
+    /// The deltas left to read, for lists that aren't block-encoded
+    deltas: &'a [u8],
+    blocks: Option<Blocks<'a>>,
+}
+
+impl<'a> PostingList<'a> {
+    /// Creates a reader for the posting list of `count` file IDs that
+    /// starts at `data` (just past its trigram) in an index of `format`.
+    pub fn new(data: &'a [u8], count: usize, format: IndexFormat) -> IndexReadResult<Self> {
+        let mut list = PostingList {
+            count,
+            read: 0,
+            file_id: -1,
+            deltas: data,
+            blocks: None,
+        };
+        if format.block_postings() {
+            let num_blocks = count.div_ceil(POST_BLOCK_SIZE);
+            let table_len = num_blocks * BLOCK_ENTRY_SIZE;
+            if data.len() < table_len {
+                return Err(truncated("block table"));
+            }
+            let (table, data) = data.split_at(table_len);
+            list.deltas = &[];
+            list.blocks = Some(Blocks {
+                table,
+                data,
+                current: None,
+                ids: Vec::with_capacity(POST_BLOCK_SIZE),
+            });
+        }
+        Ok(list)
+    }
+
+    /// Creates a reader for an empty posting list
+    pub fn empty() -> Self {
+        PostingList {
+            count: 0,
+            read: 0,
+            file_id: -1,
+            deltas: &[],
+            blocks: None,
+        }
+    }
+
+    /// Returns the number of file IDs in the list
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Returns true if the list has no file IDs
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the next file ID in the list, or None at the end of the list
+    pub fn next_id(&mut self) -> IndexReadResult<Option<FileId>> {
+        if self.read >= self.count {
+            return Ok(None);
+        }
+        let id = match self.blocks {
+            Some(ref mut b) => {
+                let i = self.read % POST_BLOCK_SIZE;
+                if i == 0 {
+                    b.load(self.read / POST_BLOCK_SIZE, self.count)?;
+                }
+                let id = b.ids[i] as i64;
+                if id <= self.file_id {
+                    return Err(IndexReadError::new(
+                        IndexReadErrorKind::BadBlock,
+                        format!(
+                            "corrupt index: posting list blocks out of order at file {}",
+                            id
+                        ),
+                    ));
+                }
+                id
+            }
+            None => {
+                let id = self.file_id + next_delta(&mut self.deltas)? as i64;
+                if id > FileId::MAX as i64 {
+                    return Err(IndexReadError::new(
+                        IndexReadErrorKind::BadVarint,
+                        "corrupt index: posting list overflows the file IDs",
+                    ));
+                }
+                id
+            }
+        };
+        self.read += 1;
+        self.file_id = id;
+        Ok(Some(id as FileId))
+    }
+
+    /// Reads up to the first file ID that is at least `target` and returns
+    /// it, or None if the rest of the list is smaller than `target`.
+    ///
+    /// Like `next_id`, this only looks at file IDs that haven't been read
+    /// yet.  Block-encoded lists skip the blocks that come before `target`
+    /// without decoding them.
+    pub fn advance_to(&mut self, target: FileId) -> IndexReadResult<Option<FileId>> {
+        if let Some(ref b) = self.blocks {
+            // the next block to decode, and the last block that starts at or before target
+            let next = self.read.div_ceil(POST_BLOCK_SIZE);
+            let num_blocks = b.table.len() / BLOCK_ENTRY_SIZE;
+            // gallop forward from next, since the target is usually close,
+            // then bisect the last step
+            let (mut lo, mut hi) = (next, next);
+            let mut step = 1;
+            while hi < num_blocks && b.first_id(hi) <= target {
+                lo = hi + 1;
+                hi = next + step;
+                step *= 2;
+            }
+            hi = ::std::cmp::min(hi, num_blocks);
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                if b.first_id(mid) <= target {
+                    lo = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+            if lo > next {
+                // blocks next..lo-1 start at or before target, so only the last one matters
+                self.read = (lo - 1) * POST_BLOCK_SIZE;
+                self.file_id = b.first_id(lo - 1) as i64 - 1;
+            }
+        }
+        while let Some(id) = self.next_id()? {
+            if id >= target {
+                return Ok(Some(id));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a> Blocks<'a> {
+    fn first_id(&self, block: usize) -> FileId {
+        read_u32(&self.table[block * BLOCK_ENTRY_SIZE..])
+    }
+
+    /// Decodes block number `block` of a list of `count` file IDs into `ids`
+    fn load(&mut self, block: usize, count: usize) -> IndexReadResult<()> {
+        if self.current == Some(block) {
+            return Ok(());
+        }
+        let entry = &self.table[block * BLOCK_ENTRY_SIZE..];
+        let first = read_u32(entry);
+        let offset = read_u32(&entry[4..]) as usize;
+        let n = ::std::cmp::min(POST_BLOCK_SIZE, count - block * POST_BLOCK_SIZE);
+        let d = match self.data.get(offset..) {
+            Some(d) if !d.is_empty() => d,
+            _ => return Err(truncated("posting list block")),
+        };
+        let width = d[0] as usize;
+        if width > 32 {
+            return Err(IndexReadError::new(
+                IndexReadErrorKind::BadBlock,
+                format!("corrupt index: posting list block has {}-bit deltas", width),
+            ));
+        }
+        let packed_len = ((n - 1) * width).div_ceil(8);
+        let packed = match d.get(1..1 + packed_len) {
+            Some(p) => p,
+            None => return Err(truncated("posting list block")),
+        };
+
+        self.ids.clear();
+        self.ids.push(first);
+        let mut last = first as u64;
+        let mut bits: u64 = 0;
+        let mut nbits = 0;
+        let mut p = packed.iter();
+        for _ in 1..n {
+            while nbits < width {
+                bits |= (*p.next().unwrap() as u64) << nbits;
+                nbits += 8;
+            }
+            let delta = bits & ((1u64 << width) - 1);
+            bits >>= width;
+            nbits -= width;
+            last += delta + 1;
+            if last > FileId::MAX as u64 {
+                return Err(IndexReadError::new(
+                    IndexReadErrorKind::BadBlock,
+                    "corrupt index: posting list block overflows the file IDs",
+                ));
+            }
+            self.ids.push(last as FileId);
+        }
+        self.current = Some(block);
+        Ok(())
+    }
+}
+
+/// Decodes the next delta of a posting list, advancing `d` past it.
+/// A zero delta ends the list, so it's an error while entries remain.
+pub fn next_delta(d: &mut &[u8]) -> IndexReadResult<u64> {
+    let (delta, n) = libvarint::read_uvarint(d).map_err(|_| {
+        IndexReadError::new(IndexReadErrorKind::BadVarint, "corrupt index: bad varint")
+    })?;
+    if delta == 0 {
+        return Err(IndexReadError::new(
+            IndexReadErrorKind::TruncatedSection,
+            "corrupt index: posting list ends early",
+        ));
+    }
+    *d = &d[n as usize..];
+    Ok(delta)
+}
@@ -7,8 +7,9 @@
 //
 // An index stored on disk has the format:
 //
-// 	"csearch index 1\n"
+// 	"csearch index 2\n"
 // 	list of paths
+// 	list of file metadata
 // 	list of names
 // 	list of posting lists
 // 	name index
@@ -19,6 +20,21 @@
 // The index covers the file trees rooted at those paths.
 // The list ends with an empty name ("\x00").
 //
+// The list of file metadata holds one fixed-size entry per name,
+// in the same order as the list of names:
+//
+// 	size [8]
+// 	modification time [8]
+// 	content hash [8]
+//
+// All values are big-endian.  The modification time is in nanoseconds
+// since the unix epoch and the content hash is the 64-bit FNV-1a hash of
+// the file contents.  The metadata is used by cindex to skip files that
+// haven't changed since they were last indexed.  The list sits in the gap
+// between the end of the list of paths and the start of the list of names,
+// which readers that don't know about it (like the Go tools) never look at.
+// Indexes written without metadata simply have no gap.
+//
 // The list of names is a sorted sequence of NUL-terminated file names.
 // The initial entry in the list corresponds to file #0,
 // the next to file #1, and so on.  The list ends with an
@@ -61,28 +77,200 @@
 // 	offset of posting lists [4]
 // 	offset of name index [4]
 // 	offset of posting list index [4]
+// 	checksum of path list [4]
+// 	checksum of name list [4]
+// 	checksum of posting lists [4]
+// 	checksum of name index [4]
+// 	checksum of posting list index [4]
 // 	"\ncsearch trailr\n"
+//
+// Each checksum is the CRC-32 (IEEE) of the bytes from the start of its
+// section up to the start of the next one; the path list checksum also
+// covers the file metadata, and the posting list index checksum runs up
+// to the trailer.
+//
+// Version 1 indexes, which are what the Go tools read and write, start
+// with "csearch index 1\n" instead and have no checksums in the trailer.
+// Otherwise the two versions are the same.
+//
+// Version 3 indexes start with "csearch index 3\n" and add a word of
+// format flags to the trailer, just before the trailer magic:
+//
+// 	format flags [4]
+//
+// FLAG_WIDE_OFFSETS makes every offset (the five offsets in the trailer,
+// the name index entries and the offsets in the posting list index)
+// 8 bytes wide instead of 4, for indexes larger than 4 GiB.
+//
+// FLAG_BLOCK_POSTINGS changes the encoding of the posting lists.  The
+// file IDs of each list are split into blocks of 128 (the last block may
+// be shorter), and each posting list has the form:
+//
+// 	trigram [3]
+// 	block table [8]...
+// 	blocks [v]...
+//
+// The block table has one entry per block, holding the first file ID of
+// the block [4] and the offset of the block from the end of the table [4].
+// Each block has the form:
+//
+// 	bit width [1]
+// 	packed deltas [v]
+//
+// The packed deltas are the differences between successive file IDs of the
+// block, minus one, stored in `bit width` bits each, least significant bit
+// first, and padded to a whole byte.  There is no terminating zero, since
+// the file count in the posting list index says how many file IDs there
+// are.  The block table lets a reader skip straight to the block that
+// can hold a file ID.
+//
+// Writers only use version 3 when they need one of the flags, so small
+// indexes are still written as version 2.  Readers refuse indexes with
+// flags they don't know about.
 
-use std::collections::BTreeSet;
+use std::cell::Cell;
 use std::fmt;
 use std::fmt::Debug;
-use std::io;
 use std::io::Cursor;
 use std::path::Path;
 
 use byteorder::{BigEndian, ReadBytesExt};
-use consts::TRAILER_MAGIC;
-use libvarint;
+use consts::{MAGIC, MAGIC_V2, MAGIC_V3, TRAILER_MAGIC};
 use memmap::{Mmap, Protection};
 
+use super::error::{IndexReadError, IndexReadErrorKind, IndexReadResult};
+use super::plan::QueryPlan;
+use super::postings::PostingList;
 use super::search;
-use regexp::{Query, QueryOperation};
+use super::stream::PostStream;
+use regexp::Query;
 
+/// Size of a posting list index entry, unless the index has wide offsets
 pub const POST_ENTRY_SIZE: usize = 3 + 4 + 4;
+pub const FILE_META_ENTRY_SIZE: usize = 8 + 8 + 8;
+
+/// Format flag: offsets are 8 bytes wide instead of 4
+pub const FLAG_WIDE_OFFSETS: u32 = 1;
+/// Format flag: posting lists are split into blocks of bit-packed deltas
+pub const FLAG_BLOCK_POSTINGS: u32 = 2;
+/// All the format flags this version knows how to read
+pub const KNOWN_FLAGS: u32 = FLAG_WIDE_OFFSETS | FLAG_BLOCK_POSTINGS;
+
+/// Version of the on-disk index format
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IndexVersion {
+    /// The original format, shared with the Go tools
+    V1,
+    /// Adds a checksum of each section to the trailer
+    #[default]
+    V2,
+    /// Adds a word of format flags to the trailer
+    V3,
+}
+
+impl IndexVersion {
+    /// Detects the version of an index from the magic at its start
+    pub fn detect(data: &[u8]) -> Option<IndexVersion> {
+        if data.starts_with(MAGIC.as_bytes()) {
+            Some(IndexVersion::V1)
+        } else if data.starts_with(MAGIC_V2.as_bytes()) {
+            Some(IndexVersion::V2)
+        } else if data.starts_with(MAGIC_V3.as_bytes()) {
+            Some(IndexVersion::V3)
+        } else {
+            None
+        }
+    }
+    /// Returns the magic an index of this version starts with
+    pub fn magic(self) -> &'static str {
+        match self {
+            IndexVersion::V1 => MAGIC,
+            IndexVersion::V2 => MAGIC_V2,
+            IndexVersion::V3 => MAGIC_V3,
+        }
+    }
+    /// Returns true if the trailer holds a checksum of each section
+    pub fn has_checksums(self) -> bool {
+        self != IndexVersion::V1
+    }
+    /// Returns true if the trailer holds format flags
+    pub fn has_flags(self) -> bool {
+        self == IndexVersion::V3
+    }
+}
+
+/// Layout of an index: its version and, for version 3, its format flags
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IndexFormat {
+    pub version: IndexVersion,
+    pub flags: u32,
+}
+
+impl IndexFormat {
+    /// Detects the format of the index in `data`.
+    ///
+    /// The format flags are read from the trailer, so `data` has to hold
+    /// the whole index.  Returns None if the magic isn't recognized.
+    pub fn detect(data: &[u8]) -> Option<IndexFormat> {
+        let version = IndexVersion::detect(data)?;
+        let mut flags = 0;
+        if version.has_flags() && data.len() >= MAGIC.len() + 4 + TRAILER_MAGIC.len() {
+            flags = read_u32_at(&data[data.len() - TRAILER_MAGIC.len() - 4..]);
+        }
+        Some(IndexFormat { version, flags })
+    }
+    /// Returns true if offsets are 8 bytes wide
+    pub fn wide_offsets(self) -> bool {
+        self.flags & FLAG_WIDE_OFFSETS != 0
+    }
+    /// Returns true if posting lists are block-encoded
+    pub fn block_postings(self) -> bool {
+        self.flags & FLAG_BLOCK_POSTINGS != 0
+    }
+    /// Returns the size of an offset
+    pub fn offset_len(self) -> usize {
+        if self.wide_offsets() {
+            8
+        } else {
+            4
+        }
+    }
+    /// Returns the size of a posting list index entry
+    pub fn post_entry_len(self) -> usize {
+        3 + 4 + self.offset_len()
+    }
+    /// Returns the size of the trailer, including the trailer magic
+    pub fn trailer_len(self) -> usize {
+        let checksums = if self.version.has_checksums() {
+            5 * 4
+        } else {
+            0
+        };
+        let flags = if self.version.has_flags() { 4 } else { 0 };
+        5 * self.offset_len() + checksums + flags + TRAILER_MAGIC.len()
+    }
+}
+
+impl From<IndexVersion> for IndexFormat {
+    fn from(version: IndexVersion) -> Self {
+        IndexFormat { version, flags: 0 }
+    }
+}
 
 /// Simple alias for an ID representing a filename in the Index.
 pub type FileId = u32;
 
+/// Metadata recorded for each indexed file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FileMeta {
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Modification time in nanoseconds since the unix epoch
+    pub mtime: u64,
+    /// FNV-1a hash of the file contents
+    pub hash: u64,
+}
+
 /// Representation of an Index
 ///
 /// ```rust
@@ -99,19 +287,22 @@ pub type FileId = u32;
 ///
 /// let idx = try!(IndexReader::open("foo.txt"));
 ///
-/// let matching_file_ids = idx.query(q);
+/// let matching_file_ids = try!(idx.query(q));
 ///
-/// for each in matching_file_ids.into_inner() {
-///    println!("filename = {}", idx.name(each));
+/// for each in matching_file_ids {
+///    println!("filename = {}", try!(idx.name(each)));
 /// }
 /// # Ok(())
 /// # }
 /// ```
 pub struct IndexReader {
     data: Mmap,
-    path_data: u32,
-    name_data: u32,
-    pub post_data: u32,
+    format: IndexFormat,
+    trailer: usize,
+    path_data: usize,
+    meta_data: usize,
+    name_data: usize,
+    pub post_data: usize,
     name_index: usize,
     pub post_index: usize,
     pub num_name: usize,
@@ -134,195 +325,399 @@ impl Debug for IndexReader {
     }
 }
 
-fn extract_data_from_mmap(data: &Mmap, offset: usize) -> u32 {
-    unsafe {
-        let mut buf = Cursor::new(&data.as_slice()[offset..offset + 4]);
-        buf.read_u32::<BigEndian>().unwrap()
+/// Reads a big-endian u32 from the start of `d`, which must be at least 4 bytes
+fn read_u32_at(d: &[u8]) -> u32 {
+    let mut buf = Cursor::new(d);
+    buf.read_u32::<BigEndian>().unwrap()
+}
+
+/// Reads a big-endian offset from the start of `d`, which must be at least
+/// 8 bytes if `wide` is set and 4 bytes otherwise
+fn read_offset_at(d: &[u8], wide: bool) -> u64 {
+    let mut buf = Cursor::new(d);
+    if wide {
+        buf.read_u64::<BigEndian>().unwrap()
+    } else {
+        buf.read_u32::<BigEndian>().unwrap() as u64
     }
 }
 
 impl IndexReader {
-    fn extract_data(&self, offset: usize) -> u32 {
-        unsafe {
-            let mut buf = Cursor::new(&self.data.as_slice()[offset..offset + 4]);
-            buf.read_u32::<BigEndian>().unwrap()
+    fn extract_data(&self, offset: usize) -> IndexReadResult<u32> {
+        match unsafe { self.data.as_slice() }.get(offset..offset + 4) {
+            Some(d) => Ok(read_u32_at(d)),
+            None => Err(IndexReadError::new(
+                IndexReadErrorKind::OffsetOutOfRange,
+                format!("corrupt index: offset {} is past the end", offset),
+            )),
+        }
+    }
+    fn extract_offset(&self, offset: usize) -> IndexReadResult<u64> {
+        let len = self.format.offset_len();
+        match unsafe { self.data.as_slice() }.get(offset..offset + len) {
+            Some(d) => Ok(read_offset_at(d, self.format.wide_offsets())),
+            None => Err(IndexReadError::new(
+                IndexReadErrorKind::OffsetOutOfRange,
+                format!("corrupt index: offset {} is past the end", offset),
+            )),
         }
     }
     /// Open an index file from path
     ///
+    /// Indexes of every version can be opened.  The section checksums of
+    /// a version 2 or 3 index aren't checked here, since that means
+    /// reading the whole index; use `verify` for that.
+    ///
+    /// The header, trailer and section offsets are checked, so opening a
+    /// truncated or garbled file fails instead of panicking later on.
+    ///
     /// ```no_run
-    /// # use libcsearch::reader::IndexReader;
-    /// # use std::io;
-    /// # fn foo() -> io::Result<()> {
+    /// # use libcsearch::reader::{IndexReader, IndexReadResult};
+    /// # fn foo() -> IndexReadResult<()> {
     /// let idx = try!(IndexReader::open("foo.txt"));
     /// # Ok(())
     /// # }
-    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<IndexReader> {
-        Mmap::open_path(path, Protection::Read).map(|m| {
-            let n = m.len() - (TRAILER_MAGIC.bytes().len()) - 5 * 4;
-            let path_data = extract_data_from_mmap(&m, n);
-            let name_data = extract_data_from_mmap(&m, n + 4);
-            let post_data = extract_data_from_mmap(&m, n + 8);
-            let name_index = extract_data_from_mmap(&m, n + 12) as usize;
-            let post_index = extract_data_from_mmap(&m, n + 16) as usize;
-            let num_name: usize = if post_index > name_index {
-                let d = (post_index - name_index) / 4;
-                if d == 0 {
-                    0
-                } else {
-                    (d - 1) as usize
-                }
-            } else {
-                0
-            };
-            let num_post = if n > (post_index as usize) {
-                (n - (post_index as usize)) / (3 + 4 + 4)
-            } else {
-                0
-            };
-            IndexReader {
-                data: m,
-                path_data: path_data,
-                name_data: name_data,
-                post_data: post_data,
-                name_index: name_index,
-                post_index: post_index,
-                num_name: num_name,
-                num_post: num_post,
+    pub fn open<P: AsRef<Path>>(path: P) -> IndexReadResult<IndexReader> {
+        let m = Mmap::open_path(path, Protection::Read)?;
+        let (format, n, off) = {
+            let data = unsafe { m.as_slice() };
+            let format = IndexFormat::detect(data).ok_or_else(|| {
+                IndexReadError::new(IndexReadErrorKind::BadMagic, "not a csearch index")
+            })?;
+            if data.len() < MAGIC.len() + format.trailer_len()
+                || !data.ends_with(TRAILER_MAGIC.as_bytes())
+            {
+                return Err(IndexReadError::new(
+                    IndexReadErrorKind::TruncatedSection,
+                    "corrupt index: missing trailer (truncated?)",
+                ));
             }
-        })
+            if format.flags & !KNOWN_FLAGS != 0 {
+                return Err(IndexReadError::new(
+                    IndexReadErrorKind::UnsupportedFormat,
+                    format!("index has unknown format flags {:08x}", format.flags),
+                ));
+            }
+            let n = data.len() - format.trailer_len();
+            let len = format.offset_len();
+            let mut off = [0usize; 5];
+            for (i, o) in off.iter_mut().enumerate() {
+                *o = read_offset_at(&data[n + len * i..], format.wide_offsets()) as usize;
+            }
+            let in_order = off.windows(2).all(|w| w[0] <= w[1]);
+            if off[0] < MAGIC.len() || !in_order || off[4] > n {
+                return Err(IndexReadError::new(
+                    IndexReadErrorKind::OffsetOutOfRange,
+                    format!("corrupt index: bad section offsets {:?}", off),
+                ));
+            }
+            (format, n, off)
+        };
+        let [path_data, name_data, post_data, name_index, post_index] = off;
+        let num_name = ((post_index - name_index) / format.offset_len()).saturating_sub(1);
+        let num_post = (n - post_index) / format.post_entry_len();
+        let mut ix = IndexReader {
+            data: m,
+            format,
+            trailer: n,
+            path_data,
+            meta_data: 0,
+            name_data,
+            post_data,
+            name_index,
+            post_index,
+            num_name,
+            num_post,
+        };
+        // the file metadata (if any) sits between the path list and the name list
+        ix.meta_data = ix.end_of_paths()?;
+        Ok(ix)
     }
 
     /// Takes a query and returns a list of matching file IDs.
-    pub fn query<'a>(&'a self, query: Query) -> PostSet<'a> {
-        // writeln!(io::stderr(), "query {:?}", query).unwrap();
-        match query.operation {
-            QueryOperation::None => PostSet::new(self),
-            QueryOperation::All => PostSet {
-                index: self,
-                list: (0..self.num_name as u32).collect::<BTreeSet<FileId>>(),
-            },
-            QueryOperation::And => {
-                // writeln!(io::stderr(), "AND {:?}", query.trigram).unwrap();
-                let mut trigram_it = query
-                    .trigram
-                    .into_iter()
-                    .map(|t| (t[0] as u32) << 16 | (t[1] as u32) << 8 | (t[2] as u32));
-                let mut sub_iter = query.sub.into_iter().map(|q| self.query(q));
-                let post_set = if let Some(i) = trigram_it.next() {
-                    let s = PostSet::new(self).or(i).unwrap_or(PostSet::new(self));
-                    Some(trigram_it.fold(s, |a, b| a.and(b).unwrap_or(PostSet::new(self))))
-                } else {
-                    sub_iter.next()
-                };
-                let post_set = if let Some(ps) = post_set {
-                    ps
-                } else {
-                    return PostSet::new(self);
-                };
-                let sub_iter = sub_iter.map(|q| q.into_inner());
-                sub_iter.fold(post_set, |mut a, b| {
-                    a.list = &a.list & &b;
-                    a
-                })
+    /// Returns the sorted IDs of the files that may match `query`
+    pub fn query(&self, query: Query) -> IndexReadResult<Vec<FileId>> {
+        self.query_stream(&query)?.into_vec()
+    }
+
+    /// Returns the IDs of the files that may match `query` as a stream,
+    /// which only reads the posting lists as far as the caller reads it
+    pub fn query_stream<'a>(&'a self, query: &Query) -> IndexReadResult<PostStream<'a>> {
+        self.plan_stream(&self.plan(query))
+    }
+
+    /// Returns how `query` would be evaluated
+    pub fn plan(&self, query: &Query) -> QueryPlan {
+        QueryPlan::new(self, query)
+    }
+
+    /// Returns the IDs of the files that may match the query `plan` was
+    /// made for, as a stream
+    pub fn plan_stream<'a>(&'a self, plan: &QueryPlan) -> IndexReadResult<PostStream<'a>> {
+        match *plan {
+            QueryPlan::None => Ok(PostStream::empty()),
+            QueryPlan::All => Ok(PostStream::all(self.num_name as u64)),
+            QueryPlan::Trigram { trigram, .. } => Ok(PostStream::list(self.posting_list(trigram)?)),
+            QueryPlan::And { ref operands, .. } => {
+                let streams = operands.iter().map(|p| self.plan_stream(p));
+                Ok(PostStream::and(streams.collect::<IndexReadResult<_>>()?))
             }
-            QueryOperation::Or => {
-                // writeln!(io::stderr(), "OR {:?}", query.trigram).unwrap();
-                let trigram_it = query
-                    .trigram
-                    .into_iter()
-                    .map(|t| (t[0] as u32) << 16 | (t[1] as u32) << 8 | (t[2] as u32));
-                let post_set = trigram_it.fold(PostSet::new(self), |a, b| {
-                    a.or(b).unwrap_or(PostSet::new(self))
-                });
-                // writeln!(io::stderr(), "post set size = {:?}", post_set.list.len()).unwrap();
-                query
-                    .sub
-                    .into_iter()
-                    .map(|q| self.query(q).into_inner())
-                    .fold(post_set, |mut a, b| {
-                        a.list.extend(b.into_iter());
-                        a
-                    })
+            QueryPlan::Or(ref operands) => {
+                let streams = operands.iter().map(|p| self.plan_stream(p));
+                Ok(PostStream::or(streams.collect::<IndexReadResult<_>>()?))
             }
         }
     }
 
+    /// Returns the number of candidate files after each operand of an AND
+    /// or OR plan is applied, in order, to see how a query narrows down.
+    /// Other plans have a single step.
+    pub fn plan_steps(&self, plan: &QueryPlan) -> IndexReadResult<Vec<usize>> {
+        let count = |p: &QueryPlan| -> IndexReadResult<usize> {
+            let mut n = 0;
+            let mut s = self.plan_stream(p)?;
+            while s.next_id()?.is_some() {
+                n += 1;
+            }
+            Ok(n)
+        };
+        let (operands, and) = match *plan {
+            QueryPlan::And { ref operands, .. } => (operands, true),
+            QueryPlan::Or(ref operands) => (operands, false),
+            _ => return Ok(vec![count(plan)?]),
+        };
+        let mut steps = Vec::with_capacity(operands.len());
+        for i in 1..operands.len() + 1 {
+            let prefix = operands[..i].to_vec();
+            let step = if and {
+                QueryPlan::And {
+                    operands: prefix,
+                    skipped: Vec::new(),
+                }
+            } else {
+                QueryPlan::Or(prefix)
+            };
+            steps.push(count(&step)?);
+        }
+        Ok(steps)
+    }
+
     /// Returns the size of the index
     pub fn len(&self) -> usize {
         self.data.len()
     }
 
+    /// Returns the format version of the index
+    pub fn version(&self) -> IndexVersion {
+        self.format.version
+    }
+
+    /// Returns the format of the index, including its format flags
+    pub fn format(&self) -> IndexFormat {
+        self.format
+    }
+
+    /// Returns the checksums of the five sections of the index, in
+    /// order, or None if the index is too old to have them.
+    pub fn checksums(&self) -> Option<[u32; 5]> {
+        if !self.format.version.has_checksums() {
+            return None;
+        }
+        let start = self.trailer + 5 * self.format.offset_len();
+        let mut sums = [0; 5];
+        for (i, sum) in sums.iter_mut().enumerate() {
+            // the trailer was bounds-checked in open
+            *sum = self.extract_data(start + 4 * i).unwrap_or(0);
+        }
+        Some(sums)
+    }
+
     /// Returns the index as a slice
     pub unsafe fn as_slice(&self) -> &[u8] {
         self.data.as_slice()
     }
 
     /// Returns all indexed paths
-    pub fn indexed_paths(&self) -> Vec<String> {
+    pub fn indexed_paths(&self) -> IndexReadResult<Vec<String>> {
         let mut paths = Vec::new();
-        let mut offset = self.path_data as usize;
+        let mut offset = self.path_data;
         loop {
-            let s = self.extract_string_at(offset);
-            if s.len() == 0 {
+            let s = self.extract_string_at(offset, self.name_data)?;
+            if s.is_empty() {
                 break;
             }
             offset += s.len() + 1;
             paths.push(s);
         }
-        paths
+        Ok(paths)
+    }
+
+    /// Returns the offset just past the terminating empty path
+    fn end_of_paths(&self) -> IndexReadResult<usize> {
+        let mut offset = self.path_data;
+        loop {
+            let s = self.extract_string_at(offset, self.name_data)?;
+            offset += s.len() + 1;
+            if s.is_empty() {
+                return Ok(offset);
+            }
+        }
+    }
+
+    /// Returns true if the index records metadata for each file
+    pub fn has_file_meta(&self) -> bool {
+        let meta_len = self.name_data.saturating_sub(self.meta_data);
+        self.num_name > 0 && meta_len == self.num_name * FILE_META_ENTRY_SIZE
+    }
+
+    /// Returns the metadata recorded for the file identified by file_id,
+    /// or None if the index was written without file metadata.
+    pub fn file_meta(&self, file_id: FileId) -> Option<FileMeta> {
+        if !self.has_file_meta() || (file_id as usize) >= self.num_name {
+            return None;
+        }
+        let offset = self.meta_data + (file_id as usize) * FILE_META_ENTRY_SIZE;
+        let mut d = unsafe { &self.data.as_slice()[offset..offset + FILE_META_ENTRY_SIZE] };
+        Some(FileMeta {
+            size: d.read_u64::<BigEndian>().unwrap(),
+            mtime: d.read_u64::<BigEndian>().unwrap(),
+            hash: d.read_u64::<BigEndian>().unwrap(),
+        })
+    }
+
+    /// Returns the ID of the file named `name`, if it is in the index
+    pub fn find_name(&self, name: &str) -> IndexReadResult<Option<FileId>> {
+        let error = Cell::new(None);
+        let i = search::search(self.num_name, |i| match self.name(i as FileId) {
+            Ok(n) => n.as_str() >= name,
+            Err(e) => {
+                error.set(Some(e));
+                true
+            }
+        });
+        if let Some(e) = error.into_inner() {
+            return Err(e);
+        }
+        if i < self.num_name && self.name(i as FileId)? == name {
+            Ok(Some(i as FileId))
+        } else {
+            Ok(None)
+        }
     }
 
     /// Returns the name of a file identified by file_id
-    pub fn name(&self, file_id: FileId) -> String {
+    pub fn name(&self, file_id: FileId) -> IndexReadResult<String> {
         let file_id_usize = file_id as usize;
-        let offset = self.extract_data(self.name_index + 4 * file_id_usize);
-        self.extract_string_at((self.name_data + offset) as usize)
+        if file_id_usize >= self.num_name {
+            return Err(IndexReadError::new(
+                IndexReadErrorKind::OffsetOutOfRange,
+                format!(
+                    "corrupt index: file {} is out of range ({} files)",
+                    file_id, self.num_name
+                ),
+            ));
+        }
+        let offset_len = self.format.offset_len();
+        let offset = self.extract_offset(self.name_index + offset_len * file_id_usize)?;
+        let name_start = self.name_data.saturating_add(offset as usize);
+        self.extract_string_at(name_start, self.post_data)
     }
 
-    pub fn list_at(&self, offset: usize) -> (u32, u32, u32) {
+    /// Returns the trigram, file count and offset of the posting list index
+    /// entry at `offset` bytes into the posting list index
+    pub fn list_at(&self, offset: usize) -> IndexReadResult<(u32, u32, u64)> {
+        let entry_len = self.format.post_entry_len();
+        if offset + entry_len > entry_len * self.num_post {
+            return Err(IndexReadError::new(
+                IndexReadErrorKind::OffsetOutOfRange,
+                format!("posting list index entry at {} is out of range", offset),
+            ));
+        }
         let d: &[u8] = unsafe {
             let s = self.data.as_slice();
-            let (_, right_side) = s.split_at(self.post_index + offset);
-            let (d, _) = right_side.split_at(POST_ENTRY_SIZE);
-            d
+            &s[self.post_index + offset..self.post_index + offset + entry_len]
         };
         let tri_val = (d[0] as u32) << 16 | (d[1] as u32) << 8 | (d[2] as u32);
-        let count = {
-            let (_, mut right) = d.split_at(3);
-            right.read_u32::<BigEndian>().unwrap()
-        };
-        let offset = {
-            let (_, mut right) = d.split_at(3 + 4);
-            right.read_u32::<BigEndian>().unwrap()
-        };
-        (tri_val, count, offset)
-    }
-
-    /// Extract a null-terminated string from `offset`
-    fn extract_string_at(&self, offset: usize) -> String {
-        let mut index = 0;
-        let mut s = String::new();
-        unsafe {
-            let sl = self.as_slice();
-            while sl[offset + index] != 0 {
-                s.push(sl[offset + index] as char);
-                index += 1;
+        let count = read_u32_at(&d[3..]);
+        let offset = read_offset_at(&d[3 + 4..], self.format.wide_offsets());
+        Ok((tri_val, count, offset))
+    }
+
+    /// Returns the data of the posting list at `offset` in the list of
+    /// posting lists, just past its trigram and running up to the end of
+    /// the list of posting lists.
+    pub fn post_list_data(&self, offset: u64) -> IndexReadResult<&[u8]> {
+        let start = self
+            .post_data
+            .saturating_add(offset as usize)
+            .saturating_add(3);
+        if start > self.name_index {
+            return Err(IndexReadError::new(
+                IndexReadErrorKind::OffsetOutOfRange,
+                format!("corrupt index: posting list at {} is out of range", offset),
+            ));
+        }
+        Ok(unsafe { &self.data.as_slice()[start..self.name_index] })
+    }
+
+    /// Returns the number of files that contain `trigram`
+    pub fn trigram_count(&self, trigram: u32) -> u32 {
+        self.find_list(trigram).0 as u32
+    }
+
+    /// Returns a reader for the posting list of `trigram`, which is empty
+    /// if no file contains `trigram`.
+    pub fn posting_list<'a>(&'a self, trigram: u32) -> IndexReadResult<PostingList<'a>> {
+        let (count, offset) = self.find_list(trigram);
+        if count <= 0 {
+            return Ok(PostingList::empty());
+        }
+        PostingList::new(self.post_list_data(offset)?, count as usize, self.format)
+    }
+
+    /// Returns a reader for the posting list of `count` file IDs at
+    /// `offset` in the list of posting lists, as returned by `list_at`.
+    pub fn posting_list_at<'a>(
+        &'a self,
+        count: u32,
+        offset: u64,
+    ) -> IndexReadResult<PostingList<'a>> {
+        PostingList::new(self.post_list_data(offset)?, count as usize, self.format)
+    }
+
+    /// Extract a null-terminated string from `offset`, which must end before `end`
+    fn extract_string_at(&self, offset: usize, end: usize) -> IndexReadResult<String> {
+        let sl = unsafe { self.as_slice() };
+        let sl = match sl.get(offset..end) {
+            Some(sl) => sl,
+            None => {
+                return Err(IndexReadError::new(
+                    IndexReadErrorKind::OffsetOutOfRange,
+                    format!("corrupt index: string at {} is out of range", offset),
+                ))
             }
-            s
+        };
+        match sl.iter().position(|&b| b == 0) {
+            // names are written as UTF-8, so decoding them byte by byte would
+            // mangle any that aren't ASCII
+            Some(len) => Ok(String::from_utf8_lossy(&sl[..len]).into_owned()),
+            None => Err(IndexReadError::new(
+                IndexReadErrorKind::TruncatedSection,
+                format!("corrupt index: string at {} isn't terminated", offset),
+            )),
         }
     }
 
     /// Returns the offset and size of a list
-    fn find_list(&self, trigram: u32) -> (isize, u32) {
+    fn find_list(&self, trigram: u32) -> (isize, u64) {
+        let entry_len = self.format.post_entry_len();
+        // open made sure the posting list index lies within the index
         let d: &[u8] = unsafe {
             let s = self.data.as_slice();
-            let (_, right_side) = s.split_at(self.post_index);
-            let (d, _) = right_side.split_at(POST_ENTRY_SIZE * self.num_post);
-            d
+            &s[self.post_index..self.post_index + entry_len * self.num_post]
         };
         let result = search::search(self.num_post, |i| {
-            let i_scaled = i * POST_ENTRY_SIZE;
+            let i_scaled = i * entry_len;
             let tri_val = (d[i_scaled] as u32) << 16
                 | (d[i_scaled + 1] as u32) << 8
                 | (d[i_scaled + 2] as u32);
@@ -331,202 +726,62 @@ impl IndexReader {
         if result >= self.num_post {
             return (0, 0);
         }
-        let result_scaled: usize = result * POST_ENTRY_SIZE;
+        let result_scaled: usize = result * entry_len;
         let tri_val = (d[result_scaled] as u32) << 16
             | (d[result_scaled + 1] as u32) << 8
             | (d[result_scaled + 2] as u32);
         if tri_val != trigram {
             return (0, 0);
         }
-        let count = {
-            let (_, mut right) = d.split_at(result_scaled + 3);
-            right.read_i32::<BigEndian>().unwrap() as isize
-        };
-        let offset = {
-            let (_, mut right) = d.split_at(result_scaled + 3 + 4);
-            right.read_u32::<BigEndian>().unwrap()
-        };
+        let count = read_u32_at(&d[result_scaled + 3..]) as isize;
+        let offset = read_offset_at(&d[result_scaled + 3 + 4..], self.format.wide_offsets());
         (count, offset)
     }
 }
 
-#[derive(Debug)]
-pub struct PostReader<'a, 'b> {
-    count: isize,
-    file_id: i64,
-    d: &'a [u8],
-    restrict: &'b Option<BTreeSet<u32>>,
-}
+/// Set operations between a sorted list of file IDs and the posting list
+/// of a trigram.  If `restrict` is given, only the file IDs in it, which
+/// must be sorted, are returned.
+pub struct PostReader;
 
-impl<'a, 'b> PostReader<'a, 'b> {
-    pub fn new(
+impl PostReader {
+    fn stream<'a>(
         index: &'a IndexReader,
         trigram: u32,
-        restrict: &'b Option<BTreeSet<u32>>,
-    ) -> Option<Self> {
-        let (count, offset) = index.find_list(trigram);
-        if count == 0 {
-            return None;
-        }
-        let view = unsafe {
-            let v = index.data.as_slice();
-            let split_point = (index.post_data as usize) + (offset as usize) + 3;
-            v.split_at(split_point).1
-        };
-        Some(PostReader {
-            count,
-            file_id: -1,
-            d: view,
-            restrict,
+        restrict: Option<&[FileId]>,
+    ) -> IndexReadResult<PostStream<'a>> {
+        let list = PostStream::list(index.posting_list(trigram)?);
+        Ok(match restrict {
+            Some(r) => PostStream::and(vec![list, PostStream::ids(r.to_vec())]),
+            None => list,
         })
     }
+    /// Returns the file IDs in `list` that contain `trigram`
     pub fn and(
-        index: &'a IndexReader,
-        list: BTreeSet<u32>,
+        index: &IndexReader,
+        list: Vec<FileId>,
         trigram: u32,
-        restrict: &'b Option<BTreeSet<u32>>,
-    ) -> BTreeSet<u32> {
-        if let Some(mut r) = Self::new(index, trigram, restrict) {
-            let mut h = BTreeSet::new();
-            while r.next() {
-                let file_id = r.file_id;
-                if list.contains(&(file_id as u32)) {
-                    h.insert(file_id as u32);
-                }
-            }
-            h
-        } else {
-            BTreeSet::new()
-        }
+        restrict: Option<&[FileId]>,
+    ) -> IndexReadResult<Vec<FileId>> {
+        let r = Self::stream(index, trigram, restrict)?;
+        PostStream::and(vec![PostStream::ids(list), r]).into_vec()
     }
+    /// Returns the file IDs in `list` and the ones that contain `trigram`
     pub fn or(
-        index: &'a IndexReader,
-        list: BTreeSet<u32>,
+        index: &IndexReader,
+        list: Vec<FileId>,
         trigram: u32,
-        restrict: &'b Option<BTreeSet<u32>>,
-    ) -> BTreeSet<u32> {
-        if let Some(mut r) = Self::new(index, trigram, restrict) {
-            let mut h = list;
-            while r.next() {
-                h.insert(r.file_id as u32);
-            }
-            h
-        } else {
-            BTreeSet::new()
-        }
+        restrict: Option<&[FileId]>,
+    ) -> IndexReadResult<Vec<FileId>> {
+        let r = Self::stream(index, trigram, restrict)?;
+        PostStream::or(vec![PostStream::ids(list), r]).into_vec()
     }
+    /// Returns the file IDs that contain `trigram`
     pub fn list(
-        index: &'a IndexReader,
+        index: &IndexReader,
         trigram: u32,
-        restrict: &'b Option<BTreeSet<u32>>,
-    ) -> BTreeSet<u32> {
-        if let Some(mut r) = Self::new(index, trigram, restrict) {
-            let mut x = BTreeSet::<u32>::new();
-            while r.next() {
-                x.insert(r.file_id as u32);
-            }
-            x
-        } else {
-            BTreeSet::new()
-        }
-    }
-    // FIXME: refactor either to use rust iterator or don't look like an iterator
-    fn next(&mut self) -> bool {
-        while self.count > 0 {
-            self.count -= 1;
-            let (delta, n) = libvarint::read_uvarint(self.d).unwrap();
-            if n <= 0 || delta == 0 {
-                panic!("corrupt index");
-            }
-            self.d = self.d.split_at(n as usize).1;
-            self.file_id += delta as i64;
-            let is_file_id_found = match *self.restrict {
-                Some(ref r) if r.contains(&(self.file_id as u32)) => true,
-                None => true,
-                _ => false,
-            };
-            if !is_file_id_found {
-                continue;
-            }
-            return true;
-        }
-        // list should end with terminating 0 delta
-        // FIXME: add bounds checking
-        self.file_id = -1;
-        return false;
-    }
-}
-
-pub struct PostSet<'a> {
-    index: &'a IndexReader,
-    list: BTreeSet<u32>,
-}
-
-impl<'a> PostSet<'a> {
-    pub fn new(index: &'a IndexReader) -> Self {
-        PostSet {
-            index: index,
-            list: BTreeSet::new(),
-        }
-    }
-    pub fn into_inner(self) -> BTreeSet<u32> {
-        self.list
-    }
-    pub fn and(self, trigram: u32) -> Option<Self> {
-        let (mut d, count) = unsafe {
-            if let Some(tup) = Self::make_view(&self.index, trigram) {
-                tup
-            } else {
-                return None;
-            }
-        };
-        let mut file_id = -1;
-        let mut h = BTreeSet::new();
-        for _ in 0..count {
-            let (delta, n) = libvarint::read_uvarint(d).unwrap();
-            if n <= 0 || delta == 0 {
-                panic!("corrupt index");
-            }
-            d = d.split_at(n as usize).1;
-            file_id += delta as i64;
-            if self.list.contains(&(file_id as u32)) {
-                h.insert(file_id as u32);
-            }
-        }
-        Some(PostSet {
-            index: self.index,
-            list: h,
-        })
-    }
-    pub fn or(mut self, trigram: u32) -> Option<Self> {
-        let (mut d, count) = unsafe {
-            if let Some(tup) = Self::make_view(&self.index, trigram) {
-                tup
-            } else {
-                return Some(self);
-            }
-        };
-        let mut file_id = -1;
-        // writeln!(io::stderr(), "TRI 0x{:6x}: {}", trigram, count).unwrap();
-        for _ in 0..count {
-            let (delta, n) = libvarint::read_uvarint(d).unwrap();
-            if n <= 0 || delta == 0 {
-                panic!("corrupt index");
-            }
-            d = d.split_at(n as usize).1;
-            file_id += delta as i64;
-            self.list.insert(file_id as u32);
-        }
-        Some(self)
-    }
-    unsafe fn make_view(index: &'a IndexReader, trigram: u32) -> Option<(&'a [u8], usize)> {
-        let (count, offset) = index.find_list(trigram);
-        if count == 0 {
-            // writeln!(io::stderr(), "TRI 0x{:6x}: 0", trigram).unwrap();
-            return None;
-        }
-        let v = index.data.as_slice();
-        let split_point = (index.post_data as usize) + (offset as usize) + 3;
-        Some((v.split_at(split_point).1, count as usize))
+        restrict: Option<&[FileId]>,
+    ) -> IndexReadResult<Vec<FileId>> {
+        Self::stream(index, trigram, restrict)?.into_vec()
     }
 }
@@ -0,0 +1,293 @@
+// Copyright 2016 Vernon Jones.
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Lazy evaluation of queries.
+//
+// A query is evaluated as a tree of PostStreams, each of which yields file
+// IDs in increasing order.  The leaves read posting lists straight from the
+// index, AND nodes intersect their children by repeatedly advancing each one
+// to the largest file ID seen so far, and OR nodes merge their children with
+// a heap.  Nothing is materialized until the caller collects the result, and
+// AND nodes only decode the parts of their children's lists that can hold a
+// match.
+
+use std::cmp::{self, Reverse};
+use std::collections::BinaryHeap;
+
+use super::error::IndexReadResult;
+use super::postings::PostingList;
+use super::read::FileId;
+
+enum Source<'a> {
+    /// The file IDs `next..end`
+    Range {
+        next: u64,
+        end: u64,
+    },
+    /// Sorted file IDs held in memory, from `pos` on
+    Ids {
+        ids: Vec<FileId>,
+        pos: usize,
+    },
+    List(PostingList<'a>),
+    And(Vec<PostStream<'a>>),
+    Or {
+        streams: Vec<PostStream<'a>>,
+        /// The next file ID of each stream that has one, with its index in
+        /// `streams`; None until the first read
+        heads: Option<BinaryHeap<Reverse<(FileId, usize)>>>,
+    },
+}
+
+/// A stream of file IDs in increasing order, such as the files that match
+/// a query
+pub struct PostStream<'a> {
+    source: Source<'a>,
+    /// A file ID that was read from `source` but not returned yet
+    pending: Option<FileId>,
+}
+
+impl<'a> PostStream<'a> {
+    fn new(source: Source<'a>) -> Self {
+        PostStream {
+            source,
+            pending: None,
+        }
+    }
+
+    /// Returns a stream with no file IDs
+    pub fn empty() -> Self {
+        Self::new(Source::Range { next: 0, end: 0 })
+    }
+
+    /// Returns a stream of every file ID below `num_files`
+    pub fn all(num_files: u64) -> Self {
+        Self::new(Source::Range {
+            next: 0,
+            end: num_files,
+        })
+    }
+
+    /// Returns a stream of `ids`, which must be sorted and distinct
+    pub fn ids(ids: Vec<FileId>) -> Self {
+        Self::new(Source::Ids { ids, pos: 0 })
+    }
+
+    /// Returns a stream of the file IDs in a posting list
+    pub fn list(list: PostingList<'a>) -> Self {
+        Self::new(Source::List(list))
+    }
+
+    /// Returns a stream of the file IDs that are in all of `streams`, or
+    /// no file IDs if there are no streams
+    pub fn and(streams: Vec<PostStream<'a>>) -> Self {
+        if streams.is_empty() {
+            return Self::empty();
+        }
+        Self::new(Source::And(streams))
+    }
+
+    /// Returns a stream of the file IDs that are in any of `streams`
+    pub fn or(streams: Vec<PostStream<'a>>) -> Self {
+        Self::new(Source::Or {
+            streams,
+            heads: None,
+        })
+    }
+
+    /// Returns the next file ID, or None at the end of the stream
+    pub fn next_id(&mut self) -> IndexReadResult<Option<FileId>> {
+        self.advance_to(0)
+    }
+
+    /// Reads up to the first file ID that is at least `target` and returns
+    /// it, or None if the rest of the stream is smaller than `target`.
+    /// Like `next_id`, this never returns a file ID twice.
+    pub fn advance_to(&mut self, target: FileId) -> IndexReadResult<Option<FileId>> {
+        if let Some(id) = self.pending.take() {
+            if id >= target {
+                return Ok(Some(id));
+            }
+        }
+        match self.source {
+            Source::Range { ref mut next, end } => {
+                *next = cmp::max(*next, target as u64);
+                if *next >= end {
+                    return Ok(None);
+                }
+                *next += 1;
+                Ok(Some((*next - 1) as FileId))
+            }
+            Source::Ids {
+                ref ids,
+                ref mut pos,
+            } => {
+                *pos += gallop(&ids[*pos..], target);
+                let id = ids.get(*pos).cloned();
+                if id.is_some() {
+                    *pos += 1;
+                }
+                Ok(id)
+            }
+            Source::List(ref mut list) => list.advance_to(target),
+            Source::And(ref mut streams) => and_advance_to(streams, target),
+            Source::Or {
+                ref mut streams,
+                ref mut heads,
+            } => or_advance_to(streams, heads, target),
+        }
+    }
+
+    /// Reads the rest of the stream into a sorted vector
+    pub fn into_vec(mut self) -> IndexReadResult<Vec<FileId>> {
+        let mut ids = Vec::new();
+        while let Some(id) = self.next_id()? {
+            ids.push(id);
+        }
+        Ok(ids)
+    }
+}
+
+impl<'a> Iterator for PostStream<'a> {
+    type Item = IndexReadResult<FileId>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_id().transpose()
+    }
+}
+
+/// Returns the index of the first ID in `ids` that is at least `target`,
+/// or `ids.len()` if there is none.  The search doubles its step from the
+/// start of `ids` before bisecting, so it's cheap when the answer is near.
+fn gallop(ids: &[FileId], target: FileId) -> usize {
+    let mut hi = 1;
+    while hi <= ids.len() && ids[hi - 1] < target {
+        hi *= 2;
+    }
+    let lo = hi / 2;
+    let hi = cmp::min(hi, ids.len());
+    lo + ids[lo..hi].partition_point(|&id| id < target)
+}
+
+fn and_advance_to(
+    streams: &mut [PostStream],
+    mut target: FileId,
+) -> IndexReadResult<Option<FileId>> {
+    'next: loop {
+        for s in streams.iter_mut() {
+            match s.advance_to(target)? {
+                None => return Ok(None),
+                Some(id) if id > target => {
+                    // the streams before this one need to catch up to id,
+                    // and this one needs to return it again when they have
+                    s.pending = Some(id);
+                    target = id;
+                    continue 'next;
+                }
+                Some(_) => {}
+            }
+        }
+        // every stream was at `target`
+        return Ok(Some(target));
+    }
+}
+
+fn or_advance_to(
+    streams: &mut [PostStream],
+    heads: &mut Option<BinaryHeap<Reverse<(FileId, usize)>>>,
+    target: FileId,
+) -> IndexReadResult<Option<FileId>> {
+    if heads.is_none() {
+        let mut h = BinaryHeap::with_capacity(streams.len());
+        for (i, s) in streams.iter_mut().enumerate() {
+            if let Some(id) = s.advance_to(target)? {
+                h.push(Reverse((id, i)));
+            }
+        }
+        *heads = Some(h);
+    }
+    let heads = heads.as_mut().unwrap();
+    // move the streams that are behind up to target
+    while let Some(&Reverse((id, i))) = heads.peek() {
+        if id >= target {
+            break;
+        }
+        heads.pop();
+        if let Some(id) = streams[i].advance_to(target)? {
+            heads.push(Reverse((id, i)));
+        }
+    }
+    let id = match heads.peek() {
+        Some(&Reverse((id, _))) => id,
+        None => return Ok(None),
+    };
+    // move every stream that's at id past it
+    while let Some(&Reverse((head, i))) = heads.peek() {
+        if head != id {
+            break;
+        }
+        heads.pop();
+        if let Some(next) = streams[i].next_id()? {
+            heads.push(Reverse((next, i)));
+        }
+    }
+    Ok(Some(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(s: PostStream) -> Vec<FileId> {
+        s.into_vec().unwrap()
+    }
+
+    #[test]
+    fn test_gallop() {
+        let ids = [1, 3, 5, 7, 9, 11];
+        assert_eq!(gallop(&ids, 0), 0);
+        assert_eq!(gallop(&ids, 1), 0);
+        assert_eq!(gallop(&ids, 6), 3);
+        assert_eq!(gallop(&ids, 11), 5);
+        assert_eq!(gallop(&ids, 12), 6);
+        assert_eq!(gallop(&[], 12), 0);
+    }
+
+    #[test]
+    fn test_and() {
+        let s = PostStream::and(vec![
+            PostStream::ids(vec![1, 2, 4, 8, 16, 32]),
+            PostStream::all(20),
+            PostStream::ids((0..40).filter(|i| i % 2 == 0).collect()),
+        ]);
+        assert_eq!(collect(s), vec![2, 4, 8, 16]);
+        let s = PostStream::and(vec![PostStream::ids(vec![1, 2]), PostStream::empty()]);
+        assert_eq!(collect(s), Vec::<FileId>::new());
+        assert_eq!(collect(PostStream::and(vec![])), Vec::<FileId>::new());
+    }
+
+    #[test]
+    fn test_or() {
+        let s = PostStream::or(vec![
+            PostStream::ids(vec![1, 5, 9]),
+            PostStream::ids(vec![2, 5, 10]),
+            PostStream::empty(),
+            PostStream::ids(vec![0, 9]),
+        ]);
+        assert_eq!(collect(s), vec![0, 1, 2, 5, 9, 10]);
+        assert_eq!(collect(PostStream::or(vec![])), Vec::<FileId>::new());
+    }
+
+    #[test]
+    fn test_advance_to() {
+        let mut s = PostStream::or(vec![
+            PostStream::and(vec![PostStream::all(100), PostStream::ids(vec![3, 30, 60])]),
+            PostStream::ids(vec![4, 40]),
+        ]);
+        assert_eq!(s.advance_to(4).unwrap(), Some(4));
+        assert_eq!(s.advance_to(4).unwrap(), Some(30));
+        assert_eq!(s.next_id().unwrap(), Some(40));
+        assert_eq!(s.advance_to(61).unwrap(), None);
+    }
+}
@@ -0,0 +1,476 @@
+// Copyright 2016 Vernon Jones.
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Index verification.
+//
+// Checks an index for the problems that would make IndexReader compute
+// bogus offsets or panic: a bad magic or trailer, section offsets that are
+// out of order or out of range, unsorted names, and posting lists that
+// don't match the posting list index (in either encoding), or (for
+// version 2 and 3 indexes) section checksums that don't match.  See read.rs for the on-disk format.
+//
+// Nothing here trusts the data, so every access is bounds-checked.
+
+use std::io;
+use std::path::Path;
+
+use consts::{MAGIC, MAGIC_V2, MAGIC_V3, TRAILER_MAGIC};
+use crc32fast::Hasher;
+use libvarint;
+use memmap::{Mmap, Protection};
+
+use super::postings::PostingList;
+use super::read::{IndexFormat, IndexVersion, FILE_META_ENTRY_SIZE, KNOWN_FLAGS};
+
+/// Give up after this many problems, since the rest are likely fallout
+const MAX_PROBLEMS: usize = 100;
+
+/// Verifies the index at `path`, returning a description of each problem found.
+///
+/// An empty list means the index is sound.
+///
+/// ```no_run
+/// # use libcsearch::reader::verify;
+/// for problem in verify("foo.txt").unwrap() {
+///     println!("{}", problem);
+/// }
+/// ```
+pub fn verify<P: AsRef<Path>>(path: P) -> io::Result<Vec<String>> {
+    let m = Mmap::open_path(path, Protection::Read)?;
+    Ok(verify_bytes(unsafe { m.as_slice() }))
+}
+
+/// Verifies an index that has already been read into memory
+pub fn verify_bytes(data: &[u8]) -> Vec<String> {
+    let mut v = Verifier {
+        data,
+        format: IndexFormat::default(),
+        problems: Vec::new(),
+    };
+    v.verify();
+    v.problems
+}
+
+struct Verifier<'a> {
+    data: &'a [u8],
+    format: IndexFormat,
+    problems: Vec<String>,
+}
+
+impl<'a> Verifier<'a> {
+    fn problem(&mut self, s: String) {
+        if self.problems.len() < MAX_PROBLEMS {
+            self.problems.push(s);
+        } else if self.problems.len() == MAX_PROBLEMS {
+            self.problems
+                .push(String::from("too many problems, giving up"));
+        }
+    }
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let b = self.data.get(offset..offset + 4)?;
+        Some((b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | (b[3] as u32))
+    }
+    /// Returns the offset at `offset`, which is 4 or 8 bytes depending on the format
+    fn offset_at(&self, offset: usize) -> Option<usize> {
+        if !self.format.wide_offsets() {
+            return self.u32_at(offset).map(|o| o as usize);
+        }
+        let hi = self.u32_at(offset)? as u64;
+        let lo = self.u32_at(offset + 4)? as u64;
+        Some((hi << 32 | lo) as usize)
+    }
+    fn trigram_at(&self, offset: usize) -> Option<u32> {
+        let b = self.data.get(offset..offset + 3)?;
+        Some((b[0] as u32) << 16 | (b[1] as u32) << 8 | (b[2] as u32))
+    }
+    /// Returns the NUL-terminated string at `offset`, if it ends before `end`
+    fn string_at(&self, offset: usize, end: usize) -> Option<&'a [u8]> {
+        let data: &'a [u8] = self.data;
+        let s = data.get(offset..end)?;
+        s.iter().position(|&b| b == 0).map(|n| &s[..n])
+    }
+
+    fn verify(&mut self) {
+        let len = self.data.len();
+        let min_len = MAGIC.len() + IndexFormat::from(IndexVersion::V1).trailer_len();
+        if len < min_len {
+            self.problem(format!(
+                "index is truncated: {} bytes, need at least {}",
+                len, min_len
+            ));
+            return;
+        }
+        self.format = match IndexFormat::detect(self.data) {
+            Some(f) => f,
+            None => {
+                self.problem(format!(
+                    "bad magic: expected {:?}, {:?} or {:?}, found {:?}",
+                    MAGIC_V3,
+                    MAGIC_V2,
+                    MAGIC,
+                    String::from_utf8_lossy(&self.data[..MAGIC.len()])
+                ));
+                IndexFormat::from(IndexVersion::V1)
+            }
+        };
+        let trailer_len = self.format.trailer_len();
+        if len < trailer_len + MAGIC.len() {
+            self.problem(format!(
+                "index is truncated: {} bytes, need at least {}",
+                len,
+                trailer_len + MAGIC.len()
+            ));
+            return;
+        }
+        if &self.data[len - TRAILER_MAGIC.len()..] != TRAILER_MAGIC.as_bytes() {
+            self.problem(format!(
+                "bad trailer: expected {:?}, found {:?} (the index may be truncated)",
+                TRAILER_MAGIC,
+                String::from_utf8_lossy(&self.data[len - TRAILER_MAGIC.len()..])
+            ));
+            return;
+        }
+
+        if self.format.flags & !KNOWN_FLAGS != 0 {
+            self.problem(format!(
+                "unknown format flags {:08x} in the trailer",
+                self.format.flags
+            ));
+            return;
+        }
+
+        // the trailer offsets must be in order and inside the file
+        let n = len - trailer_len;
+        let names = [
+            "path list",
+            "name list",
+            "posting lists",
+            "name index",
+            "posting list index",
+        ];
+        let mut off = [0usize; 5];
+        for (i, o) in off.iter_mut().enumerate() {
+            *o = self.offset_at(n + self.format.offset_len() * i).unwrap();
+        }
+        let mut last = ("header", MAGIC.len());
+        let mut offsets_ok = true;
+        for (name, &o) in names.iter().zip(off.iter()) {
+            if o < last.1 {
+                self.problem(format!(
+                    "{} offset {} is before the {} offset {}",
+                    name, o, last.0, last.1
+                ));
+                offsets_ok = false;
+            }
+            last = (name, o);
+        }
+        if off[4] > n {
+            self.problem(format!(
+                "posting list index offset {} is past the trailer at {}",
+                off[4], n
+            ));
+            offsets_ok = false;
+        }
+        if !offsets_ok {
+            return;
+        }
+        if self.format.version.has_checksums() {
+            self.verify_checksums(&names, &off, n);
+        }
+        let [path_data, name_data, post_data, name_index, post_index] = off;
+
+        let end_of_paths = self.verify_paths(path_data, name_data);
+        let num_name = self.verify_names(name_data, post_data, name_index, post_index);
+        if let (Some(end_of_paths), Some(num_name)) = (end_of_paths, num_name) {
+            let meta_len = name_data - end_of_paths;
+            if meta_len != 0 && meta_len != num_name * FILE_META_ENTRY_SIZE {
+                self.problem(format!(
+                    "file metadata is {} bytes, expected {} for {} files",
+                    meta_len,
+                    num_name * FILE_META_ENTRY_SIZE,
+                    num_name
+                ));
+            }
+        }
+        self.verify_posts(post_data, name_index, post_index, n, num_name);
+    }
+
+    /// Checks each section against its checksum in the trailer
+    fn verify_checksums(&mut self, names: &[&str; 5], off: &[usize; 5], end: usize) {
+        let sums = end + 5 * self.format.offset_len();
+        for i in 0..5 {
+            let section_end = if i < 4 { off[i + 1] } else { end };
+            let mut hasher = Hasher::new();
+            hasher.update(&self.data[off[i]..section_end]);
+            let sum = hasher.finalize();
+            let expected = self.u32_at(sums + 4 * i).unwrap();
+            if sum != expected {
+                self.problem(format!(
+                    "{} checksum mismatch: trailer says {:08x}, data has {:08x}",
+                    names[i], expected, sum
+                ));
+            }
+        }
+    }
+
+    /// Checks that the path list is sorted and terminated.
+    /// Returns the offset just past the end of the path list.
+    fn verify_paths(&mut self, path_data: usize, name_data: usize) -> Option<usize> {
+        let mut offset = path_data;
+        let mut last: Option<&[u8]> = None;
+        loop {
+            let p = match self.string_at(offset, name_data) {
+                Some(p) => p,
+                None => {
+                    self.problem(format!(
+                        "path list at {} isn't terminated before the name list at {}",
+                        path_data, name_data
+                    ));
+                    return None;
+                }
+            };
+            offset += p.len() + 1;
+            if p.is_empty() {
+                return Some(offset);
+            }
+            if let Some(l) = last {
+                if l >= p {
+                    self.problem(format!(
+                        "path list isn't sorted: {:?} is followed by {:?}",
+                        String::from_utf8_lossy(l),
+                        String::from_utf8_lossy(p)
+                    ));
+                }
+            }
+            last = Some(p);
+        }
+    }
+
+    /// Checks that the name index points at a sorted list of names.
+    /// Returns the number of names.
+    fn verify_names(
+        &mut self,
+        name_data: usize,
+        post_data: usize,
+        name_index: usize,
+        post_index: usize,
+    ) -> Option<usize> {
+        let offset_len = self.format.offset_len();
+        let index_len = post_index - name_index;
+        if !index_len.is_multiple_of(offset_len) || index_len == 0 {
+            self.problem(format!(
+                "name index is {} bytes, which isn't a positive multiple of {}",
+                index_len, offset_len
+            ));
+            return None;
+        }
+        // the last entry marks the end of the names
+        let num_name = index_len / offset_len - 1;
+        let mut last: Option<&[u8]> = None;
+        let mut expected_offset = 0;
+        for i in 0..num_name {
+            let offset = self.offset_at(name_index + offset_len * i).unwrap();
+            if offset != expected_offset {
+                self.problem(format!(
+                    "name index entry {} is {}, expected {}",
+                    i, offset, expected_offset
+                ));
+            }
+            let name = match self.string_at(name_data.saturating_add(offset), post_data) {
+                Some(name) => name,
+                None => {
+                    self.problem(format!(
+                        "name {} at offset {} runs past the end of the name list",
+                        i, offset
+                    ));
+                    return Some(num_name);
+                }
+            };
+            if let Some(l) = last {
+                if l >= name {
+                    self.problem(format!(
+                        "name list isn't sorted: file {} {:?} is followed by {:?}",
+                        i - 1,
+                        String::from_utf8_lossy(l),
+                        String::from_utf8_lossy(name)
+                    ));
+                }
+            }
+            last = Some(name);
+            expected_offset = offset + name.len() + 1;
+        }
+        Some(num_name)
+    }
+
+    /// Checks that the posting list index is sorted and that each
+    /// posting list matches its index entry.
+    fn verify_posts(
+        &mut self,
+        post_data: usize,
+        name_index: usize,
+        post_index: usize,
+        end: usize,
+        num_name: Option<usize>,
+    ) {
+        let entry_len = self.format.post_entry_len();
+        let index_len = end - post_index;
+        if !index_len.is_multiple_of(entry_len) || index_len == 0 {
+            self.problem(format!(
+                "posting list index is {} bytes, which isn't a positive multiple of {}",
+                index_len, entry_len
+            ));
+            return;
+        }
+        let num_post = index_len / entry_len;
+        let mut last_trigram: Option<u32> = None;
+        for i in 0..num_post {
+            let entry = post_index + i * entry_len;
+            let trigram = self.trigram_at(entry).unwrap();
+            let count = self.u32_at(entry + 3).unwrap() as usize;
+            let offset = self.offset_at(entry + 7).unwrap();
+            let name = trigram_name(trigram);
+            if let Some(l) = last_trigram {
+                if l >= trigram {
+                    self.problem(format!(
+                        "posting list index isn't sorted: {} is followed by {}",
+                        trigram_name(l),
+                        name
+                    ));
+                }
+            }
+            last_trigram = Some(trigram);
+            if trigram == 0xffffff && count == 0 {
+                // the end marker written by IndexWriter; merged indexes omit it
+                continue;
+            }
+            if count == 0 {
+                self.problem(format!("posting list for {} is empty", name));
+                continue;
+            }
+
+            let start = post_data.saturating_add(offset);
+            // the list is read from after its trigram up to the name index
+            if start.saturating_add(3) > name_index {
+                self.problem(format!(
+                    "posting list for {} at offset {} is past the end of the posting lists",
+                    name, offset
+                ));
+                continue;
+            }
+            if self.trigram_at(start) != Some(trigram) {
+                self.problem(format!(
+                    "posting list at offset {} doesn't start with its trigram {}",
+                    offset, name
+                ));
+                continue;
+            }
+            if self.format.block_postings() {
+                self.verify_blocks(&name, start + 3, name_index, count, num_name);
+            } else {
+                self.verify_deltas(&name, start + 3, name_index, count, num_name);
+            }
+        }
+    }
+
+    /// Checks that the block-encoded posting list at `offset` decodes to
+    /// `count` increasing file IDs.
+    fn verify_blocks(
+        &mut self,
+        name: &str,
+        offset: usize,
+        end: usize,
+        count: usize,
+        num_name: Option<usize>,
+    ) {
+        let mut list = match PostingList::new(&self.data[offset..end], count, self.format) {
+            Ok(list) => list,
+            Err(e) => {
+                self.problem(format!("posting list for {}: {}", name, e));
+                return;
+            }
+        };
+        loop {
+            let file_id = match list.next_id() {
+                Ok(Some(file_id)) => file_id as usize,
+                Ok(None) => return,
+                Err(e) => {
+                    self.problem(format!("posting list for {}: {}", name, e));
+                    return;
+                }
+            };
+            if let Some(num_name) = num_name {
+                if file_id >= num_name {
+                    self.problem(format!(
+                        "posting list for {} refers to file {}, but there are only {} files",
+                        name, file_id, num_name
+                    ));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Checks that the delta list at `offset` holds `count` file IDs
+    /// followed by a terminating zero delta.
+    fn verify_deltas(
+        &mut self,
+        name: &str,
+        mut offset: usize,
+        end: usize,
+        count: usize,
+        num_name: Option<usize>,
+    ) {
+        let mut file_id: i64 = -1;
+        for i in 0..count + 1 {
+            let (delta, n) = match libvarint::read_uvarint(&self.data[offset..end]) {
+                Ok(d) => d,
+                Err(_) => {
+                    self.problem(format!(
+                        "posting list for {} has a bad varint at offset {}",
+                        name, offset
+                    ));
+                    return;
+                }
+            };
+            offset += n as usize;
+            if i == count {
+                if delta != 0 {
+                    self.problem(format!(
+                        "posting list for {} has more than the {} files in the index",
+                        name, count
+                    ));
+                }
+                return;
+            }
+            if delta == 0 {
+                self.problem(format!(
+                    "posting list for {} has {} files, but the index says {}",
+                    name, i, count
+                ));
+                return;
+            }
+            file_id += delta as i64;
+            if let Some(num_name) = num_name {
+                if file_id >= num_name as i64 {
+                    self.problem(format!(
+                        "posting list for {} refers to file {}, but there are only {} files",
+                        name, file_id, num_name
+                    ));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Formats a trigram for diagnostics
+fn trigram_name(t: u32) -> String {
+    let bytes = [(t >> 16) as u8, (t >> 8) as u8, t as u8];
+    let escaped = bytes
+        .iter()
+        .flat_map(|&b| ::std::ascii::escape_default(b))
+        .map(|b| b as char)
+        .collect::<String>();
+    format!("\"{}\"", escaped)
+}
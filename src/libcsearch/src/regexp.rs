@@ -165,6 +165,14 @@ impl RegexInfo {
         add_exact(&mut info);
         Ok(info)
     }
+    /// Analyzes a string to be matched literally, ignoring case if `casei`
+    /// is set, as `new` would the regexp that escapes it
+    pub fn literal(bytes: &[u8], casei: bool) -> Result<Self, String> {
+        Self::new(Expr::LiteralBytes {
+            bytes: bytes.to_vec(),
+            casei,
+        })
+    }
     fn analyze(expr: Expr) -> Result<Self, String> {
         // println!("expr: {:?}", expr);
         match expr {
@@ -17,7 +17,7 @@ impl Log for Logger {
         if self.enabled(record.metadata()) {
             let now = Local::now();
             let now_time = now.format("%Y/%m/%d %H:%M:%S");
-            println!("{} {}", now_time, record.args());
+            eprintln!("{} {}", now_time, record.args());
         }
     }
 }
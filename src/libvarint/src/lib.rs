@@ -9,6 +9,10 @@ pub fn read_uvarint(b: &[u8]) -> Result<(u64, u64), u64> {
     let mut x: u64 = 0;
     let mut s: usize = 0;
     for (i, b) in b.iter().enumerate() {
+        if i == 10 {
+            // overflow
+            return Err((i + 1) as u64);
+        }
         if *b < 0x80 {
             if i > 9 || i == 9 && *b > 1 {
                 return Err((i + 1) as u64);
@@ -9,6 +9,7 @@ use std::io::Cursor;
 use std::path::{Path, PathBuf};
 
 use self::libcindex::writer::IndexWriter;
+use self::libcsearch::reader::IndexVersion;
 
 pub fn build_index<P: AsRef<Path>>(
     out: P,
@@ -23,8 +24,44 @@ pub fn build_flush_index<P: AsRef<Path>>(
     paths: Vec<PathBuf>,
     do_flush: bool,
     file_data: BTreeMap<&'static str, &'static str>,
+) {
+    build_index_version(out, paths, do_flush, IndexVersion::default(), file_data);
+}
+
+pub fn build_index_version<P: AsRef<Path>>(
+    out: P,
+    paths: Vec<PathBuf>,
+    do_flush: bool,
+    version: IndexVersion,
+    file_data: BTreeMap<&'static str, &'static str>,
 ) {
     let mut ix = IndexWriter::new(out.as_ref()).unwrap();
+    ix.version = version;
+    build_with(ix, paths, do_flush, file_data);
+}
+
+/// Builds an index with the writer options set by `configure`, such as
+/// 8-byte offsets, which are normally only used for indexes larger than 4 GiB
+pub fn build_custom_index<P, F>(
+    out: P,
+    paths: Vec<PathBuf>,
+    configure: F,
+    file_data: BTreeMap<&'static str, &'static str>,
+) where
+    P: AsRef<Path>,
+    F: FnOnce(&mut IndexWriter),
+{
+    let mut ix = IndexWriter::new(out.as_ref()).unwrap();
+    configure(&mut ix);
+    build_with(ix, paths, false, file_data);
+}
+
+fn build_with(
+    mut ix: IndexWriter,
+    paths: Vec<PathBuf>,
+    do_flush: bool,
+    file_data: BTreeMap<&'static str, &'static str>,
+) {
     ix.add_paths(paths.into_iter().map(PathBuf::into_os_string));
     let mut files = file_data.keys().collect::<Vec<_>>();
     files.sort();
@@ -0,0 +1,81 @@
+extern crate tempfile;
+
+extern crate libcindex;
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use self::tempfile::NamedTempFile;
+
+use self::libcindex::lock::IndexLock;
+
+fn lock_path(index: &NamedTempFile) -> PathBuf {
+    let mut p = index.path().as_os_str().to_owned();
+    p.push(".lock");
+    PathBuf::from(p)
+}
+
+#[test]
+fn test_lock_removes_temp_files() {
+    let index = NamedTempFile::new().unwrap();
+    let (temp1, temp2) = {
+        let lock = IndexLock::acquire(index.path()).unwrap();
+        (lock.temp_path(1), lock.temp_path(2))
+    };
+    fs::write(&temp1, "left over").unwrap();
+    fs::write(&temp2, "left over").unwrap();
+    {
+        let lock = IndexLock::acquire(index.path()).unwrap();
+        assert_eq!(lock.index_path(), index.path());
+        assert!(!temp1.exists());
+        assert!(!temp2.exists());
+    }
+    assert!(index.path().exists());
+    fs::remove_file(lock_path(&index)).unwrap();
+}
+
+#[test]
+fn test_lock_replace() {
+    let index = NamedTempFile::new().unwrap();
+    fs::write(index.path(), "old").unwrap();
+    {
+        let lock = IndexLock::acquire(index.path()).unwrap();
+        let temp = lock.temp_path(1);
+        fs::write(&temp, "new").unwrap();
+        lock.replace(&temp).unwrap();
+        assert!(!temp.exists());
+        assert_eq!(fs::read_to_string(index.path()).unwrap(), "new");
+
+        lock.remove().unwrap();
+        assert!(!index.path().exists());
+        // removing an index that doesn't exist is fine
+        lock.remove().unwrap();
+    }
+    fs::remove_file(lock_path(&index)).unwrap();
+}
+
+#[test]
+fn test_lock_waits() {
+    let index = NamedTempFile::new().unwrap();
+    let path = index.path().to_path_buf();
+    let released = Arc::new(AtomicBool::new(false));
+
+    let lock = IndexLock::acquire(&path).unwrap();
+    let waiter = {
+        let path = path.clone();
+        let released = released.clone();
+        thread::spawn(move || {
+            let _lock = IndexLock::acquire(&path).unwrap();
+            assert!(released.load(Ordering::SeqCst));
+        })
+    };
+    thread::sleep(Duration::from_millis(100));
+    released.store(true, Ordering::SeqCst);
+    drop(lock);
+    waiter.join().unwrap();
+    fs::remove_file(lock_path(&index)).unwrap();
+}
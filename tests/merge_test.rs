@@ -5,15 +5,17 @@ extern crate libcsearch;
 
 mod common;
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::BTreeMap;
+use std::fs;
 use std::path::PathBuf;
 
 use self::tempfile::NamedTempFile;
 
-use self::libcindex::merge::merge;
-use self::libcsearch::reader::{IndexReader, PostReader};
+use self::libcindex::merge::{merge, merge_filtered, merge_many, prune, remove};
+use self::libcindex::writer::IndexWriter;
+use self::libcsearch::reader::{verify, IndexReader, PostReader};
 
-use common::{build_index, tri};
+use common::{build_custom_index, build_index, tri};
 
 const MERGE_PATHS_1: [&str; 3] = ["/a", "/b", "/c"];
 
@@ -63,7 +65,7 @@ fn test_merge() {
 
     fn check_files(ix: &IndexReader, l: &[&'static str]) {
         for (i, fname) in l.iter().enumerate() {
-            assert_eq!(&ix.name(i as u32), fname);
+            assert_eq!(&ix.name(i as u32).unwrap(), fname);
         }
     }
 
@@ -78,10 +80,8 @@ fn test_merge() {
 
     fn check(ix: &IndexReader, trig: &str, l: &[u32]) {
         let t = trig.chars().collect::<Vec<char>>();
-        let l1 = PostReader::list(ix, tri(t[0], t[1], t[2]), &mut None);
-        let mut h: BTreeSet<u32> = BTreeSet::new();
-        h.extend(l.iter().cloned());
-        assert_eq!(l1, h);
+        let l1 = PostReader::list(ix, tri(t[0], t[1], t[2]), None).unwrap();
+        assert_eq!(l1, l);
     }
 
     check(&ix1, "wor", &[0, 1]);
@@ -95,3 +95,346 @@ fn test_merge() {
     check(&ix3, "now", &[3, 4, 6]);
     check(&ix3, "pot", &[4, 5, 7]);
 }
+
+/// Merges MERGE_PATHS_1 with a MERGE_PATHS_2 index built with `configure`,
+/// checking that the result matches a merge of plain indexes and returning
+/// the result to check its format.
+fn check_merge_custom<F: FnOnce(&mut IndexWriter)>(configure: F) -> NamedTempFile {
+    let plain = NamedTempFile::new().unwrap();
+    build_index(
+        plain.path(),
+        MERGE_PATHS_1.iter().map(PathBuf::from).collect(),
+        merge_files_1(),
+    );
+    let custom = NamedTempFile::new().unwrap();
+    build_custom_index(
+        custom.path(),
+        MERGE_PATHS_2.iter().map(PathBuf::from).collect(),
+        configure,
+        merge_files_2(),
+    );
+    let expected = NamedTempFile::new().unwrap();
+    build_index(
+        expected.path(),
+        MERGE_PATHS_2.iter().map(PathBuf::from).collect(),
+        merge_files_2(),
+    );
+    let f1 = NamedTempFile::new().unwrap();
+    let f2 = NamedTempFile::new().unwrap();
+
+    merge(f1.path(), plain.path(), custom.path()).unwrap();
+    merge(f2.path(), plain.path(), expected.path()).unwrap();
+    assert_eq!(verify(f1.path()).unwrap(), Vec::<String>::new());
+
+    let ix1 = IndexReader::open(f1.path()).unwrap();
+    let ix2 = IndexReader::open(f2.path()).unwrap();
+    assert_eq!(ix2.format().flags, 0);
+    assert_eq!(ix1.num_name, ix2.num_name);
+    for i in 0..ix1.num_name as u32 {
+        assert_eq!(ix1.name(i).unwrap(), ix2.name(i).unwrap());
+    }
+    for t in &["all", "wor", "now", "pot"] {
+        let t = t.chars().collect::<Vec<char>>();
+        let t = tri(t[0], t[1], t[2]);
+        assert_eq!(
+            PostReader::list(&ix1, t, None).unwrap(),
+            PostReader::list(&ix2, t, None).unwrap()
+        );
+    }
+    f1
+}
+
+#[test]
+fn test_merge_wide() {
+    // the result has wide offsets if the newer index does
+    let f = check_merge_custom(|ix| ix.wide_offsets = true);
+    let ix = IndexReader::open(f.path()).unwrap();
+    assert!(ix.format().wide_offsets());
+    assert!(!ix.format().block_postings());
+}
+
+#[test]
+fn test_merge_block_postings() {
+    // and block-encoded posting lists
+    let f = check_merge_custom(|ix| ix.block_postings = true);
+    let ix = IndexReader::open(f.path()).unwrap();
+    assert!(ix.format().block_postings());
+    assert!(!ix.format().wide_offsets());
+}
+
+const MERGE_PATHS_3: [&str; 2] = ["/a/x", "/c"];
+
+fn merge_files_3() -> BTreeMap<&'static str, &'static str> {
+    let mut m = BTreeMap::new();
+    m.insert("/a/x", "hello again, world");
+    m.insert("/c/ab", "all the potatoes are gone");
+    m
+}
+
+/// Builds MERGE_PATHS_1, MERGE_PATHS_2 and MERGE_PATHS_3 indexes, the last
+/// one with `configure`, and checks that merging them all at once gives the
+/// same bytes as merging them two at a time.
+fn check_merge_many<F: FnOnce(&mut IndexWriter)>(configure: F) -> NamedTempFile {
+    let f1 = NamedTempFile::new().unwrap();
+    build_index(
+        f1.path(),
+        MERGE_PATHS_1.iter().map(PathBuf::from).collect(),
+        merge_files_1(),
+    );
+    let f2 = NamedTempFile::new().unwrap();
+    build_index(
+        f2.path(),
+        MERGE_PATHS_2.iter().map(PathBuf::from).collect(),
+        merge_files_2(),
+    );
+    let f3 = NamedTempFile::new().unwrap();
+    build_custom_index(
+        f3.path(),
+        MERGE_PATHS_3.iter().map(PathBuf::from).collect(),
+        configure,
+        merge_files_3(),
+    );
+
+    let f12 = NamedTempFile::new().unwrap();
+    let pairwise = NamedTempFile::new().unwrap();
+    merge(f12.path(), f1.path(), f2.path()).unwrap();
+    merge(pairwise.path(), f12.path(), f3.path()).unwrap();
+
+    let all = NamedTempFile::new().unwrap();
+    merge_many(all.path(), &[f1.path(), f2.path(), f3.path()]).unwrap();
+    assert_eq!(
+        fs::read(all.path()).unwrap(),
+        fs::read(pairwise.path()).unwrap()
+    );
+    assert_eq!(verify(all.path()).unwrap(), Vec::<String>::new());
+    all
+}
+
+#[test]
+fn test_merge_many() {
+    let f = check_merge_many(|_| {});
+    let ix = IndexReader::open(f.path()).unwrap();
+    let names = (0..ix.num_name as u32)
+        .map(|i| ix.name(i).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        vec!["/a/x", "/a/y", "/b/www", "/b/xx", "/b/yy", "/c/ab"]
+    );
+    // like the paths themselves, /cc is shadowed by /c
+    assert_eq!(ix.indexed_paths().unwrap(), vec!["/a", "/b", "/c"]);
+
+    fn check(ix: &IndexReader, trig: &str, l: &[u32]) {
+        let t = trig.chars().collect::<Vec<char>>();
+        let l1 = PostReader::list(ix, tri(t[0], t[1], t[2]), None).unwrap();
+        assert_eq!(l1, l);
+    }
+
+    check(&ix, "wor", &[0, 1, 2]);
+    check(&ix, "now", &[3, 4]);
+    check(&ix, "pot", &[4, 5]);
+    check(&ix, "dea", &[]);
+}
+
+#[test]
+fn test_merge_many_formats() {
+    let f = check_merge_many(|ix| ix.wide_offsets = true);
+    assert!(IndexReader::open(f.path()).unwrap().format().wide_offsets());
+    let f = check_merge_many(|ix| ix.block_postings = true);
+    assert!(IndexReader::open(f.path())
+        .unwrap()
+        .format()
+        .block_postings());
+}
+
+#[test]
+fn test_merge_many_single() {
+    // merging one index copies its files
+    let f1 = NamedTempFile::new().unwrap();
+    build_index(
+        f1.path(),
+        MERGE_PATHS_1.iter().map(PathBuf::from).collect(),
+        merge_files_1(),
+    );
+    let f2 = NamedTempFile::new().unwrap();
+    merge_many(f2.path(), &[f1.path()]).unwrap();
+    assert_eq!(verify(f2.path()).unwrap(), Vec::<String>::new());
+
+    let ix1 = IndexReader::open(f1.path()).unwrap();
+    let ix2 = IndexReader::open(f2.path()).unwrap();
+    assert_eq!(ix1.num_name, ix2.num_name);
+    for i in 0..ix1.num_name as u32 {
+        assert_eq!(ix1.name(i).unwrap(), ix2.name(i).unwrap());
+        assert_eq!(ix1.file_meta(i), ix2.file_meta(i));
+    }
+}
+
+#[test]
+fn test_merge_many_inconsistent() {
+    // files outside a newer index's paths can't be shadowed consistently
+    let f1 = NamedTempFile::new().unwrap();
+    build_index(
+        f1.path(),
+        MERGE_PATHS_1.iter().map(PathBuf::from).collect(),
+        merge_files_1(),
+    );
+    let f2 = NamedTempFile::new().unwrap();
+    build_index(f2.path(), vec![PathBuf::from("/b")], merge_files_2());
+    let f3 = NamedTempFile::new().unwrap();
+    let err = merge_many(f3.path(), &[f1.path(), f2.path()]).unwrap_err();
+    assert!(err.to_string().contains("/cc"), "{}", err);
+}
+
+#[test]
+fn test_merge_filtered() {
+    let f1 = NamedTempFile::new().unwrap();
+    build_index(
+        f1.path(),
+        MERGE_PATHS_1.iter().map(PathBuf::from).collect(),
+        merge_files_1(),
+    );
+    let f2 = NamedTempFile::new().unwrap();
+    build_index(
+        f2.path(),
+        MERGE_PATHS_2.iter().map(PathBuf::from).collect(),
+        merge_files_2(),
+    );
+    let f3 = NamedTempFile::new().unwrap();
+
+    // drop /a/y and /c/de, and keep everything else in the old index
+    merge_filtered(f3.path(), f1.path(), f2.path(), |name, meta| {
+        if name == "/a/y" || name == "/c/de" {
+            None
+        } else {
+            Some(meta)
+        }
+    })
+    .unwrap();
+
+    let ix1 = IndexReader::open(f1.path()).unwrap();
+    let ix2 = IndexReader::open(f2.path()).unwrap();
+    let ix3 = IndexReader::open(f3.path()).unwrap();
+
+    let names = (0..ix3.num_name as u32)
+        .map(|i| ix3.name(i).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        vec!["/a/x", "/b/www", "/b/xx", "/b/xy", "/b/yy", "/c/ab", "/cc"]
+    );
+    assert_eq!(ix3.indexed_paths().unwrap(), vec!["/a", "/b", "/c"]);
+
+    // metadata follows each file, with /b/xx taken from the newer index
+    assert_eq!(ix3.file_meta(0), ix1.file_meta(0));
+    assert_eq!(ix3.file_meta(2), ix2.file_meta(1));
+    assert_eq!(ix3.file_meta(3), ix1.file_meta(3));
+    assert_eq!(ix3.file_meta(6), ix2.file_meta(3));
+
+    fn check(ix: &IndexReader, trig: &str, l: &[u32]) {
+        let t = trig.chars().collect::<Vec<char>>();
+        let l1 = PostReader::list(ix, tri(t[0], t[1], t[2]), None).unwrap();
+        assert_eq!(l1, l);
+    }
+
+    check(&ix3, "wor", &[0, 1]);
+    check(&ix3, "now", &[2, 4]);
+    check(&ix3, "all", &[3, 5]);
+    check(&ix3, "pot", &[4, 5, 6]);
+}
+
+#[test]
+fn test_prune() {
+    let f1 = NamedTempFile::new().unwrap();
+    build_index(
+        f1.path(),
+        MERGE_PATHS_1.iter().map(PathBuf::from).collect(),
+        merge_files_1(),
+    );
+    let f2 = NamedTempFile::new().unwrap();
+
+    prune(f2.path(), f1.path(), |name| {
+        name != "/a/y" && name != "/b/xx"
+    })
+    .unwrap();
+
+    let ix1 = IndexReader::open(f1.path()).unwrap();
+    let ix2 = IndexReader::open(f2.path()).unwrap();
+
+    let names = (0..ix2.num_name as u32)
+        .map(|i| ix2.name(i).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["/a/x", "/b/xy", "/c/ab", "/c/de"]);
+    assert_eq!(ix2.indexed_paths().unwrap(), ix1.indexed_paths().unwrap());
+    assert_eq!(ix2.file_meta(1), ix1.file_meta(3));
+
+    fn check(ix: &IndexReader, trig: &str, l: &[u32]) {
+        let t = trig.chars().collect::<Vec<char>>();
+        let l1 = PostReader::list(ix, tri(t[0], t[1], t[2]), None).unwrap();
+        assert_eq!(l1, l);
+    }
+
+    check(&ix2, "wor", &[0]);
+    check(&ix2, "now", &[3]);
+    check(&ix2, "all", &[1, 2]);
+    check(&ix2, "bye", &[]);
+}
+
+#[test]
+fn test_prune_non_ascii() {
+    let f1 = NamedTempFile::new().unwrap();
+    let mut files = BTreeMap::new();
+    files.insert("/a/caf\u{e9}", "hello world");
+    files.insert("/a/gone", "goodbye");
+    build_index(f1.path(), vec![PathBuf::from("/a")], files);
+    let f2 = NamedTempFile::new().unwrap();
+
+    let mut seen = vec![];
+    prune(f2.path(), f1.path(), |name| {
+        seen.push(name.to_string());
+        name != "/a/gone"
+    })
+    .unwrap();
+    assert_eq!(seen, vec!["/a/caf\u{e9}", "/a/gone"]);
+
+    let ix2 = IndexReader::open(f2.path()).unwrap();
+    assert_eq!(ix2.num_name, 1);
+    assert_eq!(ix2.name(0).unwrap(), "/a/caf\u{e9}");
+}
+
+#[test]
+fn test_remove() {
+    let f1 = NamedTempFile::new().unwrap();
+    build_index(
+        f1.path(),
+        MERGE_PATHS_1.iter().map(PathBuf::from).collect(),
+        merge_files_1(),
+    );
+    let f2 = NamedTempFile::new().unwrap();
+
+    remove(f2.path(), f1.path(), "/b").unwrap();
+
+    let ix2 = IndexReader::open(f2.path()).unwrap();
+    let names = (0..ix2.num_name as u32)
+        .map(|i| ix2.name(i).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["/a/x", "/a/y", "/c/ab", "/c/de"]);
+    assert_eq!(ix2.indexed_paths().unwrap(), vec!["/a", "/c"]);
+
+    fn check(ix: &IndexReader, trig: &str, l: &[u32]) {
+        let t = trig.chars().collect::<Vec<char>>();
+        let l1 = PostReader::list(ix, tri(t[0], t[1], t[2]), None).unwrap();
+        assert_eq!(l1, l);
+    }
+
+    check(&ix2, "wor", &[0, 1]);
+    check(&ix2, "now", &[3]);
+    check(&ix2, "all", &[2]);
+
+    // removing a subtree keeps the indexed path
+    let f3 = NamedTempFile::new().unwrap();
+    remove(f3.path(), f1.path(), "/c/ab").unwrap();
+    let ix3 = IndexReader::open(f3.path()).unwrap();
+    assert_eq!(ix3.num_name, 5);
+    assert_eq!(ix3.indexed_paths().unwrap(), vec!["/a", "/b", "/c"]);
+    check(&ix3, "pot", &[]);
+}
@@ -1,27 +1,23 @@
+extern crate regex_syntax;
 extern crate tempfile;
 
 extern crate libcsearch;
 
 mod common;
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
 
-use self::libcsearch::reader::{IndexReader, PostReader};
+use self::libcsearch::reader::{
+    FileMeta, IndexFormat, IndexReadErrorKind, IndexReadResult, IndexReader, IndexVersion,
+    MultiIndexReader, PostReader, QueryPlan,
+};
+use self::libcsearch::regexp::{Query, RegexInfo};
+use self::regex_syntax::Expr;
 use self::tempfile::NamedTempFile;
 
-use common::{build_index, tri};
-
-macro_rules! set {
-    ( $( $x:expr ),* ) => {
-        {
-            let mut temp_set = BTreeSet::new();
-            $(
-                temp_set.insert($x);
-            )*
-            temp_set
-        }
-    }
-}
+use common::{build_custom_index, build_index, tri};
 
 fn post_files() -> BTreeMap<&'static str, &'static str> {
     let mut m = BTreeMap::new();
@@ -43,12 +39,12 @@ fn make_index() -> IndexReader {
 fn test_postreader_list() {
     let ix = make_index();
     assert_eq!(
-        PostReader::list(&ix, tri('S', 'e', 'a'), &mut None),
-        set![1, 3]
+        PostReader::list(&ix, tri('S', 'e', 'a'), None).unwrap(),
+        vec![1, 3]
     );
     assert_eq!(
-        PostReader::list(&ix, tri('G', 'o', 'o'), &mut None),
-        set![1, 2, 3]
+        PostReader::list(&ix, tri('G', 'o', 'o'), None).unwrap(),
+        vec![1, 2, 3]
     );
 }
 
@@ -58,20 +54,22 @@ fn test_postreader_and() {
     assert_eq!(
         PostReader::and(
             &ix,
-            PostReader::list(&ix, tri('S', 'e', 'a'), &mut None),
+            PostReader::list(&ix, tri('S', 'e', 'a'), None).unwrap(),
             tri('G', 'o', 'o'),
-            &None
-        ),
-        set![1, 3]
+            None
+        )
+        .unwrap(),
+        vec![1, 3]
     );
     assert_eq!(
         PostReader::and(
             &ix,
-            PostReader::list(&ix, tri('G', 'o', 'o'), &mut None),
+            PostReader::list(&ix, tri('G', 'o', 'o'), None).unwrap(),
             tri('S', 'e', 'a'),
-            &None
-        ),
-        set![1, 3]
+            None
+        )
+        .unwrap(),
+        vec![1, 3]
     );
 }
 
@@ -81,19 +79,367 @@ fn test_postreader_or() {
     assert_eq!(
         PostReader::or(
             &ix,
-            PostReader::list(&ix, tri('G', 'o', 'o'), &mut None),
+            PostReader::list(&ix, tri('G', 'o', 'o'), None).unwrap(),
             tri('S', 'e', 'a'),
-            &mut None
-        ),
-        set![1, 2, 3]
+            None
+        )
+        .unwrap(),
+        vec![1, 2, 3]
     );
     assert_eq!(
         PostReader::or(
             &ix,
-            PostReader::list(&ix, tri('S', 'e', 'a'), &mut None),
+            PostReader::list(&ix, tri('S', 'e', 'a'), None).unwrap(),
             tri('G', 'o', 'o'),
-            &None
-        ),
-        set![1, 2, 3]
+            None
+        )
+        .unwrap(),
+        vec![1, 2, 3]
+    );
+}
+
+#[test]
+fn test_file_meta() {
+    let ix = make_index();
+    assert!(ix.has_file_meta());
+    assert_eq!(
+        ix.file_meta(0),
+        Some(FileMeta {
+            size: 0,
+            mtime: 0,
+            hash: 0xcbf29ce484222325,
+        })
+    );
+    assert_eq!(ix.file_meta(1).map(|m| m.size), Some(18));
+    assert_eq!(ix.file_meta(4), None);
+}
+
+#[test]
+fn test_find_name() {
+    let ix = make_index();
+    assert_eq!(ix.find_name("file0").unwrap(), Some(0));
+    assert_eq!(ix.find_name("file3").unwrap(), Some(3));
+    assert_eq!(ix.find_name("file").unwrap(), None);
+    assert_eq!(ix.find_name("file4").unwrap(), None);
+}
+
+#[test]
+fn test_non_ascii_name() {
+    let f = NamedTempFile::new().unwrap();
+    let mut files = BTreeMap::new();
+    files.insert("caf\u{e9}", "Google Code Search");
+    files.insert("\u{65e5}\u{672c}", "Google Web Search");
+    build_index(f.path(), vec![], files);
+    let ix = IndexReader::open(f.path()).unwrap();
+    assert_eq!(ix.name(0).unwrap(), "caf\u{e9}");
+    assert_eq!(ix.name(1).unwrap(), "\u{65e5}\u{672c}");
+    assert_eq!(ix.find_name("caf\u{e9}").unwrap(), Some(0));
+    assert_eq!(ix.find_name("\u{65e5}\u{672c}").unwrap(), Some(1));
+}
+
+/// Builds the test index, lets `corrupt` mangle its bytes given the
+/// section offsets from the trailer, and opens the result
+/// Files numbered 0..1000, where file i has "aaa" if i is even, "bbb" if it's
+/// a multiple of 3 and "ccc" if it's a multiple of 100, so that the posting
+/// lists span several blocks
+fn many_files() -> BTreeMap<&'static str, &'static str> {
+    let mut m = BTreeMap::new();
+    for i in 0..1000 {
+        let mut text = String::new();
+        if i % 2 == 0 {
+            text.push_str("aaa\n");
+        }
+        if i % 3 == 0 {
+            text.push_str("bbb\n");
+        }
+        if i % 100 == 0 {
+            text.push_str("ccc\n");
+        }
+        let name: &'static str = Box::leak(format!("file{:04}", i).into_boxed_str());
+        m.insert(name, &*Box::leak(text.into_boxed_str()));
+    }
+    m
+}
+
+#[test]
+fn test_block_postings_query() {
+    let plain = NamedTempFile::new().unwrap();
+    let blocks = NamedTempFile::new().unwrap();
+    build_index(plain.path(), vec![], many_files());
+    build_custom_index(
+        blocks.path(),
+        vec![],
+        |ix| ix.block_postings = true,
+        many_files(),
+    );
+    let ix1 = IndexReader::open(plain.path()).unwrap();
+    let ix2 = IndexReader::open(blocks.path()).unwrap();
+    assert!(ix2.format().block_postings());
+
+    let (a, b, c) = (tri('a', 'a', 'a'), tri('b', 'b', 'b'), tri('c', 'c', 'c'));
+    let query = |ix: &IndexReader| {
+        let all_b = PostReader::list(ix, b, None).unwrap();
+        let ab = PostReader::and(ix, all_b.clone(), a, None).unwrap();
+        let abc = PostReader::and(ix, ab.clone(), c, None).unwrap();
+        let a_or_b = PostReader::or(ix, all_b, a, None).unwrap();
+        (ab, abc, a_or_b)
+    };
+    let (ab, abc, a_or_b) = query(&ix2);
+    assert_eq!((ab.clone(), abc.clone(), a_or_b.clone()), query(&ix1));
+    assert_eq!(ab, (0..1000).filter(|i| i % 6 == 0).collect::<Vec<_>>());
+    assert_eq!(abc, vec![0, 300, 600, 900]);
+    assert_eq!(a_or_b.len(), 667);
+}
+
+#[test]
+fn test_query_stream() {
+    let plain = NamedTempFile::new().unwrap();
+    let blocks = NamedTempFile::new().unwrap();
+    build_index(plain.path(), vec![], many_files());
+    build_custom_index(
+        blocks.path(),
+        vec![],
+        |ix| ix.block_postings = true,
+        many_files(),
+    );
+    for path in &[plain.path(), blocks.path()] {
+        let ix = IndexReader::open(path).unwrap();
+
+        let expected = (0..1000).filter(|i| i % 6 == 0).collect::<Vec<_>>();
+        assert_eq!(ix.query(query("aaa\nbbb")).unwrap(), expected);
+        let expected = (0..1000)
+            .filter(|i| i % 3 == 0 || i % 100 == 0)
+            .collect::<Vec<_>>();
+        assert_eq!(ix.query(query("bbb|ccc")).unwrap(), expected);
+
+        // the stream can be read a little at a time
+        let mut s = ix.query_stream(&query("(aaa|bbb)\nccc")).unwrap();
+        assert_eq!(s.next_id().unwrap(), Some(0));
+        assert_eq!(s.advance_to(301).unwrap(), Some(400));
+        assert_eq!(
+            s.collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![500, 600, 700, 800, 900]
+        );
+    }
+}
+
+fn query(re: &str) -> Query {
+    RegexInfo::new(Expr::parse(re).unwrap()).unwrap().query
+}
+
+#[test]
+fn test_plan_order() {
+    let ix = make_index();
+    let plan = ix.plan(&query("Google Web"));
+    let (operands, skipped) = match plan {
+        QueryPlan::And { operands, skipped } => (operands, skipped),
+        p => panic!("expected an AND, got {:?}", p),
+    };
+    assert_eq!(skipped, vec![]);
+    // the trigrams of "Web" come first, since only file3 has them
+    assert_eq!(
+        operands[0],
+        QueryPlan::Trigram {
+            trigram: tri(' ', 'W', 'e'),
+            count: 1
+        }
+    );
+    let counts = operands
+        .iter()
+        .map(|p| p.estimate(ix.num_name as u64))
+        .collect::<Vec<_>>();
+    let mut sorted = counts.clone();
+    sorted.sort();
+    assert_eq!(counts, sorted);
+    assert_eq!(ix.query(query("Google Web")).unwrap(), vec![3]);
+
+    // the candidates narrow down to file3 as soon as " We" is applied
+    let steps = ix.plan_steps(&ix.plan(&query("Google Web"))).unwrap();
+    assert_eq!(steps.len(), operands.len());
+    assert!(steps.iter().all(|&n| n == 1));
+    let steps = ix.plan_steps(&ix.plan(&query("Goo|Web"))).unwrap();
+    assert_eq!(steps, vec![3, 3]);
+
+    // a trigram that's in no file empties the whole query
+    let plan = ix.plan(&query("Google Xylophone"));
+    assert_eq!(plan.estimate(ix.num_name as u64), 0);
+    assert_eq!(ix.query(query("Google Xylophone")).unwrap(), vec![]);
+}
+
+#[test]
+fn test_plan_skip() {
+    let f = NamedTempFile::new().unwrap();
+    build_index(f.path(), vec![], many_files());
+    let ix = IndexReader::open(f.path()).unwrap();
+
+    // "a\nc" is only in the 6 files with "aaa" and "ccc" but not "bbb",
+    // which makes the "aaa" and "aa\n" in 500 files not worth reading
+    let plan = ix.plan(&query("aaa\nccc"));
+    let skipped = match plan {
+        QueryPlan::And { ref skipped, .. } => skipped.clone(),
+        ref p => panic!("expected an AND, got {:?}", p),
+    };
+    assert_eq!(
+        skipped,
+        vec![
+            QueryPlan::Trigram {
+                trigram: tri('a', 'a', '\n'),
+                count: 500
+            },
+            QueryPlan::Trigram {
+                trigram: tri('a', 'a', 'a'),
+                count: 500
+            },
+        ]
     );
+    assert!(plan
+        .to_string()
+        .contains("skipped\n  \"aa\\n\": 500 files\n"));
+    assert_eq!(
+        ix.query(query("aaa\nccc")).unwrap(),
+        vec![100, 200, 400, 500, 700, 800]
+    );
+
+    // the intersection of two common trigrams is read in full
+    let plan = ix.plan(&query("aaa\nbbb"));
+    match plan {
+        QueryPlan::And { ref skipped, .. } => assert_eq!(skipped, &vec![]),
+        ref p => panic!("expected an AND, got {:?}", p),
+    }
+}
+
+#[test]
+fn test_multi_index() {
+    let f1 = NamedTempFile::new().unwrap();
+    let f2 = NamedTempFile::new().unwrap();
+    build_index(f1.path(), vec![], post_files());
+    let mut m = BTreeMap::new();
+    m.insert("file1", "Google Code Search");
+    m.insert("another", "Bing Search");
+    build_index(f2.path(), vec![], m);
+
+    let ix = MultiIndexReader::open(&[f2.path(), f1.path()]).unwrap();
+    assert_eq!(ix.paths(), &[f2.path(), f1.path()]);
+    assert_eq!(ix.indexes().len(), 2);
+    // file1 is in both indexes, but only found once
+    assert_eq!(
+        ix.query(&query("Search")).unwrap(),
+        vec!["another", "file1", "file3"]
+    );
+    assert_eq!(ix.query(&query("Code")).unwrap(), vec!["file1", "file2"]);
+    assert_eq!(ix.query(&Query::all()).unwrap().len(), 5);
+
+    // errors say which index they came from
+    let missing = f1.path().with_extension("missing");
+    let err = match MultiIndexReader::open(&[f1.path(), &missing]) {
+        Ok(_) => panic!("opened a missing index"),
+        Err(e) => e,
+    };
+    assert!(err.to_string().starts_with(&missing.display().to_string()));
+}
+
+fn open_corrupt<F: FnOnce(&mut Vec<u8>, &[usize])>(corrupt: F) -> IndexReadResult<IndexReader> {
+    let f = NamedTempFile::new().unwrap();
+    build_index(f.path(), vec![], post_files());
+    let mut data = Vec::new();
+    File::open(f.path())
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    let n = data.len() - IndexFormat::detect(&data).unwrap().trailer_len();
+    let off = (0..5)
+        .map(|i| {
+            let d = &data[n + 4 * i..];
+            (d[0] as usize) << 24 | (d[1] as usize) << 16 | (d[2] as usize) << 8 | d[3] as usize
+        })
+        .collect::<Vec<_>>();
+    corrupt(&mut data, &off);
+    let out = NamedTempFile::new().unwrap();
+    File::create(out.path()).unwrap().write_all(&data).unwrap();
+    IndexReader::open(out.path())
+}
+
+fn error_kind<T>(r: IndexReadResult<T>) -> IndexReadErrorKind {
+    match r {
+        Ok(_) => panic!("expected an error"),
+        Err(e) => e.kind(),
+    }
+}
+
+#[test]
+fn test_open_bad_magic() {
+    let r = open_corrupt(|data, _| data[0] = b'x');
+    assert_eq!(error_kind(r), IndexReadErrorKind::BadMagic);
+}
+
+#[test]
+fn test_open_truncated() {
+    let r = open_corrupt(|data, _| {
+        let len = data.len();
+        data.truncate(len - 1)
+    });
+    assert_eq!(error_kind(r), IndexReadErrorKind::TruncatedSection);
+    let r = open_corrupt(|data, _| data.truncate(20));
+    assert_eq!(error_kind(r), IndexReadErrorKind::TruncatedSection);
+}
+
+#[test]
+fn test_open_bad_offsets() {
+    // point the name index past the end of the file
+    let r = open_corrupt(|data, off| {
+        let n = data.len() - IndexFormat::from(IndexVersion::V2).trailer_len();
+        for b in &mut data[n + 12..n + 16] {
+            *b = 0xff;
+        }
+        assert!(off[3] < 0xffffffff);
+    });
+    assert_eq!(error_kind(r), IndexReadErrorKind::OffsetOutOfRange);
+}
+
+#[test]
+fn test_open_unknown_flags() {
+    let r = open_corrupt(|data, _| {
+        // a version 3 index with a flag from the future
+        data[..16].copy_from_slice(b"csearch index 3\n");
+        let len = data.len();
+        let n = len - IndexFormat::from(IndexVersion::V2).trailer_len();
+        let trailer = data.split_off(n);
+        data.extend_from_slice(&trailer[..trailer.len() - 16]);
+        data.extend_from_slice(&[0x80, 0, 0, 0]);
+        data.extend_from_slice(&trailer[trailer.len() - 16..]);
+    });
+    assert_eq!(error_kind(r), IndexReadErrorKind::UnsupportedFormat);
+}
+
+#[test]
+fn test_bad_name() {
+    let ix = make_index();
+    assert_eq!(error_kind(ix.name(4)), IndexReadErrorKind::OffsetOutOfRange);
+
+    // point the first name past the end of the name list
+    let ix = open_corrupt(|data, off| {
+        for b in &mut data[off[3]..off[3] + 4] {
+            *b = 0xff;
+        }
+    })
+    .unwrap();
+    assert_eq!(error_kind(ix.name(0)), IndexReadErrorKind::OffsetOutOfRange);
+    assert_eq!(ix.name(1).unwrap(), "file1");
+}
+
+#[test]
+fn test_bad_posting_list() {
+    // every varint in the posting lists runs off the end of the section
+    let ix = open_corrupt(|data, off| {
+        for b in &mut data[off[2]..off[3]] {
+            *b = 0x80;
+        }
+    })
+    .unwrap();
+    let r = PostReader::list(&ix, tri('G', 'o', 'o'), None);
+    assert_eq!(error_kind(r), IndexReadErrorKind::BadVarint);
+    let q = RegexInfo::new(Expr::parse("Google").unwrap())
+        .unwrap()
+        .query;
+    assert_eq!(error_kind(ix.query(q)), IndexReadErrorKind::BadVarint);
 }
@@ -167,3 +167,22 @@ fn test_query_word_boundary() {
     regex_eq!(r"ab\bc", "\"abc\"");
     regex_eq!(r"ab\Bc", "\"abc\"");
 }
+
+macro_rules! literal_eq {
+    ( $s:expr, $casei:expr, $expected:expr ) => {{
+        let regexinfo = RegexInfo::literal($s.as_bytes(), $casei).unwrap();
+        println!("RegexInfo = {}", regexinfo.format_as_string());
+        assert_eq!($expected.to_string(), regexinfo.query.format_as_string());
+    };};
+}
+
+#[test]
+fn test_query_literal() {
+    // Metacharacters are matched literally, as if escaped.
+    literal_eq!("a.b(", false, "\".b(\" \"a.b\"");
+    literal_eq!("foo.*", false, "\"foo\" \"o.*\" \"oo.\"");
+    literal_eq!("[ab]", false, "\"[ab\" \"ab]\"");
+    literal_eq!("ab", false, "+");
+    literal_eq!("", false, "+");
+    literal_eq!("a~b", true, "(\"A~B\"|\"A~b\"|\"a~B\"|\"a~b\")");
+}
@@ -0,0 +1,191 @@
+extern crate tempfile;
+
+extern crate libcindex;
+extern crate libcsearch;
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+
+use self::libcsearch::reader::{verify, verify_bytes, IndexFormat, IndexVersion};
+use self::tempfile::NamedTempFile;
+
+use common::{build_custom_index, build_flush_index, build_index, build_index_version};
+
+fn post_files() -> BTreeMap<&'static str, &'static str> {
+    let mut m = BTreeMap::new();
+    m.insert("file0", "");
+    m.insert("file1", "Google Code Search");
+    m.insert("file2", "Google Code Project Hosting");
+    m.insert("file3", "Google Web Search");
+    m
+}
+
+fn make_index_bytes() -> Vec<u8> {
+    let f = NamedTempFile::new().unwrap();
+    build_index(f.path(), vec!["/a".into(), "/b".into()], post_files());
+    let mut data = Vec::new();
+    File::open(f.path())
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    data
+}
+
+fn u32_at(data: &[u8], offset: usize) -> usize {
+    ((data[offset] as usize) << 24)
+        | ((data[offset + 1] as usize) << 16)
+        | ((data[offset + 2] as usize) << 8)
+        | (data[offset + 3] as usize)
+}
+
+/// Returns the offset of the trailer
+fn trailer_start(data: &[u8]) -> usize {
+    data.len() - IndexFormat::detect(data).unwrap().trailer_len()
+}
+
+/// Returns the section offsets stored in the trailer
+fn trailer(data: &[u8]) -> Vec<usize> {
+    let n = trailer_start(data);
+    (0..5).map(|i| u32_at(data, n + 4 * i)).collect()
+}
+
+fn assert_problem(data: &[u8], expected: &str) {
+    let problems = verify_bytes(data);
+    assert!(
+        problems.iter().any(|p| p.contains(expected)),
+        "expected a problem containing {:?}, got {:?}",
+        expected,
+        problems
+    );
+}
+
+#[test]
+fn test_verify_ok() {
+    assert_eq!(verify_bytes(&make_index_bytes()), Vec::<String>::new());
+
+    let f = NamedTempFile::new().unwrap();
+    build_flush_index(f.path(), vec![], true, post_files());
+    assert_eq!(verify(f.path()).unwrap(), Vec::<String>::new());
+}
+
+#[test]
+fn test_verify_merged() {
+    let f1 = NamedTempFile::new().unwrap();
+    let f2 = NamedTempFile::new().unwrap();
+    let out = NamedTempFile::new().unwrap();
+    build_index(f1.path(), vec!["/a".into()], post_files());
+    let mut m = BTreeMap::new();
+    m.insert("/b/x", "Google Code Search");
+    build_index(f2.path(), vec!["/b".into()], m);
+    libcindex::merge::merge(out.path(), f1.path(), f2.path()).unwrap();
+    assert_eq!(verify(out.path()).unwrap(), Vec::<String>::new());
+}
+
+#[test]
+fn test_verify_truncated() {
+    let data = make_index_bytes();
+    assert_problem(&data[..10], "truncated");
+    assert_problem(&data[..data.len() - 1], "bad trailer");
+}
+
+#[test]
+fn test_verify_bad_magic() {
+    let mut data = make_index_bytes();
+    data[0] = b'x';
+    assert_problem(&data, "bad magic");
+}
+
+#[test]
+fn test_verify_bad_offsets() {
+    let mut data = make_index_bytes();
+    // point the posting list index past the trailer
+    let n = trailer_start(data.as_slice()) + 4 * 4;
+    data[n] = 0xff;
+    assert_problem(&data, "past the trailer");
+}
+
+#[test]
+fn test_verify_unsorted_names() {
+    let mut data = make_index_bytes();
+    let name_data = trailer(&data)[1];
+    // "file0" sorts after "file1" once it becomes "file9"
+    assert_eq!(&data[name_data..name_data + 5], b"file0");
+    data[name_data + 4] = b'9';
+    assert_problem(&data, "name list isn't sorted");
+}
+
+#[test]
+fn test_verify_bad_count() {
+    let mut data = make_index_bytes();
+    let off = trailer(&data);
+    // bump the count of the first posting list
+    let count_at = off[4] + 3 + 3;
+    data[count_at] += 1;
+    assert_problem(&data, "but the index says");
+}
+
+#[test]
+fn test_verify_bad_checksum() {
+    let mut data = make_index_bytes();
+    let name_data = trailer(&data)[1];
+    // "fIle0" still sorts before "file1", so only the checksum catches it
+    data[name_data + 1] = b'I';
+    assert_problem(&data, "name list checksum mismatch");
+}
+
+#[test]
+fn test_verify_v1() {
+    let f = NamedTempFile::new().unwrap();
+    build_index_version(f.path(), vec![], false, IndexVersion::V1, post_files());
+    assert_eq!(verify(f.path()).unwrap(), Vec::<String>::new());
+}
+
+#[test]
+fn test_verify_bad_post_offset() {
+    let mut data = make_index_bytes();
+    let off = trailer(&data);
+    // move the first posting list onto the second one
+    let offset_at = off[4] + 7 + 3;
+    data[offset_at] += 1;
+    assert_problem(&data, "doesn't start with its trigram");
+}
+
+#[test]
+fn test_verify_post_offset_at_end() {
+    let mut data = make_index_bytes();
+    let off = trailer(&data);
+    let (post_data, name_index) = (off[2], off[3]);
+    // move the first posting list to two bytes before the end of the posting
+    // lists, and put its trigram there so that only the bounds check is left
+    let trigram = data[post_data..post_data + 3].to_vec();
+    let offset = (name_index - 2 - post_data) as u32;
+    let offset_at = off[4] + 7;
+    data[offset_at..offset_at + 4].copy_from_slice(&offset.to_be_bytes());
+    data[name_index - 2..name_index + 1].copy_from_slice(&trigram);
+    assert_problem(&data, "past the end of the posting lists");
+}
+
+#[test]
+fn test_verify_bad_block() {
+    let f = NamedTempFile::new().unwrap();
+    build_custom_index(
+        f.path(),
+        vec![],
+        |ix| ix.block_postings = true,
+        post_files(),
+    );
+    let mut data = Vec::new();
+    File::open(f.path())
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    assert_eq!(verify_bytes(&data), Vec::<String>::new());
+    // the first posting list holds file 0 alone, so its block is just the
+    // bit width after the trigram and block table
+    let off = trailer(&data);
+    data[off[2] + 3 + 8] = 33;
+    assert_problem(&data, "33-bit deltas");
+}
@@ -6,14 +6,21 @@ extern crate libcsearch;
 mod common;
 
 use std::collections::BTreeMap;
-use std::io::Read;
+use std::fs;
+use std::io::{Read, Write};
 use std::num::Wrapping;
 use std::ops::DerefMut;
+use std::path::PathBuf;
 use std::u32;
 
+use self::libcindex::merge::{merge_filtered, prune};
+use self::libcindex::writer::{fit_format, IndexErrorKind, IndexWriter};
+use self::libcsearch::reader::{
+    verify, IndexFormat, IndexReader, IndexVersion, PostReader, FLAG_WIDE_OFFSETS,
+};
 use self::tempfile::NamedTempFile;
 
-use common::build_flush_index;
+use common::{build_custom_index, build_index_version};
 
 fn trivial_files() -> BTreeMap<&'static str, &'static str> {
     let mut d = BTreeMap::new();
@@ -26,14 +33,29 @@ fn trivial_files() -> BTreeMap<&'static str, &'static str> {
     d
 }
 
-fn trivial_index() -> Vec<u8> {
+// size of the file metadata for the 6 trivial files
+const META: u32 = 6 * 24;
+
+fn trivial_index(version: IndexVersion) -> Vec<u8> {
     let mut s = Vec::<u8>::new();
     // header
-    s.extend_from_slice("csearch index 1\n".as_bytes());
+    match version {
+        IndexVersion::V1 => s.extend_from_slice("csearch index 1\n".as_bytes()),
+        IndexVersion::V2 => s.extend_from_slice("csearch index 2\n".as_bytes()),
+        IndexVersion::V3 => s.extend_from_slice("csearch index 3\n".as_bytes()),
+    }
 
     // list of paths
     s.extend_from_slice("\x00".as_bytes());
 
+    // list of file metadata
+    s.extend_from_slice(&file_meta("\ndabc\n")); // afile4
+    s.extend_from_slice(&file_meta("\n\n")); // f0
+    s.extend_from_slice(&file_meta("\na\n")); // file1
+    s.extend_from_slice(&file_meta("\nabc\n")); // file3
+    s.extend_from_slice(&file_meta("\nxyzw\n")); // file5
+    s.extend_from_slice(&file_meta("\nab\n")); // thefile2
+
     // list of names
     s.extend_from_slice("afile4\x00".as_bytes());
     s.extend_from_slice("f0\x00".as_bytes());
@@ -123,11 +145,28 @@ fn trivial_index() -> Vec<u8> {
     s.extend_from_slice(&u32_to_vec(5 + 6 + 5 + 5 + 5 + 6 + 6 + 5 + 5 + 5 + 5));
 
     // trailer
-    s.extend_from_slice(&u32_to_vec(16));
-    s.extend_from_slice(&u32_to_vec(16 + 1));
-    s.extend_from_slice(&u32_to_vec(16 + 1 + 38));
-    s.extend_from_slice(&u32_to_vec(16 + 1 + 38 + 62));
-    s.extend_from_slice(&u32_to_vec(16 + 1 + 38 + 62 + 28));
+    let off = [
+        16,
+        16 + 1 + META,
+        16 + 1 + META + 38,
+        16 + 1 + META + 38 + 62,
+        16 + 1 + META + 38 + 62 + 28,
+    ];
+    let end = s.len() as u32;
+    for &o in off.iter() {
+        s.extend_from_slice(&u32_to_vec(o));
+    }
+    if version != IndexVersion::V1 {
+        for i in 0..5 {
+            let section_end = if i < 4 { off[i + 1] } else { end };
+            let sum = crc32(&s[off[i] as usize..section_end as usize]);
+            s.extend_from_slice(&u32_to_vec(sum));
+        }
+    }
+    if version == IndexVersion::V3 {
+        // no flags
+        s.extend_from_slice(&u32_to_vec(0));
+    }
 
     s.extend_from_slice("\ncsearch trailr\n".as_bytes());
 
@@ -151,6 +190,42 @@ fn file_list(list: Vec<u32>) -> Vec<u8> {
     buf
 }
 
+fn file_meta(contents: &str) -> Vec<u8> {
+    // FNV-1a
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in contents.bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    let mut v = Vec::new();
+    v.extend_from_slice(&u64_to_vec(contents.len() as u64)); // size
+    v.extend_from_slice(&u64_to_vec(0)); // mtime
+    v.extend_from_slice(&u64_to_vec(hash));
+    v
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    // CRC-32 (IEEE), one bit at a time
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn u64_to_vec(value: u64) -> Vec<u8> {
+    let mut v = u32_to_vec((value >> 32) as u32);
+    v.extend_from_slice(&u32_to_vec((value & 0xffffffff) as u32));
+    v
+}
+
 fn u32_to_vec(value: u32) -> Vec<u8> {
     let mut v = Vec::new();
     v.push((value >> 24) as u8);
@@ -162,24 +237,287 @@ fn u32_to_vec(value: u32) -> Vec<u8> {
 
 #[test]
 fn test_trivial_write() {
-    test_write(false);
+    test_write(false, IndexVersion::V2);
 }
 
 #[test]
 fn test_trivial_write_disk() {
-    test_write(true);
+    test_write(true, IndexVersion::V2);
+}
+
+#[test]
+fn test_trivial_write_v1() {
+    test_write(false, IndexVersion::V1);
+    test_write(true, IndexVersion::V1);
+}
+
+#[test]
+fn test_trivial_write_v3() {
+    test_write(false, IndexVersion::V3);
+    test_write(true, IndexVersion::V3);
+}
+
+#[test]
+fn test_merge_keeps_version() {
+    for &version in [IndexVersion::V1, IndexVersion::V2].iter() {
+        let f1 = NamedTempFile::new().unwrap();
+        let f2 = NamedTempFile::new().unwrap();
+        let out = NamedTempFile::new().unwrap();
+        build_index_version(f1.path(), vec![], false, IndexVersion::V2, trivial_files());
+        build_index_version(f2.path(), vec![], false, version, trivial_files());
+        merge_filtered(out.path(), f1.path(), f2.path(), |_, m| Some(m)).unwrap();
+        let ix = IndexReader::open(out.path()).unwrap();
+        assert_eq!(ix.version(), version);
+        assert_eq!(ix.checksums().is_some(), version == IndexVersion::V2);
+        assert_eq!(verify(out.path()).unwrap(), Vec::<String>::new());
+
+        // rewriting an index keeps its version
+        prune(out.path(), f2.path(), |_| true).unwrap();
+        let ix = IndexReader::open(out.path()).unwrap();
+        assert_eq!(ix.version(), version);
+        assert_eq!(verify(out.path()).unwrap(), Vec::<String>::new());
+    }
+}
+
+/// Checks that two indexes of the same files hold the same names and posting lists
+fn assert_same_index(ix1: &IndexReader, ix2: &IndexReader) {
+    assert_eq!(ix1.num_name, ix2.num_name);
+    for i in 0..ix1.num_name as u32 {
+        assert_eq!(ix1.name(i).unwrap(), ix2.name(i).unwrap());
+    }
+    assert_eq!(ix1.num_post, ix2.num_post);
+    for i in 0..ix1.num_post {
+        let (t1, count1, _) = ix1.list_at(i * ix1.format().post_entry_len()).unwrap();
+        let (t2, count2, _) = ix2.list_at(i * ix2.format().post_entry_len()).unwrap();
+        assert_eq!((t1, count1), (t2, count2));
+        assert_eq!(
+            PostReader::list(ix1, t1, None).unwrap(),
+            PostReader::list(ix2, t2, None).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_wide_offsets() {
+    let narrow = NamedTempFile::new().unwrap();
+    let wide = NamedTempFile::new().unwrap();
+    build_index_version(
+        narrow.path(),
+        vec![],
+        false,
+        IndexVersion::V2,
+        trivial_files(),
+    );
+    build_custom_index(
+        wide.path(),
+        vec![],
+        |ix| ix.wide_offsets = true,
+        trivial_files(),
+    );
+    assert_eq!(verify(wide.path()).unwrap(), Vec::<String>::new());
+
+    let ix1 = IndexReader::open(narrow.path()).unwrap();
+    let ix2 = IndexReader::open(wide.path()).unwrap();
+    assert_eq!(ix2.version(), IndexVersion::V3);
+    assert!(ix2.format().wide_offsets());
+    // 7 name index entries, 12 posting list index entries, 5 trailer
+    // offsets and the format flags
+    assert_eq!(ix2.len(), ix1.len() + 4 * (7 + 12 + 5) + 4);
+    assert_same_index(&ix1, &ix2);
+}
+
+#[test]
+fn test_block_postings() {
+    let plain = NamedTempFile::new().unwrap();
+    build_index_version(
+        plain.path(),
+        vec![],
+        false,
+        IndexVersion::V2,
+        trivial_files(),
+    );
+    let ix1 = IndexReader::open(plain.path()).unwrap();
+    for &wide in [false, true].iter() {
+        let blocks = NamedTempFile::new().unwrap();
+        build_custom_index(
+            blocks.path(),
+            vec![],
+            |ix| {
+                ix.block_postings = true;
+                ix.wide_offsets = wide;
+            },
+            trivial_files(),
+        );
+        assert_eq!(verify(blocks.path()).unwrap(), Vec::<String>::new());
+
+        let ix2 = IndexReader::open(blocks.path()).unwrap();
+        assert_eq!(ix2.version(), IndexVersion::V3);
+        assert!(ix2.format().block_postings());
+        assert_eq!(ix2.format().wide_offsets(), wide);
+        assert_same_index(&ix1, &ix2);
+    }
+
+    // the Go format can't have block-encoded posting lists
+    let v1 = NamedTempFile::new().unwrap();
+    let mut ix = IndexWriter::new(v1.path()).unwrap();
+    ix.version = IndexVersion::V1;
+    ix.block_postings = true;
+    assert!(ix.flush().is_err());
+}
+
+#[test]
+fn test_add_files() {
+    // files on disk, some of which can't be indexed
+    let mut files = (0..50)
+        .map(|i| {
+            let mut f = NamedTempFile::new().unwrap();
+            if i % 10 == 3 {
+                f.write_all(b"binary\x00data").unwrap();
+            } else {
+                writeln!(f, "file {} of {}, {}", i, i * 7, "x".repeat(i)).unwrap();
+            }
+            f
+        })
+        .collect::<Vec<_>>();
+    files.sort_by(|a, b| a.path().cmp(b.path()));
+    let mut names = files
+        .iter()
+        .map(|f| f.path().to_path_buf())
+        .collect::<Vec<_>>();
+    names.insert(20, PathBuf::from("/does/not/exist"));
+    names.sort();
+
+    let one_by_one = NamedTempFile::new().unwrap();
+    let mut ix = IndexWriter::new(one_by_one.path()).unwrap();
+    for name in &names {
+        let _ = ix.add_file(name);
+    }
+    ix.flush().unwrap();
+
+    for &threads in [1, 4, 16].iter() {
+        let parallel = NamedTempFile::new().unwrap();
+        let mut ix = IndexWriter::new(parallel.path()).unwrap();
+        let mut skipped = Vec::new();
+        ix.add_files(names.clone(), threads, |f, e| {
+            skipped.push((f.to_path_buf(), e.kind()))
+        })
+        .unwrap();
+        ix.flush().unwrap();
+
+        assert_eq!(
+            fs::read(parallel.path()).unwrap(),
+            fs::read(one_by_one.path()).unwrap()
+        );
+        assert_eq!(skipped.len(), 6);
+        assert!(skipped
+            .iter()
+            .any(|s| s.0 == PathBuf::from("/does/not/exist")
+                && s.1 == IndexErrorKind::IoError(std::io::ErrorKind::NotFound)));
+        assert_eq!(
+            skipped
+                .iter()
+                .filter(|s| s.1 == IndexErrorKind::BinaryDataPresent)
+                .count(),
+            5
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_add_files_non_utf8_name() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut good = NamedTempFile::new().unwrap();
+    writeln!(good, "hello world").unwrap();
+    let good = good.path().to_path_buf();
+    let mut bad = good.clone().into_os_string();
+    bad.push(OsStr::from_bytes(b"caf\xe9"));
+    let bad = PathBuf::from(bad);
+    fs::write(&bad, "hello world\n").unwrap();
+
+    let f = NamedTempFile::new().unwrap();
+    let mut ix = IndexWriter::new(f.path()).unwrap();
+    let mut skipped = Vec::new();
+    let added = ix.add_files(vec![good.clone(), bad.clone()], 2, |f, e| {
+        skipped.push((f.to_path_buf(), e.kind()))
+    });
+    fs::remove_file(&bad).unwrap();
+    added.unwrap();
+    ix.flush().unwrap();
+    assert_eq!(skipped, vec![(bad, IndexErrorKind::FileNameError)]);
+
+    let ix = IndexReader::open(f.path()).unwrap();
+    assert_eq!(ix.num_name, 1);
+    assert_eq!(ix.name(0).unwrap(), good.to_str().unwrap());
+    assert_eq!(verify(f.path()).unwrap(), Vec::<String>::new());
+}
+
+#[test]
+fn test_memory_budget() {
+    // spilling the posting entries to disk doesn't change the index
+    let expected = NamedTempFile::new().unwrap();
+    build_custom_index(expected.path(), vec![], |_| {}, trivial_files());
+    for &memory in [0, 16, 64, 100].iter() {
+        let f = NamedTempFile::new().unwrap();
+        build_custom_index(f.path(), vec![], |ix| ix.memory = memory, trivial_files());
+        assert_eq!(
+            fs::read(f.path()).unwrap(),
+            fs::read(expected.path()).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_memory_budget_many_runs() {
+    // with one entry per temporary file, they're merged in several levels
+    let write = |memory: Option<usize>| {
+        let f = NamedTempFile::new().unwrap();
+        let mut ix = IndexWriter::new(f.path()).unwrap();
+        if let Some(m) = memory {
+            ix.memory = m;
+        }
+        for i in 0..60 {
+            let text = format!("file {} has {}\n{}\n", i, i * 7919, "ab".repeat(i));
+            let name = format!("file{:02}", i);
+            ix.add(&name, text.as_bytes(), text.len() as u64).unwrap();
+        }
+        ix.flush().unwrap();
+        fs::read(f.path()).unwrap()
+    };
+    let expected = write(None);
+    assert_eq!(write(Some(0)), expected);
+    assert_eq!(write(Some(1000)), expected);
+}
+
+#[test]
+fn test_fit_format() {
+    let v2 = IndexFormat::from(IndexVersion::V2);
+    let big = u32::MAX as u64 + 1;
+    assert_eq!(fit_format(v2, u32::MAX as u64).unwrap(), v2);
+    assert_eq!(
+        fit_format(v2, big).unwrap(),
+        IndexFormat {
+            version: IndexVersion::V3,
+            flags: FLAG_WIDE_OFFSETS,
+        }
+    );
+    let v3 = IndexFormat::from(IndexVersion::V3);
+    assert_eq!(fit_format(v3, 0).unwrap(), v3);
+    assert!(fit_format(IndexFormat::from(IndexVersion::V1), big).is_err());
 }
 
-fn test_write(do_flush: bool) {
+fn test_write(do_flush: bool, version: IndexVersion) {
     let mut f = NamedTempFile::new().unwrap();
     {
         let out = f.path();
-        build_flush_index(out, vec![], do_flush, trivial_files());
+        build_index_version(out, vec![], do_flush, version, trivial_files());
     }
 
     let mut data = Vec::new();
     f.deref_mut().read_to_end(&mut data).unwrap();
-    let want = trivial_index();
+    let want = trivial_index(version);
     if data != want {
         let mut i = 0;
         while i < data.len() && i < want.len() && data[i] == want[i] {